@@ -0,0 +1,115 @@
+//! Channels module: Tracks which named chat rooms this peer has joined and which one is active.
+//!
+//! Channel names are stored without the leading `#` and in lowercase. Every peer starts in
+//! `#general`. The joined set is advertised to other peers in `PeerInfo::channels`, and chat
+//! messages are tagged with the sender's active channel so they are only delivered to, and
+//! only displayed by, peers subscribed to it.
+
+use std::collections::BTreeSet;
+
+pub const DEFAULT_CHANNEL: &str = "general";
+const MAX_CHANNEL_LEN: usize = 32;
+
+/// The default subscription set, used for peers that don't advertise any channels.
+pub fn default_channels() -> BTreeSet<String> {
+    BTreeSet::from([DEFAULT_CHANNEL.to_string()])
+}
+
+/// The channel assumed for messages that don't name one.
+pub fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
+}
+
+/// Normalize user input like `#Rust` into a channel name, or `None` if it isn't valid.
+pub fn normalize(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_CHANNEL_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelState {
+    joined: BTreeSet<String>,
+    active: String,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            joined: default_channels(),
+            active: DEFAULT_CHANNEL.to_string(),
+        }
+    }
+}
+
+impl ChannelState {
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    pub fn joined(&self) -> &BTreeSet<String> {
+        &self.joined
+    }
+
+    pub fn is_joined(&self, channel: &str) -> bool {
+        self.joined.contains(channel)
+    }
+
+    /// Join `channel` (if not already joined) and make it active. Returns `true` if the
+    /// subscription set changed.
+    pub fn join(&mut self, channel: &str) -> bool {
+        self.active = channel.to_string();
+        self.joined.insert(channel.to_string())
+    }
+
+    /// Join `channel` without making it active. Returns `true` if the subscription set changed.
+    pub fn subscribe(&mut self, channel: &str) -> bool {
+        self.joined.insert(channel.to_string())
+    }
+
+    /// Leave the active channel and switch to another joined one. Returns the channel left,
+    /// or `None` if it is the only channel we are in.
+    pub fn leave(&mut self) -> Option<String> {
+        if self.joined.len() <= 1 {
+            return None;
+        }
+        let left = std::mem::take(&mut self.active);
+        self.joined.remove(&left);
+        self.active = self.joined.iter().next().cloned().unwrap_or_default();
+        Some(left)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("#Rust").as_deref(), Some("rust"));
+        assert_eq!(normalize("build-bots").as_deref(), Some("build-bots"));
+        assert_eq!(normalize("#"), None);
+        assert_eq!(normalize("two words"), None);
+        assert_eq!(normalize(&"a".repeat(40)), None);
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let mut state = ChannelState::default();
+        assert_eq!(state.active(), DEFAULT_CHANNEL);
+        // Can't leave the last channel
+        assert_eq!(state.leave(), None);
+
+        assert!(state.join("rust"));
+        assert_eq!(state.active(), "rust");
+        assert!(!state.join("rust"));
+
+        assert_eq!(state.leave().as_deref(), Some("rust"));
+        assert_eq!(state.active(), DEFAULT_CHANNEL);
+        assert!(!state.is_joined("rust"));
+    }
+}
@@ -0,0 +1,440 @@
+//! Events module: Typed notifications published on `Peer::events` for UIs, bots, loggers and tests.
+//!
+//! Everything the rest of the application may want to react to (incoming messages, peers coming
+//! and going, delivery receipts, security warnings) is sent as a `ChatEvent` on a broadcast
+//! channel. Subscribers match on the variants they care about; the `Display` impl gives the
+//! one-line rendering used by the CLI and the terminal UI.
+
+use crate::chat::receipts::DeliveryStatus;
+use crate::chat::transfer::{format_size, short_id};
+use crate::known_peers::fingerprint;
+use crate::peer::{FileOffer, Message, PeerInfo, ShareManifest};
+use std::fmt;
+use std::path::PathBuf;
+
+/// How we learned about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryMethod {
+    Mdns,
+    Beacon,
+    /// An address from the static peer list.
+    Static,
+    /// The shared registry directory.
+    Registry,
+    /// The user asked to connect to its address (`/connect`).
+    Manual,
+    /// It introduced itself over a TCP connection.
+    Tcp,
+}
+
+impl fmt::Display for DiscoveryMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DiscoveryMethod::Mdns => "mDNS",
+            DiscoveryMethod::Beacon => "beacon",
+            DiscoveryMethod::Static => "static peer list",
+            DiscoveryMethod::Registry => "registry",
+            DiscoveryMethod::Manual => "/connect",
+            DiscoveryMethod::Tcp => "TCP",
+        })
+    }
+}
+
+/// Why a peer is no longer in the peer map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// It told us it was quitting.
+    Exited,
+    /// It went silent for longer than the liveness timeout.
+    TimedOut,
+    /// The discovery backend that found it reported it gone.
+    Lost(DiscoveryMethod),
+}
+
+/// Which end of a file transfer we are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Sending,
+    Receiving,
+}
+
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// A validly signed chat or direct message from another peer.
+    MessageReceived {
+        message: Message,
+        direct: bool,
+        /// `false` if the sender's name is bound to a different key in the known peers store.
+        trusted: bool,
+    },
+    /// Messages a sender numbered but that never reached us.
+    MessagesMissed {
+        from_name: String,
+        channel: String,
+        missed: u64,
+    },
+    /// Messages added to our history by history sync.
+    HistorySynced {
+        count: usize,
+    },
+    PeerJoined {
+        peer: PeerInfo,
+        via: DiscoveryMethod,
+    },
+    PeerLeft {
+        peer: PeerInfo,
+        reason: LeaveReason,
+    },
+    /// A peer missed its heartbeats and is now shown as stale.
+    PeerStale(PeerInfo),
+    /// A stale peer was heard from again.
+    PeerBack(PeerInfo),
+    /// A peer key seen for the first time was added to the known peers store.
+    PeerTrusted {
+        peer_id: String,
+    },
+    /// A known peer ID showed up with a different key.
+    KeyMismatch {
+        peer_id: String,
+        expected_key: String,
+        presented_key: String,
+    },
+    /// A recipient's delivery status for one of our messages changed.
+    DeliveryStatus {
+        message_id: String,
+        peer_id: String,
+        peer_name: String,
+        status: DeliveryStatus,
+    },
+    /// Messages waiting in the outbox were delivered.
+    OutboxFlushed {
+        peer: PeerInfo,
+        count: usize,
+    },
+    /// A peer offered us a file; accept it with `Peer::accept_file`.
+    FileOffered(FileOffer),
+    /// The recipient of one of our offers declined it.
+    FileDeclined {
+        file_name: String,
+        peer_name: String,
+    },
+    /// Another tenth of a file was sent or received.
+    TransferProgress {
+        offer_id: String,
+        file_name: String,
+        direction: TransferDirection,
+        done: u64,
+        total: u64,
+    },
+    /// A file was sent, or received and verified against its checksum.
+    TransferComplete {
+        offer_id: String,
+        file_name: String,
+        direction: TransferDirection,
+        /// The file we sent, or where the received one was saved.
+        path: PathBuf,
+    },
+    /// A transfer broke off. A failed download can be resumed by accepting the offer again.
+    TransferFailed {
+        offer_id: String,
+        file_name: String,
+        direction: TransferDirection,
+        reason: String,
+    },
+    /// A peer shared a file with everyone; fetch it with `Peer::fetch_share`.
+    ShareAvailable(ShareManifest),
+    /// Another tenth of a shared file's chunks arrived.
+    ShareProgress {
+        share_id: String,
+        file_name: String,
+        have: usize,
+        total: usize,
+        /// Peers known to hold chunks of the file.
+        sources: usize,
+    },
+    /// A shared file was fetched and verified.
+    ShareComplete {
+        share_id: String,
+        file_name: String,
+        path: PathBuf,
+    },
+    /// Fetching a shared file stopped; fetching it again keeps the chunks we already have.
+    ShareFailed {
+        share_id: String,
+        file_name: String,
+        reason: String,
+    },
+    /// The config file was read again and applied.
+    ConfigReloaded,
+    /// Something went wrong that the user should know about.
+    Error(String),
+    /// A background problem (failed write, unreachable peer, malformed announcement) worth
+    /// logging but not worth interrupting the chat for.
+    Warning(String),
+}
+
+impl ChatEvent {
+    /// Whether the chat UIs print this event in the conversation. Delivered and read receipts
+    /// are only shown by `/status`, so a busy channel doesn't drown in them, and warnings go to
+    /// the log.
+    pub fn is_shown(&self) -> bool {
+        !matches!(
+            self,
+            ChatEvent::DeliveryStatus {
+                status: DeliveryStatus::Pending | DeliveryStatus::Delivered | DeliveryStatus::Read,
+                ..
+            } | ChatEvent::Warning(_)
+        )
+    }
+}
+
+impl fmt::Display for ChatEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatEvent::MessageReceived {
+                message,
+                direct,
+                trusted,
+            } => {
+                let tag = if *trusted { "" } else { "[UNTRUSTED] " };
+                if *direct {
+                    write!(
+                        f,
+                        "🔒 {}[DM] {} whispers: {}",
+                        tag, message.from_name, message.content
+                    )
+                } else {
+                    write!(
+                        f,
+                        "{}[#{}] {} says: {}",
+                        tag, message.channel, message.from_name, message.content
+                    )
+                }
+            }
+            ChatEvent::MessagesMissed {
+                from_name,
+                channel,
+                missed,
+            } => write!(
+                f,
+                "⚠️  Missed {} message(s) from {} in #{}",
+                missed, from_name, channel
+            ),
+            ChatEvent::HistorySynced { count } => {
+                write!(f, "🕘 Synced {} message(s) from history", count)
+            }
+            ChatEvent::PeerJoined { peer, via } => match via {
+                DiscoveryMethod::Static | DiscoveryMethod::Manual => write!(
+                    f,
+                    "🔗 Connected to {} at {}:{}",
+                    peer.name, peer.ip, peer.port
+                ),
+                DiscoveryMethod::Tcp => write!(
+                    f,
+                    "🔗 Discovered peer via TCP: {} at {}",
+                    peer.name, peer.ip
+                ),
+                method => write!(
+                    f,
+                    "🔍 Discovered peer via {}: {} at {}:{}",
+                    method, peer.name, peer.ip, peer.port
+                ),
+            },
+            ChatEvent::PeerLeft { peer, reason } => match reason {
+                LeaveReason::Exited => write!(
+                    f,
+                    "❌ Peer {} ({}) exited and was removed from the list.",
+                    peer.name, peer.id
+                ),
+                LeaveReason::TimedOut => write!(f, "🔴 {} timed out and left", peer.name),
+                LeaveReason::Lost(_) => write!(f, "⚪ {} is no longer discoverable", peer.name),
+            },
+            ChatEvent::PeerStale(peer) => write!(f, "🟡 {} has gone quiet", peer.name),
+            ChatEvent::PeerBack(peer) => write!(f, "🟢 {} is back online", peer.name),
+            ChatEvent::PeerTrusted { peer_id } => write!(
+                f,
+                "🔑 Trusting new peer with fingerprint {}",
+                fingerprint(peer_id)
+            ),
+            ChatEvent::KeyMismatch {
+                peer_id,
+                expected_key,
+                presented_key,
+            } => write!(
+                f,
+                "⚠️  WARNING: PEER KEY MISMATCH! Peer {} was previously seen with key {}, \
+                 but now presents {}. Someone may be impersonating them; use /verify to check.",
+                fingerprint(peer_id),
+                fingerprint(expected_key),
+                fingerprint(presented_key)
+            ),
+            ChatEvent::DeliveryStatus {
+                peer_name, status, ..
+            } => match status {
+                DeliveryStatus::Pending => write!(f, "… Message to {} is pending", peer_name),
+                DeliveryStatus::Failed => {
+                    write!(f, "✗ Could not deliver your message to {}", peer_name)
+                }
+                DeliveryStatus::Delivered => write!(f, "✓ {} received your message", peer_name),
+                DeliveryStatus::Read => write!(f, "✓✓ {} read your message", peer_name),
+            },
+            ChatEvent::OutboxFlushed { peer, count } => write!(
+                f,
+                "📬 Delivered {} queued message(s) to {}",
+                count, peer.name
+            ),
+            ChatEvent::FileOffered(offer) => write!(
+                f,
+                "📎 {} offers {} ({}); /accept {} or /decline {}",
+                offer.from_name,
+                offer.file_name,
+                format_size(offer.size),
+                short_id(&offer.id),
+                short_id(&offer.id)
+            ),
+            ChatEvent::FileDeclined {
+                file_name,
+                peer_name,
+            } => write!(f, "🚫 {} declined {}", peer_name, file_name),
+            ChatEvent::TransferProgress {
+                file_name,
+                direction,
+                done,
+                total,
+                ..
+            } => {
+                let verb = match direction {
+                    TransferDirection::Sending => "Sending",
+                    TransferDirection::Receiving => "Receiving",
+                };
+                let percent = (*done * 100).checked_div(*total).unwrap_or(100);
+                write!(
+                    f,
+                    "⏳ {} {}: {}% ({} of {})",
+                    verb,
+                    file_name,
+                    percent,
+                    format_size(*done),
+                    format_size(*total)
+                )
+            }
+            ChatEvent::TransferComplete {
+                file_name,
+                direction,
+                path,
+                ..
+            } => match direction {
+                TransferDirection::Sending => write!(f, "✅ Sent {}", file_name),
+                TransferDirection::Receiving => write!(
+                    f,
+                    "✅ Received {} (checksum verified), saved to {}",
+                    file_name,
+                    path.display()
+                ),
+            },
+            ChatEvent::TransferFailed {
+                offer_id,
+                file_name,
+                direction,
+                reason,
+            } => match direction {
+                TransferDirection::Sending => {
+                    write!(f, "✗ Sending {} failed: {}", file_name, reason)
+                }
+                TransferDirection::Receiving => write!(
+                    f,
+                    "✗ Receiving {} failed: {}; /accept {} to resume",
+                    file_name,
+                    reason,
+                    short_id(offer_id)
+                ),
+            },
+            ChatEvent::ShareAvailable(manifest) => write!(
+                f,
+                "📦 {} shares {} ({}); /fetch {}",
+                manifest.from_name,
+                manifest.file_name,
+                format_size(manifest.size),
+                short_id(&manifest.id)
+            ),
+            ChatEvent::ShareProgress {
+                file_name,
+                have,
+                total,
+                sources,
+                ..
+            } => write!(
+                f,
+                "⏳ Fetching {}: {}% ({} of {} chunks, from {} peer(s))",
+                file_name,
+                (have * 100).checked_div(*total).unwrap_or(100),
+                have,
+                total,
+                sources
+            ),
+            ChatEvent::ShareComplete {
+                file_name, path, ..
+            } => write!(
+                f,
+                "✅ Fetched {} (checksum verified), saved to {}",
+                file_name,
+                path.display()
+            ),
+            ChatEvent::ShareFailed {
+                share_id,
+                file_name,
+                reason,
+            } => write!(
+                f,
+                "✗ Fetching {} stopped: {}; /fetch {} to continue",
+                file_name,
+                reason,
+                short_id(share_id)
+            ),
+            ChatEvent::ConfigReloaded => write!(f, "🔄 Configuration reloaded"),
+            ChatEvent::Error(error) => write!(f, "⚠️  {}", error),
+            ChatEvent::Warning(warning) => write!(f, "{}", warning),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_event_rendering() {
+        let message = Message {
+            id: Message::new_id(),
+            from_id: "id1".to_string(),
+            from_name: "Alice".to_string(),
+            content: "hi".to_string(),
+            timestamp: 1234567890,
+            channel: "rust".to_string(),
+            seq: 1,
+            to_id: String::new(),
+            signature: String::new(),
+        };
+        let event = ChatEvent::MessageReceived {
+            message: message.clone(),
+            direct: false,
+            trusted: true,
+        };
+        assert_eq!(event.to_string(), "[#rust] Alice says: hi");
+        let event = ChatEvent::MessageReceived {
+            message,
+            direct: true,
+            trusted: false,
+        };
+        assert_eq!(event.to_string(), "🔒 [UNTRUSTED] [DM] Alice whispers: hi");
+        assert!(event.is_shown());
+        let receipt = |status| ChatEvent::DeliveryStatus {
+            message_id: "m1".to_string(),
+            peer_id: "id2".to_string(),
+            peer_name: "Bob".to_string(),
+            status,
+        };
+        assert!(!receipt(DeliveryStatus::Read).is_shown());
+        assert!(receipt(DeliveryStatus::Failed).is_shown());
+    }
+}
@@ -0,0 +1,71 @@
+//! Ignore module: The list of peers whose messages are dropped without being shown.
+//!
+//! Entries are matched against a message's sender name and peer ID. Ignored messages are still
+//! acknowledged as delivered, so the sender doesn't keep retrying, but they are neither displayed
+//! nor written to the history.
+
+use crate::peer::Message;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreList {
+    entries: BTreeSet<String>,
+}
+
+impl IgnoreList {
+    /// Replace the whole list, e.g. after the config file was reloaded.
+    pub fn replace(&mut self, entries: impl IntoIterator<Item = String>) {
+        self.entries = entries
+            .into_iter()
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect();
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        self.matches_peer(&message.from_id, &message.from_name)
+    }
+
+    pub fn matches_peer(&self, peer_id: &str, name: &str) -> bool {
+        self.entries.contains(name) || self.entries.contains(peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from_id: &str, from_name: &str) -> Message {
+        Message {
+            id: Message::new_id(),
+            from_id: from_id.to_string(),
+            from_name: from_name.to_string(),
+            content: "hi".to_string(),
+            timestamp: 0,
+            channel: "general".to_string(),
+            seq: 1,
+            to_id: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_matches_name_or_id() {
+        let mut list = IgnoreList::default();
+        list.replace(["Mallory".to_string(), " abcd ".to_string(), String::new()]);
+        assert_eq!(list.len(), 2);
+        assert!(list.matches(&message("1234", "Mallory")));
+        assert!(list.matches(&message("abcd", "Eve")));
+        assert!(!list.matches(&message("1234", "Bob")));
+        list.replace(Vec::new());
+        assert!(list.is_empty());
+    }
+}
@@ -1,110 +1,384 @@
 //! Chat module: Provides the main struct and logic for peer-to-peer Chat functionality, including peer management, message broadcasting, and coordination of submodules.
 //!
-//! This module defines the `Peer` struct, which represents a peer in the Chat network.
-//! It handles the initialization of the peer, starting of necessary services like TCP listener,
-//! mDNS discovery, heartbeat sending, and CLI handling. It also provides functionality to broadcast
-//! messages to other peers.
+//! This module defines the `Peer` struct, which represents a peer in the Chat network and holds
+//! the state shared by its services (TCP listener, peer discovery, heartbeats, peer liveness
+//! reaping and delivery retries, started by `node::ChatNode`).
+//! It also provides functionality to broadcast messages to other peers, send direct messages,
+//! and manage channel subscriptions. Nothing here reads stdin or writes stdout; everything worth
+//! showing is published as a `ChatEvent`.
+
+pub mod channels;
+pub mod events;
+pub mod ignore;
+pub mod receipts;
+pub mod sequence;
+pub mod swarm;
+pub mod transfer;
 
 pub mod net {
+    pub mod beacon;
     pub mod broadcast;
+    pub mod connection;
+    pub mod delivery;
     pub mod discovery;
     pub mod heartbeat;
     pub mod listener;
+    pub mod liveness;
+    pub mod mdns;
+    pub mod registry;
+    pub mod static_peers;
+    pub mod swarm;
+    pub mod sync;
+    #[cfg(test)]
+    pub mod testing;
+    pub mod transfer;
 }
 
-pub mod display {
-    pub mod cli;
-    pub mod message_display;
-}
-
+use crate::chat::channels::ChannelState;
+use crate::chat::events::ChatEvent;
+use crate::chat::ignore::IgnoreList;
+use crate::chat::net::connection::ConnectionManager;
+use crate::chat::net::liveness::LivenessConfig;
+use crate::chat::receipts::ReceiptTracker;
+use crate::chat::sequence::{SequenceCounter, SequenceTracker};
+use crate::chat::swarm::Shares;
+use crate::chat::transfer::FileTransfers;
+use crate::config::Config;
 use crate::error::ChatError;
-use crate::peer::PeerInfo;
-use colored::*;
+use crate::history::{HistoryEntry, HistoryStore};
+use crate::identity::Identity;
+use crate::known_peers::KnownPeers;
+use crate::network::secure::NoiseKeys;
+use crate::network::tcp::ConnectionContext;
+use crate::outbox::Outbox;
+use crate::peer::{FileOffer, NetworkMessage, PeerInfo, ShareManifest};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use uuid::Uuid;
+use tokio_util::sync::CancellationToken;
+
+/// Trim a display name, falling back to "Anonymous" if it is empty or too long.
+fn valid_name(name: &str) -> String {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 128 {
+        "Anonymous".to_string()
+    } else {
+        name.to_string()
+    }
+}
 
 #[derive(Clone)]
 pub struct Peer {
     pub peer_id: String,
-    pub name: String,
+    /// Display name; changes when a reloaded config file renames us, see `name()`.
+    name: Arc<std::sync::RwLock<String>>,
     pub port: u16,
     pub peers: Arc<Mutex<HashMap<String, PeerInfo>>>,
-    pub message_sender: tokio::sync::broadcast::Sender<String>,
+    /// Everything happening in the chat, as typed events; see `events::ChatEvent`.
+    pub events: tokio::sync::broadcast::Sender<ChatEvent>,
+    pub connections: Arc<ConnectionManager>,
+    pub liveness: LivenessConfig,
+    pub noise_keys: Arc<NoiseKeys>,
+    pub identity: Arc<Identity>,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub channels: Arc<Mutex<ChannelState>>,
+    pub history: Arc<Mutex<HistoryStore>>,
+    /// Numbering of our own channel messages.
+    pub outgoing_seq: Arc<std::sync::Mutex<SequenceCounter>>,
+    /// Numbering seen from other senders, for gap detection.
+    pub sequences: Arc<Mutex<SequenceTracker>>,
+    /// Delivery state of the messages we sent, and read receipts we owe.
+    pub receipts: Arc<Mutex<ReceiptTracker>>,
+    /// Messages waiting for peers we couldn't reach.
+    pub outbox: Arc<Mutex<Outbox>>,
+    /// Senders whose messages are dropped.
+    pub ignored: Arc<Mutex<IgnoreList>>,
+    /// File offers we made and received.
+    pub transfers: Arc<Mutex<FileTransfers>>,
+    /// Files shared with everyone that we hold or have heard of.
+    pub shares: Arc<Mutex<Shares>>,
+    /// Cancel to stop every service; see `node::ChatHandle::shutdown`.
+    pub shutdown: CancellationToken,
 }
 
 impl Peer {
+    /// Create a peer with a throwaway identity and in-memory stores; see `with_storage` for
+    /// persistent ones.
     pub fn new(name: String, port: u16) -> Self {
+        Self::with_storage(
+            name,
+            port,
+            Identity::generate(),
+            KnownPeers::in_memory(),
+            HistoryStore::in_memory(),
+            Outbox::in_memory(),
+        )
+    }
+
+    pub fn with_storage(
+        name: String,
+        port: u16,
+        identity: Identity,
+        known_peers: KnownPeers,
+        history: HistoryStore,
+        outbox: Outbox,
+    ) -> Self {
         // Validate name and port
-        let valid_name = name.trim();
-        let name = if valid_name.is_empty() || valid_name.len() > 128 {
-            "Anonymous".to_string()
-        } else {
-            valid_name.to_string()
-        };
+        let name = Arc::new(std::sync::RwLock::new(valid_name(&name)));
         let port = if port == 0 { 8080 } else { port };
-        let peer_id = Uuid::new_v4().to_string();
-        let (message_sender, _) = tokio::sync::broadcast::channel(100);
+        let peer_id = identity.peer_id();
+        let (events, _) = tokio::sync::broadcast::channel(100);
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let noise_keys = Arc::new(NoiseKeys::new(&identity));
+        let known_peers = Arc::new(Mutex::new(known_peers));
+        let channels = Arc::new(Mutex::new(ChannelState::default()));
+        let outgoing_seq = Arc::new(std::sync::Mutex::new(SequenceCounter::from_history(
+            &history,
+        )));
+        let history = Arc::new(Mutex::new(history));
+        let sequences = Arc::new(Mutex::new(SequenceTracker::default()));
+        let receipts = Arc::new(Mutex::new(ReceiptTracker::default()));
+        let outbox = Arc::new(Mutex::new(outbox));
+        let shutdown = CancellationToken::new();
+        let ignored = Arc::new(Mutex::new(IgnoreList::default()));
+        let transfers = Arc::new(Mutex::new(FileTransfers::default()));
+        let shares = Arc::new(Mutex::new(Shares::default()));
+        let connections = Arc::new_cyclic(|connections| {
+            let ctx = ConnectionContext {
+                peers: peers.clone(),
+                events: events.clone(),
+                peer_id: peer_id.clone(),
+                known_peers: known_peers.clone(),
+                channels: channels.clone(),
+                history: history.clone(),
+                sequences: sequences.clone(),
+                receipts: receipts.clone(),
+                outbox: outbox.clone(),
+                connections: connections.clone(),
+                shutdown: shutdown.clone(),
+                ignored: ignored.clone(),
+                transfers: transfers.clone(),
+                shares: shares.clone(),
+            };
+            ConnectionManager::new(ctx, noise_keys.clone())
+        });
         Self {
             peer_id,
             name,
             port,
-            peers: Arc::new(Mutex::new(HashMap::new())),
-            message_sender,
+            peers,
+            events,
+            connections,
+            liveness: LivenessConfig::default(),
+            noise_keys,
+            identity: Arc::new(identity),
+            known_peers,
+            channels,
+            history,
+            outgoing_seq,
+            sequences,
+            receipts,
+            outbox,
+            ignored,
+            transfers,
+            shares,
+            shutdown,
         }
     }
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("{}", "🎙️  Starting P2P Chat...".bright_cyan().bold());
-        println!("👤 Your ID: {}", self.peer_id.bright_yellow());
-        println!("📡 Your Name: {}", self.name.bright_green());
-        println!(
-            "🔌 Listening on port: {}",
-            self.port.to_string().bright_blue()
-        );
 
-        // Start all services concurrently
-        let tcp_listener = net::listener::start_tcp_listener(self);
-        let mdns_discovery = net::discovery::start_mdns(Arc::new(self.clone()));
-        let heartbeat_sender = net::heartbeat::start_heartbeat(self);
-        let cli_handler = display::cli::start_cli_handler(self);
-        let message_display = display::message_display::start_message_display(self);
-
-        tokio::select! {
-            result = tcp_listener => {
-                if let Err(e) = result {
-                    eprintln!("TCP listener error: {}", e);
-                    std::process::exit(1);
-                }
-            }
-            result = mdns_discovery => {
-                if let Err(e) = result {
-                    eprintln!("mDNS discovery error: {}", e);
-                    std::process::exit(1);
-                }
-            }
-            result = heartbeat_sender => {
-                if let Err(e) = result {
-                    eprintln!("Heartbeat sender error: {}", e);
-                    std::process::exit(1);
-                }
-            }
-            result = cli_handler => {
-                if let Err(e) = result {
-                    eprintln!("CLI handler error: {}", e);
-                    std::process::exit(1);
-                }
+    /// The shared state handed to every connection handler.
+    pub fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            peers: self.peers.clone(),
+            events: self.events.clone(),
+            peer_id: self.peer_id.clone(),
+            known_peers: self.known_peers.clone(),
+            channels: self.channels.clone(),
+            history: self.history.clone(),
+            sequences: self.sequences.clone(),
+            receipts: self.receipts.clone(),
+            outbox: self.outbox.clone(),
+            connections: Arc::downgrade(&self.connections),
+            shutdown: self.shutdown.clone(),
+            ignored: self.ignored.clone(),
+            transfers: self.transfers.clone(),
+            shares: self.shares.clone(),
+        }
+    }
+
+    /// Append a message to the local history log, reporting (but not propagating) failures.
+    pub async fn record(&self, entry: HistoryEntry) {
+        if let Err(e) = self.history.lock().await.append(entry) {
+            self.warn(format!("Failed to write message history: {}", e));
+        }
+    }
+
+    /// Make sure everything written to the history, known peers and outbox files has reached
+    /// the disk.
+    pub async fn sync_storage(&self) -> Result<(), ChatError> {
+        self.history.lock().await.sync()?;
+        self.known_peers.lock().await.sync()?;
+        self.outbox.lock().await.sync()
+    }
+
+    pub fn name(&self) -> String {
+        self.name.read().expect("name lock poisoned").clone()
+    }
+
+    /// Apply a (re)loaded config file: rename us, join any newly listed channels, replace the
+    /// ignore list and switch the download directory. Channels no longer listed are kept;
+    /// `/leave` them instead. Peers are told about a new name or channels right away.
+    pub async fn apply_config(&self, config: &Config) -> Result<(), ChatError> {
+        let configured = config
+            .channels
+            .iter()
+            .map(|channel| {
+                channels::normalize(channel)
+                    .ok_or_else(|| ChatError::InvalidChannel(channel.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut changed = false;
+        {
+            let name = valid_name(&config.name);
+            let mut current = self.name.write().expect("name lock poisoned");
+            if *current != name {
+                *current = name;
+                changed = true;
             }
-            result = message_display => {
-                if let Err(e) = result {
-                    eprintln!("Message display error: {}", e);
-                    std::process::exit(1);
-                }
+        }
+        {
+            let mut joined = self.channels.lock().await;
+            for channel in &configured {
+                changed |= joined.subscribe(channel);
             }
         }
+        self.ignored
+            .lock()
+            .await
+            .replace(config.ignore.iter().cloned());
+        self.transfers
+            .lock()
+            .await
+            .set_download_dir(config.downloads.clone());
+        if changed {
+            self.announce().await;
+        }
         Ok(())
     }
-    pub async fn broadcast_message(&self, content: &str) -> Result<(), ChatError> {
+
+    /// Publish a `ChatEvent::Warning`.
+    pub fn warn(&self, text: String) {
+        let _ = self.events.send(ChatEvent::Warning(text));
+    }
+
+    /// Our own `PeerInfo` as advertised to other peers. Receivers replace the IP with the
+    /// address they see the connection coming from.
+    pub async fn own_info(&self) -> PeerInfo {
+        let ip = local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let mut info = PeerInfo::new(self.peer_id.clone(), self.name(), ip, self.port);
+        info.channels = self.channels.lock().await.joined().clone();
+        info
+    }
+
+    /// Join `channel` and make it the active one, telling peers if our subscriptions changed.
+    pub async fn join_channel(&self, channel: &str) -> Result<String, ChatError> {
+        let channel = channels::normalize(channel)
+            .ok_or_else(|| ChatError::InvalidChannel(channel.to_string()))?;
+        let changed = self.channels.lock().await.join(&channel);
+        if changed {
+            self.announce().await;
+        }
+        Ok(channel)
+    }
+
+    /// Leave the active channel. Returns the channel left, or `None` if it was the only one.
+    pub async fn leave_channel(&self) -> Option<String> {
+        let left = self.channels.lock().await.leave();
+        if left.is_some() {
+            self.announce().await;
+        }
+        left
+    }
+
+    /// Re-send our `PeerInfo` so peers learn our current name and channel subscriptions.
+    async fn announce(&self) {
+        let msg = NetworkMessage::Discovery(self.own_info().await);
+        let targets = net::broadcast::snapshot_peers(self).await;
+        net::broadcast::fan_out(self, targets, &msg).await;
+    }
+
+    /// Look up a peer by exact name, exact ID, or unique ID prefix.
+    pub async fn find_peer(&self, query: &str) -> Option<PeerInfo> {
+        let peers = self.peers.lock().await;
+        if let Some(info) = peers.get(query) {
+            return Some(info.clone());
+        }
+        let mut matches = peers
+            .values()
+            .filter(|info| info.name == query || info.id.starts_with(query));
+        match (matches.next(), matches.next()) {
+            (Some(info), None) => Some(info.clone()),
+            _ => None,
+        }
+    }
+
+    /// Send a private message to the peer matching `to` (name, ID, or unique ID prefix).
+    pub async fn send_direct(
+        &self,
+        to: &str,
+        content: &str,
+    ) -> Result<net::broadcast::DeliveryResult, ChatError> {
+        let target = self
+            .find_peer(to)
+            .await
+            .ok_or_else(|| ChatError::PeerNotFound(to.to_string()))?;
+        net::broadcast::send_direct(self, target, content).await
+    }
+
+    /// Offer the file at `path` to the peer matching `to`; it is sent once they accept.
+    pub async fn send_file(&self, to: &str, path: &Path) -> Result<FileOffer, ChatError> {
+        let target = self
+            .find_peer(to)
+            .await
+            .ok_or_else(|| ChatError::PeerNotFound(to.to_string()))?;
+        net::transfer::offer_file(self, target, path).await
+    }
+
+    /// Start (or resume) downloading the received offer whose ID starts with `offer_id`.
+    pub async fn accept_file(&self, offer_id: &str) -> Result<FileOffer, ChatError> {
+        net::transfer::accept_offer(self, offer_id).await
+    }
+
+    /// Turn down the received offer whose ID starts with `offer_id`.
+    pub async fn decline_file(&self, offer_id: &str) -> Result<FileOffer, ChatError> {
+        net::transfer::decline_offer(self, offer_id).await
+    }
+
+    /// Connect to the peer listening at `addr` (`host:port`), swap `Discovery` messages with it
+    /// and add it to the peers, for when discovery can't find it.
+    pub async fn connect(&self, addr: &str) -> Result<PeerInfo, ChatError> {
+        net::discovery::connect(self, addr).await
+    }
+
+    /// Share the file at `path` with every peer; any of them can fetch it with `fetch_share`.
+    pub async fn share_file(
+        &self,
+        path: &Path,
+    ) -> Result<(ShareManifest, Vec<net::broadcast::DeliveryResult>), ChatError> {
+        net::swarm::share_file(self, path).await
+    }
+
+    /// Start (or continue) fetching the share whose ID starts with `share_id`.
+    pub async fn fetch_share(&self, share_id: &str) -> Result<ShareManifest, ChatError> {
+        net::swarm::fetch_share(self, share_id).await
+    }
+
+    pub async fn broadcast_message(
+        &self,
+        content: &str,
+    ) -> Result<Vec<net::broadcast::DeliveryResult>, ChatError> {
         net::broadcast::broadcast_message(self, content).await
     }
 }
@@ -118,12 +392,12 @@ mod tests {
 
     #[test]
     fn test_peer_info_creation() {
-        let peer = PeerInfo {
-            id: "test-id".to_string(),
-            name: "TestPeer".to_string(),
-            ip: IpAddr::from_str("127.0.0.1").unwrap(),
-            port: 8080,
-        };
+        let peer = PeerInfo::new(
+            "test-id".to_string(),
+            "TestPeer".to_string(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            8080,
+        );
         assert_eq!(peer.name, "TestPeer");
         assert_eq!(peer.port, 8080);
     }
@@ -131,7 +405,81 @@ mod tests {
     #[test]
     fn test_chat_new() {
         let peer = Peer::new("Tester".to_string(), 9000);
-        assert_eq!(peer.name, "Tester");
+        assert_eq!(peer.name(), "Tester");
         assert_eq!(peer.port, 9000);
     }
+
+    #[tokio::test]
+    async fn test_find_peer_by_name_or_id_prefix() {
+        let peer = Peer::new("Alice".to_string(), 9000);
+        let bob = PeerInfo::new(
+            "b0b0aaaa".to_string(),
+            "Bob".to_string(),
+            "192.168.1.2".parse().unwrap(),
+            9001,
+        );
+        let carol = PeerInfo::new(
+            "b0b1cccc".to_string(),
+            "Carol".to_string(),
+            "192.168.1.3".parse().unwrap(),
+            9002,
+        );
+        {
+            let mut peers = peer.peers.lock().await;
+            peers.insert(bob.id.clone(), bob);
+            peers.insert(carol.id.clone(), carol);
+        }
+        assert_eq!(peer.find_peer("Bob").await.unwrap().id, "b0b0aaaa");
+        assert_eq!(peer.find_peer("b0b1").await.unwrap().name, "Carol");
+        // Ambiguous prefix and unknown name
+        assert!(peer.find_peer("b0b").await.is_none());
+        assert!(peer.find_peer("Dave").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_direct_unknown_peer() {
+        let peer = Peer::new("Alice".to_string(), 9000);
+        let err = peer.send_direct("Nobody", "hi").await.unwrap_err();
+        assert!(matches!(err, ChatError::PeerNotFound(name) if name == "Nobody"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_config() {
+        let peer = Peer::new("Alice".to_string(), 9000);
+        let config = Config {
+            name: "Alicia".to_string(),
+            channels: vec!["#Rust".to_string()],
+            ignore: vec!["Mallory".to_string()],
+            ..Config::default()
+        };
+        peer.apply_config(&config).await.unwrap();
+        assert_eq!(peer.name(), "Alicia");
+        assert!(peer.channels.lock().await.is_joined("rust"));
+        assert_eq!(peer.channels.lock().await.active(), "general");
+        assert_eq!(peer.ignored.lock().await.len(), 1);
+
+        let invalid = Config {
+            channels: vec!["not valid".to_string()],
+            ..Config::default()
+        };
+        assert!(matches!(
+            peer.apply_config(&invalid).await,
+            Err(ChatError::InvalidChannel(_))
+        ));
+        assert_eq!(peer.name(), "Alicia");
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_channel() {
+        let peer = Peer::new("Alice".to_string(), 9000);
+        assert!(matches!(
+            peer.join_channel("#not valid").await,
+            Err(ChatError::InvalidChannel(_))
+        ));
+        assert_eq!(peer.join_channel("#Rust").await.unwrap(), "rust");
+        assert!(peer.own_info().await.channels.contains("rust"));
+        assert_eq!(peer.leave_channel().await.as_deref(), Some("rust"));
+        assert_eq!(peer.channels.lock().await.active(), "general");
+        assert_eq!(peer.leave_channel().await, None);
+    }
 }
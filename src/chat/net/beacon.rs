@@ -0,0 +1,157 @@
+//! Beacon module: Discovery backend that broadcasts UDP beacons and listens for other peers' beacons.
+//!
+//! Many networks drop multicast, which leaves mDNS finding nobody, while plain broadcasts still get
+//! through. Every heartbeat interval we broadcast a JSON `Beacon` to
+//! `255.255.255.255:BEACON_PORT`. It carries our full `PeerInfo` (ID, name, TCP port and channels)
+//! along with `BEACON_PROTOCOL` and `PROTOCOL_VERSION`. The listener reads the protocol and version
+//! first, so it can skip stray traffic and tell the user about peers running an incompatible
+//! version instead of failing to parse them. Everything else is reported as found, with the
+//! address taken from the datagram rather than from what the peer claims. A beacon from a known
+//! peer marks it as seen, like a heartbeat. Beacons run next to mDNS by default, or on their own
+//! with `--discovery beacon`.
+
+use crate::chat::events::DiscoveryMethod;
+use crate::chat::net::discovery::{Discovery, Reporter};
+use crate::chat::net::heartbeat::bind_shared;
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::peer::{Beacon, BeaconHeader, PeerInfo, BEACON_PROTOCOL, PROTOCOL_VERSION};
+use futures_util::future::BoxFuture;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+
+/// UDP port beacons are broadcast to and received on.
+pub const BEACON_PORT: u16 = 9998;
+
+pub struct BeaconDiscovery;
+
+impl Discovery for BeaconDiscovery {
+    fn method(&self) -> DiscoveryMethod {
+        DiscoveryMethod::Beacon
+    }
+
+    fn run(
+        self: Box<Self>,
+        peer: Arc<Peer>,
+        reporter: Reporter,
+    ) -> BoxFuture<'static, Result<(), ChatError>> {
+        Box::pin(async move {
+            tokio::select! {
+                result = send_beacons(&peer) => result,
+                result = listen(&peer, &reporter) => result,
+            }
+        })
+    }
+}
+
+pub fn new_beacon(info: PeerInfo) -> Beacon {
+    Beacon {
+        protocol: BEACON_PROTOCOL.to_string(),
+        version: PROTOCOL_VERSION,
+        peer: info,
+    }
+}
+
+/// What a received datagram turned out to be.
+#[derive(Debug)]
+pub enum Received {
+    /// A beacon we understand, from the peer in it.
+    Peer(PeerInfo),
+    /// A beacon in another version of the protocol.
+    OtherVersion(u32),
+    /// Not a beacon.
+    Unknown,
+}
+
+/// Decode a datagram received from `from`.
+pub fn read_beacon(data: &[u8], from: SocketAddr) -> Received {
+    let Ok(header) = serde_json::from_slice::<BeaconHeader>(data) else {
+        return Received::Unknown;
+    };
+    if header.protocol != BEACON_PROTOCOL {
+        return Received::Unknown;
+    }
+    if header.version != PROTOCOL_VERSION {
+        return Received::OtherVersion(header.version);
+    }
+    match serde_json::from_slice::<Beacon>(data) {
+        Ok(beacon) => {
+            let mut info = beacon.peer;
+            info.ip = from.ip();
+            Received::Peer(info)
+        }
+        Err(_) => Received::Unknown,
+    }
+}
+
+async fn send_beacons(peer: &Peer) -> Result<(), ChatError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    loop {
+        let bytes = serde_json::to_vec(&new_beacon(peer.own_info().await))?;
+        if let Err(e) = socket
+            .send_to(&bytes, (Ipv4Addr::BROADCAST, BEACON_PORT))
+            .await
+        {
+            peer.warn(format!("Failed to send beacon: {}", e));
+        }
+        sleep(peer.liveness.heartbeat_interval).await;
+    }
+}
+
+async fn listen(peer: &Peer, reporter: &Reporter) -> Result<(), ChatError> {
+    let socket = bind_shared(BEACON_PORT)?;
+    let mut buf = vec![0u8; 8192];
+    // Senders already told about, so an incompatible peer is only reported once
+    let mut incompatible: HashSet<(IpAddr, u32)> = HashSet::new();
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        match read_beacon(&buf[..n], from) {
+            Received::Peer(info) if info.id != peer.peer_id => reporter.found(info).await,
+            Received::OtherVersion(version) if incompatible.insert((from.ip(), version)) => {
+                peer.warn(format!(
+                    "Ignoring beacons from {}: protocol version {}, we speak {}",
+                    from.ip(),
+                    version,
+                    PROTOCOL_VERSION
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_beacon() {
+        let from: SocketAddr = "192.168.1.20:40000".parse().unwrap();
+        let info = PeerInfo::new(
+            "ab".repeat(32),
+            "Bob".to_string(),
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            9100,
+        );
+        let bytes = serde_json::to_vec(&new_beacon(info)).unwrap();
+        let Received::Peer(found) = read_beacon(&bytes, from) else {
+            panic!("expected a peer");
+        };
+        assert_eq!(found.name, "Bob");
+        assert_eq!((found.ip, found.port), (from.ip(), 9100));
+
+        // A later version may change everything but the header
+        let future = br#"{"protocol":"p2p_chat","version":7,"peer":{"key":"..."}}"#;
+        assert!(matches!(
+            read_beacon(future, from),
+            Received::OtherVersion(7)
+        ));
+        let other = br#"{"protocol":"something_else","version":1}"#;
+        assert!(matches!(read_beacon(other, from), Received::Unknown));
+        assert!(matches!(read_beacon(b"\x00\x01", from), Received::Unknown));
+    }
+}
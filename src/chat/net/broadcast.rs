@@ -1,65 +1,263 @@
 use crate::chat::Peer;
 use crate::error::ChatError;
-use crate::peer::{Message, NetworkMessage};
-use serde_json;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use crate::history::HistoryEntry;
+use crate::peer::{Message, NetworkMessage, PeerInfo};
+use futures_util::future::join_all;
+use tokio::time::{timeout, Duration};
 
-pub async fn broadcast_message(peer: &Peer, content: &str) -> Result<(), ChatError> {
-    let message = Message {
+/// Upper bound on how long a single peer may take to accept a message.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of sending one message to one peer.
+#[derive(Debug)]
+pub struct DeliveryResult {
+    pub peer: PeerInfo,
+    pub outcome: Result<(), ChatError>,
+}
+
+impl DeliveryResult {
+    pub fn is_delivered(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+/// Build a message from us for `channel`, or for the peer `to_id` if `channel` is empty, number it
+/// and sign it with our identity key.
+pub fn new_message(
+    peer: &Peer,
+    channel: &str,
+    to_id: &str,
+    content: &str,
+) -> Result<Message, ChatError> {
+    let seq = if channel.is_empty() {
+        0
+    } else {
+        peer.outgoing_seq
+            .lock()
+            .expect("sequence counter lock poisoned")
+            .next(channel)
+    };
+    let mut message = Message {
+        id: Message::new_id(),
         from_id: peer.peer_id.clone(),
-        from_name: peer.name.clone(),
+        from_name: peer.name(),
         content: content.to_string(),
         timestamp: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .map_err(|e| ChatError::Unknown(e.to_string()))?
             .as_secs(),
+        channel: channel.to_string(),
+        seq,
+        to_id: to_id.to_string(),
+        signature: String::new(),
     };
-    let network_msg = NetworkMessage::Chat(message.clone());
-    let msg_bytes = serde_json::to_vec(&network_msg)?;
-    let peers = peer.peers.lock().await;
-    let mut successful_sends = 0;
-    for peer in peers.values() {
-        if !peer.is_valid() {
-            eprintln!("Skipping invalid peer: {:?}", peer);
-            continue;
+    peer.identity.sign(&mut message);
+    Ok(message)
+}
+
+pub async fn broadcast_message(
+    peer: &Peer,
+    content: &str,
+) -> Result<Vec<DeliveryResult>, ChatError> {
+    let channel = peer.channels.lock().await.active().to_string();
+    let message = new_message(peer, &channel, "", content)?;
+    peer.record(HistoryEntry {
+        message: message.clone(),
+        outgoing: true,
+        direct: false,
+    })
+    .await;
+    let message_id = message.id.clone();
+    let network_msg = NetworkMessage::Chat(message);
+    // Only peers subscribed to the channel receive the message
+    let targets: Vec<PeerInfo> = snapshot_peers(peer)
+        .await
+        .into_iter()
+        .filter(|info| info.channels.contains(&channel))
+        .collect();
+    track(
+        peer,
+        &message_id,
+        &format!("[#{}] {}", channel, content),
+        &network_msg,
+        &targets,
+    )
+    .await;
+    let results = fan_out(peer, targets, &network_msg).await;
+    queue_undelivered(peer, &results, &network_msg).await;
+    Ok(results)
+}
+
+/// Send a private message to `target` only.
+pub async fn send_direct(
+    peer: &Peer,
+    target: PeerInfo,
+    content: &str,
+) -> Result<DeliveryResult, ChatError> {
+    let message = new_message(peer, "", &target.id, content)?;
+    peer.record(HistoryEntry {
+        message: message.clone(),
+        outgoing: true,
+        direct: true,
+    })
+    .await;
+    let summary = format!("[DM to {}] {}", target.name, content);
+    let message_id = message.id.clone();
+    let network_msg = NetworkMessage::Direct(message);
+    track(
+        peer,
+        &message_id,
+        &summary,
+        &network_msg,
+        std::slice::from_ref(&target),
+    )
+    .await;
+    let mut results = fan_out(peer, vec![target], &network_msg).await;
+    queue_undelivered(peer, &results, &network_msg).await;
+    Ok(results.remove(0))
+}
+
+/// Tell every peer we are leaving, then close all connections.
+pub async fn broadcast_exit(peer: &Peer) -> Vec<DeliveryResult> {
+    let exit_msg = NetworkMessage::Exit(peer.peer_id.clone());
+    let targets = snapshot_peers(peer).await;
+    let results = fan_out(peer, targets, &exit_msg).await;
+    peer.connections.close_all().await;
+    results
+}
+
+/// Keep `msg` in the outbox for every peer it couldn't be written to.
+async fn queue_undelivered(peer: &Peer, results: &[DeliveryResult], msg: &NetworkMessage) {
+    let mut outbox = peer.outbox.lock().await;
+    for result in results.iter().filter(|r| !r.is_delivered()) {
+        if let Err(e) = outbox.enqueue(&result.peer.id, msg.clone()) {
+            peer.warn(format!(
+                "Failed to queue message for {}: {}",
+                result.peer.name, e
+            ));
         }
-        if let Ok(mut stream) = TcpStream::connect((peer.ip, peer.port)).await {
-            if stream.write_all(&msg_bytes).await.is_ok() {
-                successful_sends += 1;
+    }
+}
+
+/// Start waiting for acknowledgements of a message from each of `targets`.
+async fn track(
+    peer: &Peer,
+    message_id: &str,
+    summary: &str,
+    msg: &NetworkMessage,
+    targets: &[PeerInfo],
+) {
+    let recipients = targets
+        .iter()
+        .map(|info| (info.id.clone(), info.name.clone()));
+    peer.receipts
+        .lock()
+        .await
+        .track(message_id, summary, msg.clone(), recipients);
+}
+
+/// Copy the valid entries out of the peer map so no lock is held during network I/O.
+pub async fn snapshot_peers(peer: &Peer) -> Vec<PeerInfo> {
+    let peers = peer.peers.lock().await;
+    peers
+        .values()
+        .filter(|info| {
+            if !info.is_valid() {
+                peer.warn(format!("Skipping invalid peer: {:?}", info));
+                return false;
             }
+            true
+        })
+        .cloned()
+        .collect()
+}
+
+/// Send `msg` to every target concurrently, each bounded by `SEND_TIMEOUT`.
+pub async fn fan_out(
+    peer: &Peer,
+    targets: Vec<PeerInfo>,
+    msg: &NetworkMessage,
+) -> Vec<DeliveryResult> {
+    let sends = targets.into_iter().map(|info| async move {
+        let outcome = match timeout(SEND_TIMEOUT, peer.connections.send(&info, msg)).await {
+            Ok(result) => result,
+            Err(_) => Err(ChatError::Network(format!(
+                "timed out after {:?}",
+                SEND_TIMEOUT
+            ))),
+        };
+        DeliveryResult {
+            peer: info,
+            outcome,
         }
-    }
-    if successful_sends > 0 {
-        println!("📤 Message sent to {} peer(s)", successful_sends);
-    } else {
-        println!("📭 No peers available to receive the message");
-    }
-    Ok(())
+    });
+    join_all(sends).await
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::network::secure::{handshake_responder, NoiseKeys};
     use crate::peer::PeerInfo;
     use std::net::IpAddr;
     use std::str::FromStr;
 
     #[test]
     fn test_peerinfo_is_valid_for_broadcast() {
-        let valid_peer = PeerInfo {
-            id: "id1".to_string(),
-            name: "Peer1".to_string(),
-            ip: IpAddr::from_str("192.168.1.10").unwrap(),
-            port: 9000,
-        };
+        let valid_peer = PeerInfo::new(
+            "id1".to_string(),
+            "Peer1".to_string(),
+            IpAddr::from_str("192.168.1.10").unwrap(),
+            9000,
+        );
         assert!(valid_peer.is_valid());
 
-        let invalid_peer = PeerInfo {
-            id: "".to_string(),
-            name: "".to_string(),
-            ip: IpAddr::from_str("0.0.0.0").unwrap(),
-            port: 0,
-        };
+        let invalid_peer = PeerInfo::new(
+            "".to_string(),
+            "".to_string(),
+            IpAddr::from_str("0.0.0.0").unwrap(),
+            0,
+        );
         assert!(!invalid_peer.is_valid());
     }
+
+    #[tokio::test]
+    async fn test_fan_out_reports_each_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let identity = Identity::generate();
+        let targets = vec![
+            PeerInfo::new(
+                identity.peer_id(),
+                "Live".to_string(),
+                live.ip(),
+                live.port(),
+            ),
+            PeerInfo::new(
+                "dead".to_string(),
+                "Dead".to_string(),
+                dead.ip(),
+                dead.port(),
+            ),
+        ];
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let keys = NoiseKeys::new(&identity);
+            let _session = handshake_responder(&mut stream, &keys).await.unwrap();
+            // Hold the stream open until the test finishes
+            let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut [0u8; 1]).await;
+        });
+        let peer = Peer::new("Tester".to_string(), 9000);
+        let msg = NetworkMessage::Heartbeat(peer.peer_id.clone());
+        let results = fan_out(&peer, targets, &msg).await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_delivered());
+        assert!(!results[1].is_delivered());
+    }
 }
@@ -0,0 +1,534 @@
+//! Connection manager module: Keeps one long-lived TCP stream per peer and reuses it for all outgoing traffic.
+//!
+//! Instead of opening a new `TcpStream` for every message, the `ConnectionManager` owned by `Peer`
+//! keeps one bidirectional stream per peer. Whichever side connects first runs the Noise handshake
+//! from `network::secure`; we lazily connect to a `PeerInfo` on the first send, and a chat stream a
+//! peer opens to us is handed over by the listener (`serve_inbound`). Either way the encrypting
+//! write half is kept for later sends and the read half goes to `handle_tcp_connection`, so
+//! anything the remote sends on the same stream is processed like any other incoming traffic. A
+//! stream whose handshake proves a different peer ID than the `PeerInfo` we dialed is closed right
+//! away.
+//!
+//! When two peers dial each other at the same time, each ends up with a stream it opened and one
+//! it accepted. Both keep the stream opened by the peer with the lower peer ID and shut down their
+//! write half of the other, so they settle on the same stream. When a write fails the stream is
+//! dropped and the next send reconnects, waiting out an exponential backoff after repeated
+//! failures. Messages that still can't be sent wait in the `Outbox` until `flush_outbox` is called
+//! for their peer.
+
+use crate::chat::events::ChatEvent;
+use crate::error::ChatError;
+use crate::network::secure::{handshake_initiator, NoiseKeys, SecureReader, SecureWriter};
+use crate::network::tcp::{check_trust, handle_tcp_connection, ConnectionContext};
+use crate::peer::{NetworkMessage, PeerInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long opening a stream, TCP connect and Noise handshake together, may take.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Connection {
+    addr: SocketAddr,
+    writer: Option<SecureWriter<OwnedWriteHalf>>,
+    /// Whether we opened `writer`'s stream, as opposed to accepting it.
+    outbound: bool,
+    /// Number of `writer`'s stream, so its reader can tell whether it is still in use when it ends.
+    stream: u64,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            writer: None,
+            outbound: true,
+            stream: 0,
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    fn record_failure(&mut self) {
+        self.writer = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    fn record_success(
+        &mut self,
+        writer: SecureWriter<OwnedWriteHalf>,
+        outbound: bool,
+        stream: u64,
+    ) {
+        self.writer = Some(writer);
+        self.outbound = outbound;
+        self.stream = stream;
+        self.backoff = INITIAL_BACKOFF;
+        self.retry_at = None;
+    }
+}
+
+pub struct ConnectionManager {
+    connections: Mutex<HashMap<String, Arc<Mutex<Connection>>>>,
+    ctx: ConnectionContext,
+    noise_keys: Arc<NoiseKeys>,
+    /// Numbers the chat streams, starting at 1.
+    next_stream: AtomicU64,
+}
+
+impl ConnectionManager {
+    pub fn new(ctx: ConnectionContext, noise_keys: Arc<NoiseKeys>) -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            ctx,
+            noise_keys,
+            next_stream: AtomicU64::new(1),
+        }
+    }
+
+    /// Send a message to `peer` over its persistent stream, connecting or reconnecting as needed.
+    pub async fn send(&self, peer: &PeerInfo, msg: &NetworkMessage) -> Result<(), ChatError> {
+        let addr = SocketAddr::new(peer.ip, peer.port);
+        let conn = self.entry(&peer.id, addr).await;
+        let mut conn = conn.lock().await;
+        if conn.addr != addr {
+            // The peer moved. A stream we opened to the old address is no use any more, while one
+            // it opened to us still is
+            conn.addr = addr;
+            if conn.outbound {
+                *conn = Connection::new(addr);
+            }
+        }
+
+        if let Some(writer) = conn.writer.as_mut() {
+            if writer.write_message(msg).await.is_ok() {
+                return Ok(());
+            }
+            // Stale stream: fall through and try one fresh connection
+            conn.writer = None;
+        }
+
+        if let Some(retry_at) = conn.retry_at {
+            if Instant::now() < retry_at {
+                return Err(ChatError::Network(format!(
+                    "backing off reconnect to {} for {:?}",
+                    addr,
+                    retry_at - Instant::now()
+                )));
+            }
+        }
+
+        let (mut writer, stream) = match self.connect(peer).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                conn.record_failure();
+                return Err(e);
+            }
+        };
+        match writer.write_message(msg).await {
+            Ok(()) => {
+                conn.record_success(writer, true, stream);
+                Ok(())
+            }
+            Err(e) => {
+                conn.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    /// Send `msg` to `peer` from a background task, logging failures. Connection handlers use this
+    /// to reply to the remote: they are themselves spawned by `connect`, so awaiting `send`
+    /// directly would make the two futures' types depend on each other.
+    pub fn send_in_background(self: &Arc<Self>, peer: PeerInfo, msg: NetworkMessage) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = manager.send(&peer, &msg).await {
+                manager
+                    .ctx
+                    .warn(format!("Failed to send to {}: {}", peer.name, e));
+            }
+        });
+    }
+
+    /// Deliver everything queued in the outbox for `peer`, oldest first, from a background task.
+    /// Whatever still can't be sent goes back to the front of the queue.
+    pub fn flush_outbox(self: &Arc<Self>, peer: PeerInfo) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let queued = match manager.ctx.outbox.lock().await.take(&peer.id) {
+                Ok(queued) => queued,
+                Err(e) => {
+                    manager.ctx.warn(format!("Failed to update outbox: {}", e));
+                    return;
+                }
+            };
+            if queued.is_empty() {
+                return;
+            }
+            let total = queued.len();
+            let mut queued = queued.into_iter();
+            while let Some(msg) = queued.next() {
+                if let Err(e) = manager.send(&peer, &msg).await {
+                    manager
+                        .ctx
+                        .warn(format!("Could not flush outbox to {}: {}", peer.name, e));
+                    let unsent = std::iter::once(msg).chain(queued).collect();
+                    if let Err(e) = manager.ctx.outbox.lock().await.requeue(&peer.id, unsent) {
+                        manager.ctx.warn(format!("Failed to update outbox: {}", e));
+                    }
+                    return;
+                }
+            }
+            let _ = manager
+                .ctx
+                .events
+                .send(ChatEvent::OutboxFlushed { peer, count: total });
+        });
+    }
+
+    /// Serve a chat stream a peer opened to us: send to that peer over it from now on (see the
+    /// module docs for which stream wins if we have one already), and read it until it closes.
+    pub async fn serve_inbound(
+        &self,
+        reader: SecureReader<OwnedReadHalf>,
+        writer: SecureWriter<OwnedWriteHalf>,
+        addr: SocketAddr,
+    ) -> Result<(), ChatError> {
+        let peer_id = reader.peer_id().to_string();
+        let stream = self.register(&peer_id, addr, writer, false).await;
+        let result = handle_tcp_connection(reader, addr, self.ctx.clone()).await;
+        self.forget(&peer_id, stream).await;
+        result
+    }
+
+    /// Keep a stream we opened with `open_stream_to` as the chat stream to whoever answered.
+    pub async fn adopt(
+        &self,
+        reader: SecureReader<OwnedReadHalf>,
+        writer: SecureWriter<OwnedWriteHalf>,
+        addr: SocketAddr,
+    ) {
+        let stream = self.register(reader.peer_id(), addr, writer, true).await;
+        self.spawn_reader(reader, addr, stream);
+    }
+
+    /// Use `writer` to send to `peer_id` from now on, unless the peer already has a stream opened
+    /// the other way that wins the tie-break. The stream that isn't used is shut down. Returns
+    /// the number given to `writer`'s stream.
+    async fn register(
+        &self,
+        peer_id: &str,
+        addr: SocketAddr,
+        writer: SecureWriter<OwnedWriteHalf>,
+        outbound: bool,
+    ) -> u64 {
+        let stream = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        let conn = self.entry(peer_id, addr).await;
+        let mut conn = conn.lock().await;
+        // Both ends apply the same rule, so they keep the same stream: the one opened by the
+        // lower peer ID
+        let keep_outbound = self.ctx.peer_id.as_str() < peer_id;
+        let unused =
+            if conn.writer.is_some() && conn.outbound != outbound && outbound != keep_outbound {
+                Some(writer)
+            } else {
+                let replaced = conn.writer.take();
+                conn.record_success(writer, outbound, stream);
+                replaced
+            };
+        drop(conn);
+        if let Some(mut unused) = unused {
+            let _ = unused.shutdown().await;
+        }
+        stream
+    }
+
+    /// Forget `stream` once the remote has closed it, unless another stream has replaced it.
+    async fn forget(&self, peer_id: &str, stream: u64) {
+        let conn = self.connections.lock().await.get(peer_id).cloned();
+        if let Some(conn) = conn {
+            let mut conn = conn.lock().await;
+            if conn.stream == stream {
+                conn.writer = None;
+            }
+        }
+    }
+
+    async fn entry(&self, peer_id: &str, addr: SocketAddr) -> Arc<Mutex<Connection>> {
+        self.connections
+            .lock()
+            .await
+            .entry(peer_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Connection::new(addr))))
+            .clone()
+    }
+
+    /// Close and forget the stream to a single peer, e.g. once it has left.
+    pub async fn disconnect(&self, peer_id: &str) {
+        let conn = self.connections.lock().await.remove(peer_id);
+        if let Some(conn) = conn {
+            if let Some(mut writer) = conn.lock().await.writer.take() {
+                let _ = writer.shutdown().await;
+            }
+        }
+    }
+
+    /// Shut down every open stream, e.g. after broadcasting our exit.
+    pub async fn close_all(&self) {
+        let connections: Vec<_> = self.connections.lock().await.drain().collect();
+        for (_, conn) in connections {
+            if let Some(mut writer) = conn.lock().await.writer.take() {
+                let _ = writer.shutdown().await;
+            }
+        }
+    }
+
+    /// Open a separate secure stream to `peer` that isn't shared with the chat traffic, e.g. for a
+    /// file transfer. The caller reads and writes it directly.
+    pub async fn open_stream(
+        &self,
+        peer: &PeerInfo,
+    ) -> Result<(SecureReader<OwnedReadHalf>, SecureWriter<OwnedWriteHalf>), ChatError> {
+        let (reader, writer) = self
+            .open_stream_to(SocketAddr::new(peer.ip, peer.port))
+            .await?;
+        expect_peer(&reader, peer)?;
+        Ok((reader, writer))
+    }
+
+    /// Like `open_stream`, for an address whose peer we don't know yet; `SecureReader::peer_id`
+    /// tells who answered.
+    pub async fn open_stream_to(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(SecureReader<OwnedReadHalf>, SecureWriter<OwnedWriteHalf>), ChatError> {
+        self.handshake(addr).await
+    }
+
+    async fn handshake(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(SecureReader<OwnedReadHalf>, SecureWriter<OwnedWriteHalf>), ChatError> {
+        let dial = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            let session = handshake_initiator(&mut stream, &self.noise_keys).await?;
+            Ok::<_, ChatError>((stream, session))
+        };
+        let (stream, session) = timeout(DIAL_TIMEOUT, dial)
+            .await
+            .map_err(|_| ChatError::Network(format!("connecting to {} timed out", addr)))??;
+        check_trust(&self.ctx, &session).await;
+        let (reader, writer) = stream.into_split();
+        Ok((
+            SecureReader::new(reader, &session),
+            SecureWriter::new(writer, &session),
+        ))
+    }
+
+    /// Open a chat stream to `peer`, returning its write half and number.
+    async fn connect(
+        &self,
+        peer: &PeerInfo,
+    ) -> Result<(SecureWriter<OwnedWriteHalf>, u64), ChatError> {
+        let addr = SocketAddr::new(peer.ip, peer.port);
+        let (reader, writer) = self.handshake(addr).await?;
+        expect_peer(&reader, peer)?;
+        let stream = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        self.spawn_reader(reader, addr, stream);
+        Ok((writer, stream))
+    }
+
+    /// Handle what the remote sends on a stream we opened, forgetting the stream when it closes.
+    fn spawn_reader(&self, reader: SecureReader<OwnedReadHalf>, addr: SocketAddr, stream: u64) {
+        let ctx = self.ctx.clone();
+        let peer_id = reader.peer_id().to_string();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = handle_tcp_connection(reader, addr, ctx.clone()) => {
+                    if let Err(e) = result {
+                        ctx.warn(format!("Error reading from peer connection {}: {}", addr, e));
+                    }
+                }
+                _ = ctx.shutdown.cancelled() => return,
+            }
+            if let Some(connections) = ctx.connections.upgrade() {
+                connections.forget(&peer_id, stream).await;
+            }
+        });
+    }
+}
+
+/// Fail unless the handshake on `reader`'s stream proved it is `peer`.
+fn expect_peer<R: AsyncRead + Unpin>(
+    reader: &SecureReader<R>,
+    peer: &PeerInfo,
+) -> Result<(), ChatError> {
+    if reader.peer_id() == peer.id {
+        return Ok(());
+    }
+    Err(ChatError::Crypto(format!(
+        "{}:{} isn't {}, it proved to be {}",
+        peer.ip,
+        peer.port,
+        peer.name,
+        reader.peer_id()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::net::listener::start_tcp_listener;
+    use crate::chat::Peer;
+    use crate::identity::Identity;
+    use crate::network::secure::handshake_responder;
+    use tokio::net::TcpListener;
+
+    fn manager() -> Arc<ConnectionManager> {
+        Peer::new("Me".to_string(), 9000).connections
+    }
+
+    #[tokio::test]
+    async fn test_messages_reuse_one_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let identity = Identity::generate();
+        let peer = PeerInfo::new(
+            identity.peer_id(),
+            "Remote".to_string(),
+            local.ip(),
+            local.port(),
+        );
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let keys = NoiseKeys::new(&identity);
+            let session = handshake_responder(&mut stream, &keys).await.unwrap();
+            let mut reader = SecureReader::new(stream, &session);
+            let mut received = 0;
+            while reader.read_frame().await.unwrap().is_some() {
+                received += 1;
+            }
+            received
+        });
+        let manager = manager();
+        manager
+            .send(&peer, &NetworkMessage::Heartbeat("me".to_string()))
+            .await
+            .unwrap();
+        manager
+            .send(&peer, &NetworkMessage::Exit("me".to_string()))
+            .await
+            .unwrap();
+        manager.close_all().await;
+        // Both messages arrived on the one accepted stream, which then closed
+        assert_eq!(remote.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_connect_backs_off() {
+        // Bind then drop to get a port with nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let peer = PeerInfo::new(
+            "gone".to_string(),
+            "Gone".to_string(),
+            addr.ip(),
+            addr.port(),
+        );
+        let manager = manager();
+        let msg = NetworkMessage::Heartbeat("me".to_string());
+        assert!(manager.send(&peer, &msg).await.is_err());
+        let err = manager.send(&peer, &msg).await.unwrap_err();
+        assert!(err.to_string().contains("backing off"));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_peer_with_other_identity() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        // Whoever listens there isn't the peer we think we are dialing
+        let expected = PeerInfo::new(
+            Identity::generate().peer_id(),
+            "Alice".to_string(),
+            local.ip(),
+            local.port(),
+        );
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let keys = NoiseKeys::new(&Identity::generate());
+            let _session = handshake_responder(&mut stream, &keys).await;
+            let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut [0u8; 1]).await;
+        });
+        let manager = manager();
+        let err = manager
+            .send(&expected, &NetworkMessage::Heartbeat("me".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ChatError::Crypto(_)));
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_dials_settle_on_one_stream() {
+        let mut peers = Vec::new();
+        for name in ["Alice", "Bob"] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let peer = Peer::new(name.to_string(), port);
+            let serving = peer.clone();
+            tokio::spawn(async move { start_tcp_listener(&serving, listener).await });
+            peers.push(peer);
+        }
+        let info = |peer: &Peer| {
+            PeerInfo::new(
+                peer.peer_id.clone(),
+                "Peer".to_string(),
+                "127.0.0.1".parse().unwrap(),
+                peer.port,
+            )
+        };
+        let (alice, bob) = (&peers[0], &peers[1]);
+        let (to_alice, to_bob) = (info(alice), info(bob));
+        let msg = NetworkMessage::Heartbeat("hi".to_string());
+        let (a, b) = tokio::join!(
+            alice.connections.send(&to_bob, &msg),
+            bob.connections.send(&to_alice, &msg)
+        );
+        a.unwrap();
+        b.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Both keep the stream the lower peer ID opened
+        let kept = |peer: &Peer, other: &Peer| {
+            let connections = peer.connections.clone();
+            let other_id = other.peer_id.clone();
+            async move {
+                let conn = connections.connections.lock().await[&other_id].clone();
+                let conn = conn.lock().await;
+                assert!(conn.writer.is_some());
+                conn.outbound
+            }
+        };
+        let alice_lower = alice.peer_id < bob.peer_id;
+        assert_eq!(kept(alice, bob).await, alice_lower);
+        assert_eq!(kept(bob, alice).await, !alice_lower);
+
+        // And it still carries traffic both ways
+        alice.connections.send(&to_bob, &msg).await.unwrap();
+        bob.connections.send(&to_alice, &msg).await.unwrap();
+    }
+}
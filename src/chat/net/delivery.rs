@@ -0,0 +1,75 @@
+//! Delivery module: Sends acknowledgements for the messages we receive and retries the ones we sent.
+//!
+//! A receiver answers every `Chat` or `Direct` message with a `Delivered` ack once it has been
+//! displayed, and with a `Read` ack after its user next types something. On the sending side the
+//! retry loop periodically re-sends messages to recipients that haven't acknowledged them, using
+//! the state kept in `chat::receipts`. Receivers recognise retries by message ID and only ack them.
+
+use crate::chat::events::ChatEvent;
+use crate::chat::receipts::DeliveryStatus;
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::network::tcp::ConnectionContext;
+use crate::peer::{Ack, AckKind, NetworkMessage};
+use tokio::time::{sleep, Duration};
+
+/// How long a recipient has to acknowledge before the message is sent again.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts per recipient, including the first send, before giving up.
+pub const MAX_ATTEMPTS: u32 = 5;
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Acknowledge message `message_id` to its author `to_id`, if we know where to reach them.
+pub async fn send_ack(ctx: &ConnectionContext, to_id: &str, message_id: &str, kind: AckKind) {
+    let Some(target) = ctx.peers.lock().await.get(to_id).cloned() else {
+        return;
+    };
+    let Some(connections) = ctx.connections.upgrade() else {
+        return;
+    };
+    let ack = NetworkMessage::Ack(Ack {
+        message_id: message_id.to_string(),
+        from_id: ctx.peer_id.clone(),
+        kind,
+    });
+    connections.send_in_background(target, ack);
+}
+
+/// Send read receipts for every message displayed since the last call.
+pub async fn send_read_receipts(peer: &Peer) {
+    let unread = peer.receipts.lock().await.take_unread();
+    if unread.is_empty() {
+        return;
+    }
+    let ctx = peer.connection_context();
+    for (sender_id, message_id) in unread {
+        send_ack(&ctx, &sender_id, &message_id, AckKind::Read).await;
+    }
+}
+
+/// Periodically re-send messages that some recipients haven't acknowledged yet.
+pub async fn start_retry_loop(peer: &Peer) -> Result<(), ChatError> {
+    loop {
+        sleep(RETRY_INTERVAL).await;
+        let plan = peer
+            .receipts
+            .lock()
+            .await
+            .due_retries(ACK_TIMEOUT, MAX_ATTEMPTS);
+        for failed in plan.failed {
+            let _ = peer.events.send(ChatEvent::DeliveryStatus {
+                message_id: failed.message_id,
+                peer_id: failed.peer_id,
+                peer_name: failed.peer_name,
+                status: DeliveryStatus::Failed,
+            });
+        }
+        for (peer_id, message) in plan.resend {
+            // A recipient that has left still uses up its attempts, so it eventually counts as failed
+            let Some(target) = peer.peers.lock().await.get(&peer_id).cloned() else {
+                continue;
+            };
+            peer.connections.send_in_background(target, message);
+        }
+    }
+}
@@ -1,144 +1,282 @@
-//! Peer discovery module: Handles mDNS-based peer discovery and advertisement for the P2P Chat network.
+//! Peer discovery module: Runs the configured discovery backends and adds the peers they find.
 //!
-//! This module is responsible for discovering and advertising peers in the Chat network using mDNS.
-//! It handles both the sending and receiving of peer information, as well as the management of discovered peers.
+//! Every way of finding peers implements `Discovery`: it runs until shutdown and reports each peer
+//! it finds or loses through a `Reporter`. `start_discovery` runs the backends selected in
+//! `DiscoveryConfig` side by side and handles their reports in one place. A new peer is added to
+//! the peer map, sent our `Discovery` message and asked for history we missed; a known one is
+//! only marked as seen; a lost one is removed. The backends live in `net::mdns`, `net::beacon`,
+//! `net::static_peers` and `net::registry`.
+//!
+//! `introduce` connects to a bare address and swaps `Discovery` messages with whoever listens
+//! there, so a peer can be found knowing nothing but its address. The listener answers the first
+//! `Discovery` on every incoming connection with its own. `connect` does the same for an address
+//! typed by the user (`/connect`) and adds the peer right away.
 
+use crate::chat::events::{ChatEvent, DiscoveryMethod, LeaveReason};
+use crate::chat::net::beacon::BeaconDiscovery;
+use crate::chat::net::mdns::MdnsDiscovery;
+use crate::chat::net::registry::RegistryDiscovery;
+use crate::chat::net::static_peers::StaticDiscovery;
+use crate::chat::net::sync::sync_request;
 use crate::chat::Peer;
+use crate::config::{DiscoveryBackend, DiscoveryConfig};
 use crate::error::ChatError;
 use crate::peer::{NetworkMessage, PeerInfo};
-use futures_util::{pin_mut, stream::StreamExt};
-use libmdns;
-use mdns::{Record, RecordKind};
-use std::{net::IpAddr, sync::Arc, time::Duration};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-
-const SERVICE_NAME: &str = "_chat._udp.local";
-
-pub async fn start_mdns(peer: Arc<Peer>) -> Result<(), ChatError> {
-    // Spawn advertisement in a blocking thread
-    let peer_ad = peer.clone();
-    tokio::task::spawn_blocking(move || {
-        let responder = libmdns::Responder::new().unwrap();
-        let _svc = responder.register(
-            "_chat._udp".to_owned(),
-            format!("{}-{}", peer_ad.name, peer_ad.peer_id),
-            peer_ad.port,
-            &[&format!("peer_id={}", peer_ad.peer_id)],
-        );
-        loop {
-            std::thread::sleep(std::time::Duration::from_secs(10));
-        }
-    });
+use futures_util::future::BoxFuture;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+
+/// How long `introduce` waits for the other side's `Discovery`.
+const INTRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a backend found out.
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    /// A peer is reachable at the address in its `PeerInfo`.
+    Found(PeerInfo),
+    /// The peer with this ID is gone.
+    Lost(String),
+}
+
+/// Where a backend reports what it finds; tags each report with the backend's method.
+#[derive(Clone)]
+pub struct Reporter {
+    method: DiscoveryMethod,
+    events: mpsc::Sender<(DiscoveryMethod, DiscoveryEvent)>,
+}
+
+impl Reporter {
+    pub async fn found(&self, info: PeerInfo) {
+        let _ = self
+            .events
+            .send((self.method, DiscoveryEvent::Found(info)))
+            .await;
+    }
+
+    pub async fn lost(&self, peer_id: String) {
+        let _ = self
+            .events
+            .send((self.method, DiscoveryEvent::Lost(peer_id)))
+            .await;
+    }
+}
+
+/// A way of finding peers.
+pub trait Discovery: Send {
+    /// Shown as how a peer was found.
+    fn method(&self) -> DiscoveryMethod;
+
+    /// Look for peers and report them to `reporter`. The future is dropped on shutdown, so it
+    /// only returns early if the backend fails.
+    fn run(
+        self: Box<Self>,
+        peer: Arc<Peer>,
+        reporter: Reporter,
+    ) -> BoxFuture<'static, Result<(), ChatError>>;
+}
 
-    // Discovery
-    let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))
-        .map_err(|e| ChatError::Network(e.to_string()))?
-        .listen();
-    pin_mut!(stream);
-    while let Some(Ok(response)) = stream.next().await {
-        let addr = response.records().filter_map(to_ip_addr).next();
-        let peer_name = response
-            .records()
-            .find_map(|r| match &r.kind {
-                RecordKind::PTR(name) => Some(name.to_string()),
-                _ => None,
-            })
-            .unwrap_or_else(|| "unknown".to_string());
-        let peer_id = response
-            .records()
-            .find_map(|r| match &r.kind {
-                RecordKind::TXT(ref txts) => {
-                    for txt in txts {
-                        if let Some(id) = txt.strip_prefix("peer_id=") {
-                            return Some(id.to_string());
-                        }
-                    }
-                    None
+/// The backends selected in `config`, ready for `start_discovery`.
+pub fn backends(config: &DiscoveryConfig) -> Vec<Box<dyn Discovery>> {
+    config
+        .methods
+        .iter()
+        .map(|backend| -> Box<dyn Discovery> {
+            match backend {
+                DiscoveryBackend::Mdns => Box::new(MdnsDiscovery),
+                DiscoveryBackend::Beacon => Box::new(BeaconDiscovery),
+                DiscoveryBackend::Static => Box::new(StaticDiscovery::new(config.peers.clone())),
+                DiscoveryBackend::Registry => {
+                    Box::new(RegistryDiscovery::new(config.registry.clone()))
                 }
-                _ => None,
-            })
-            .unwrap_or_else(|| peer_name.clone());
-        // Extract port from SRV record if available
-        let peer_port = response
-            .records()
-            .find_map(|r| match &r.kind {
-                RecordKind::SRV { port, .. } => Some(*port),
-                _ => None,
-            })
-            // fallback to our port if not found
-            .unwrap_or(peer.port);
-        // Validate peer_id and port
-        if peer_id.is_empty() || peer_port == 0 {
-            eprint!("⚠️  Warning: Discovered peer has invalid ID or port.");
-            continue; // Skip invalid peer
-        }
-        // Validate peer_name (non-empty, reasonable length)
-        if peer_name.trim().is_empty() || peer_name.len() > 128 {
-            eprint!("⚠️  Warning: Discovered peer has invalid name.");
-            continue; // Skip invalid peer name
-        }
-        if let Some(ip) = addr {
-            // Ignore self
-            if peer_id == peer.peer_id {
-                continue;
             }
-            // Validate IP address (skip loopback and multicast)
-            if ip.is_loopback() || ip.is_multicast() {
-                eprint!("⚠️  Warning: Discovered peer has invalid IP address.");
-                continue;
-            }
-            let peer_info = PeerInfo {
-                id: peer_id.clone(),
-                name: peer_name.clone(),
-                ip,
-                port: peer_port, // Use discovered port
-            };
-            if !peer_info.is_valid() {
-                eprint!(
-                    "⚠️  Warning: Discovered peer has invalid PeerInfo. {:?}",
-                    peer_info
-                );
-                continue;
+        })
+        .collect()
+}
+
+/// Run `backends` and apply what they report until one of them fails. Without any backends
+/// this waits forever, so it can sit next to the other services.
+pub async fn start_discovery(
+    peer: Arc<Peer>,
+    backends: Vec<Box<dyn Discovery>>,
+) -> Result<(), ChatError> {
+    let (events, mut reports) = mpsc::channel(64);
+    let mut running = JoinSet::new();
+    for backend in backends {
+        let method = backend.method();
+        let reporter = Reporter {
+            method,
+            events: events.clone(),
+        };
+        let run = backend.run(peer.clone(), reporter);
+        running.spawn(async move { (method, run.await) });
+    }
+    drop(events);
+    loop {
+        tokio::select! {
+            Some((method, event)) = reports.recv() => match event {
+                DiscoveryEvent::Found(info) => handle_found(&peer, info, method).await,
+                DiscoveryEvent::Lost(peer_id) => handle_lost(&peer, &peer_id, method).await,
+            },
+            Some(joined) = running.join_next() => {
+                let (method, result) = joined.map_err(|e| ChatError::Unknown(e.to_string()))?;
+                result.map_err(|e| ChatError::Network(format!("{} discovery: {}", method, e)))?;
             }
-            let mut peers = peer.peers.lock().await;
-            if !peers.contains_key(&peer_info.id) {
-                println!(
-                    "🔍 Discovered peer via mDNS: {} at {}:{}",
-                    peer_name, ip, peer_port
-                );
-                // Try to send our PeerInfo to the new peer via TCP
-                let my_info = PeerInfo {
-                    id: peer.peer_id.clone(),
-                    name: peer.name.clone(),
-                    ip, // fallback to discovered IP if local IP is not available
-                    port: peer.port,
-                };
-                if !my_info.is_valid() {
-                    println!(
-                        "⚠️  Warning: Our PeerInfo is invalid, not sending discovery message."
-                    );
-                    continue;
-                }
-                let msg = NetworkMessage::Discovery(my_info);
-                let socket_addr = std::net::SocketAddr::new(ip, peer_port);
-                let msg_bytes = serde_json::to_vec(&msg).unwrap();
-                tokio::spawn(async move {
-                    if let Ok(mut stream) = TcpStream::connect(socket_addr).await {
-                        let _ = stream.write_all(&msg_bytes).await;
-                    }
-                });
+            else => std::future::pending().await,
+        }
+    }
+}
+
+/// Add a peer a backend found, introducing ourselves if it is new.
+async fn handle_found(peer: &Peer, peer_info: PeerInfo, via: DiscoveryMethod) {
+    // Ignore self
+    if peer_info.id == peer.peer_id {
+        return;
+    }
+    if !peer_info.is_valid() {
+        peer.warn(format!(
+            "Discovered peer has invalid PeerInfo: {:?}",
+            peer_info
+        ));
+        return;
+    }
+    let mut peers = peer.peers.lock().await;
+    if let Some(known) = peers.get_mut(&peer_info.id) {
+        // Already known: keep what it has told us over TCP since and just note that it is
+        // still around
+        if known.touch() {
+            let _ = peer.events.send(ChatEvent::PeerBack(known.clone()));
+        }
+        if peer.outbox.lock().await.queued(&known.id) > 0 {
+            peer.connections.flush_outbox(known.clone());
+        }
+        return;
+    }
+    let _ = peer.events.send(ChatEvent::PeerJoined {
+        peer: peer_info.clone(),
+        via,
+    });
+    // Try to send our PeerInfo to the new peer via TCP
+    let my_info = peer.own_info().await;
+    if my_info.is_valid() {
+        let msg = NetworkMessage::Discovery(my_info);
+        // Then ask for any history we missed; the peer answers the same once it learns of us
+        let sync = sync_request(&peer.connection_context()).await;
+        let connections = peer.connections.clone();
+        let target = peer_info.clone();
+        tokio::spawn(async move {
+            if connections.send(&target, &msg).await.is_ok() {
+                let _ = connections.send(&target, &sync).await;
+                connections.flush_outbox(target);
             }
-            peers.insert(peer_info.id.clone(), peer_info);
+        });
+    } else {
+        peer.warn("Our PeerInfo is invalid, not sending discovery message".to_string());
+    }
+    peers.insert(peer_info.id.clone(), peer_info);
+}
+
+async fn handle_lost(peer: &Peer, peer_id: &str, via: DiscoveryMethod) {
+    if let Some(info) = peer.peers.lock().await.remove(peer_id) {
+        let _ = peer.events.send(ChatEvent::PeerLeft {
+            peer: info,
+            reason: LeaveReason::Lost(via),
+        });
+    }
+}
+
+/// Introduce ourselves to the peer at `addr` (`host:port`) and add it to the peer map, as if a
+/// backend had found it.
+pub async fn connect(peer: &Peer, addr: &str) -> Result<PeerInfo, ChatError> {
+    let info = introduce_to(peer, addr).await?;
+    handle_found(peer, info.clone(), DiscoveryMethod::Manual).await;
+    Ok(info)
+}
+
+/// Look up `addr` (`host:port`) and `introduce` ourselves to the first address it resolves to.
+pub async fn introduce_to(peer: &Peer, addr: &str) -> Result<PeerInfo, ChatError> {
+    let resolved = lookup_host(addr)
+        .await
+        .map_err(|e| ChatError::Network(format!("cannot resolve {}: {}", addr, e)))?
+        .next()
+        .ok_or_else(|| ChatError::Network(format!("{} doesn't resolve", addr)))?;
+    introduce(peer, resolved).await
+}
+
+/// Connect to `addr`, send our `Discovery` and return the `Discovery` the listener answers with,
+/// its address set to the one we reached it at. A listener that introduces itself as someone other
+/// than the handshake proved, turns out to be us or can't be reached at that address is refused,
+/// and the stream is dropped. Otherwise the stream is kept as our chat stream to the peer, and the
+/// listener adds us to its peers as well.
+pub async fn introduce(peer: &Peer, addr: SocketAddr) -> Result<PeerInfo, ChatError> {
+    let (mut reader, mut writer) = peer.connections.open_stream_to(addr).await?;
+    writer
+        .write_message(&NetworkMessage::Discovery(peer.own_info().await))
+        .await?;
+    let frame = timeout(INTRODUCE_TIMEOUT, reader.read_frame())
+        .await
+        .map_err(|_| ChatError::Network(format!("{} didn't answer", addr)))??
+        .ok_or_else(|| ChatError::Network(format!("{} closed the connection", addr)))?;
+    let mut info = match serde_json::from_slice(&frame)? {
+        NetworkMessage::Discovery(info) if info.id != reader.peer_id() => {
+            return Err(ChatError::Crypto(format!(
+                "{} introduced itself as {} but proved to be {}",
+                addr,
+                info.id,
+                reader.peer_id()
+            )));
+        }
+        NetworkMessage::Discovery(info) => info,
+        _ => {
+            return Err(ChatError::Network(format!(
+                "{} didn't introduce itself",
+                addr
+            )))
         }
+    };
+    info.ip = addr.ip();
+    if info.id == peer.peer_id {
+        return Err(ChatError::Network(format!("{} is this chat", addr)));
+    }
+    if !info.is_valid() {
+        return Err(ChatError::Network(format!(
+            "{} answered with unusable peer info (loopback addresses can't be used)",
+            addr
+        )));
     }
-    Ok(())
+    // The stream stays open as our chat stream to the peer
+    peer.connections.adopt(reader, writer, addr).await;
+    Ok(info)
 }
 
-fn to_ip_addr(record: &Record) -> Option<IpAddr> {
-    match record.kind {
-        RecordKind::A(addr) => Some(addr.into()),
-        RecordKind::AAAA(addr) => Some(addr.into()),
-        _ => None,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::net::listener::start_tcp_listener;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_introduce_refuses_unusable_peers() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bob = Peer::new("Bob".to_string(), addr.port());
+        let serving = bob.clone();
+        tokio::spawn(async move { start_tcp_listener(&serving, listener).await });
+
+        // Loopback addresses can't be broadcast to, so the stream isn't kept
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let result = introduce(&alice, addr).await;
+        assert!(matches!(result, Err(ChatError::Network(_))));
+        let result = connect(&alice, &format!("localhost:{}", addr.port())).await;
+        assert!(matches!(result, Err(ChatError::Network(_))));
+        assert!(alice.peers.lock().await.is_empty());
+
+        // Nor does a peer keep a stream to itself
+        let result = introduce(&bob, addr).await;
+        assert!(matches!(result, Err(ChatError::Network(_))));
     }
 }
@@ -1,9 +1,22 @@
+//! Heartbeat module: Periodically announces that we are alive and listens for other peers' heartbeats.
+//!
+//! Heartbeats are small `NetworkMessage::Heartbeat` datagrams broadcast over UDP. Every heartbeat
+//! received from a known peer refreshes its `last_seen` timestamp, which the liveness reaper in
+//! `net::liveness` uses to decide when a silent peer is stale or gone. A heartbeat from a peer with
+//! messages waiting in the outbox also triggers a flush.
+
+use crate::chat::events::ChatEvent;
 use crate::chat::Peer;
 use crate::error::ChatError;
 use crate::peer::NetworkMessage;
 use serde_json;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
 use tokio::net::UdpSocket;
-use tokio::time::{sleep, Duration};
+use tokio::time::sleep;
+
+/// UDP port heartbeats are broadcast to and received on.
+pub const HEARTBEAT_PORT: u16 = 9999;
 
 pub async fn start_heartbeat(peer: &Peer) -> Result<(), ChatError> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
@@ -11,9 +24,47 @@ pub async fn start_heartbeat(peer: &Peer) -> Result<(), ChatError> {
     loop {
         let heartbeat = NetworkMessage::Heartbeat(peer.peer_id.clone());
         let msg_bytes = serde_json::to_vec(&heartbeat)?;
-        if let Err(e) = socket.send_to(&msg_bytes, "255.255.255.255:9999").await {
-            eprintln!("Failed to send heartbeat: {}", e);
+        if let Err(e) = socket
+            .send_to(&msg_bytes, (Ipv4Addr::BROADCAST, HEARTBEAT_PORT))
+            .await
+        {
+            peer.warn(format!("Failed to send heartbeat: {}", e));
         }
-        sleep(Duration::from_secs(10)).await;
+        sleep(peer.liveness.heartbeat_interval).await;
     }
 }
+
+pub async fn start_heartbeat_listener(peer: &Peer) -> Result<(), ChatError> {
+    let socket = bind_shared(HEARTBEAT_PORT)?;
+    let mut buf = [0u8; 1024];
+    loop {
+        let (n, _addr) = socket.recv_from(&mut buf).await?;
+        let Ok(NetworkMessage::Heartbeat(peer_id)) = serde_json::from_slice(&buf[..n]) else {
+            continue;
+        };
+        if peer_id == peer.peer_id {
+            continue;
+        }
+        let mut peers = peer.peers.lock().await;
+        if let Some(info) = peers.get_mut(&peer_id) {
+            if info.touch() {
+                let _ = peer.events.send(ChatEvent::PeerBack(info.clone()));
+            }
+            if peer.outbox.lock().await.queued(&peer_id) > 0 {
+                peer.connections.flush_outbox(info.clone());
+            }
+        }
+    }
+}
+
+/// Bind a UDP socket that several local instances can share, so each one receives broadcasts.
+pub(crate) fn bind_shared(port: u16) -> Result<UdpSocket, ChatError> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
@@ -1,35 +1,96 @@
 //! TCP listener module: Listens for incoming TCP connections from peers and delegates connection handling.
 //!
-//! This module is responsible for starting a TCP listener on a specified port,
-//! accepting incoming TCP connections, and spawning a new task to handle each
-//! connection. It utilizes the `handle_tcp_connection` function from the
-//! `network::tcp` module to process the connections.
+//! This module is responsible for binding a TCP listener on a specified port,
+//! accepting incoming TCP connections, and spawning a new task that completes the Noise
+//! handshake, checks the peer's key against the known peers store and then handles each
+//! connection. A chat connection is handed to the `ConnectionManager`, which reads it with
+//! `handle_tcp_connection` and sends to the peer over it as well. Binding is separate
+//! from accepting so a busy port is reported before any other service starts. Handlers stop
+//! reading once the peer's shutdown token is cancelled. A connection whose first message is a
+//! `FileRequest` or `ChunkRequest` is a dedicated file transfer stream and is handed to
+//! `chat::net::transfer` or `chat::net::swarm`. A connection that starts with a `Discovery` is
+//! answered with our own, so a peer that only knows our address learns who we are (see
+//! `discovery::introduce`).
 
+use crate::chat::net::{swarm, transfer};
 use crate::chat::Peer;
-use crate::network::tcp::handle_tcp_connection;
-use colored::*;
+use crate::error::ChatError;
+use crate::network::secure::{handshake_responder, SecureReader, SecureWriter};
+use crate::network::tcp::{check_trust, handle_message, ConnectionContext, Remote};
+use crate::peer::NetworkMessage;
+use std::net::SocketAddr;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpListener;
 
-pub async fn start_tcp_listener(peer: &Peer) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = format!("0.0.0.0:{}", peer.port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!(
-        "🔗 TCP listener started on port {}",
-        peer.port.to_string().bright_blue()
-    );
+pub async fn bind_tcp_listener(port: u16) -> Result<TcpListener, ChatError> {
+    TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| ChatError::Network(format!("cannot listen on port {}: {}", port, e)))
+}
 
+pub async fn start_tcp_listener(peer: &Peer, listener: TcpListener) -> Result<(), ChatError> {
     loop {
-        let (stream, addr) = listener.accept().await?;
-        let peers = peer.peers.clone();
-        let message_sender = peer.message_sender.clone();
-        let peer_id = peer.peer_id.clone();
+        let (mut stream, addr) = listener.accept().await?;
+        let ctx = peer.connection_context();
+        let noise_keys = peer.noise_keys.clone();
+        let peer = peer.clone();
 
         tokio::spawn(async move {
-            if let Err(e) =
-                handle_tcp_connection(stream, addr, peers, message_sender, peer_id).await
-            {
-                eprintln!("Error handling TCP connection from {}: {}", addr, e);
+            let session = match handshake_responder(&mut stream, &noise_keys).await {
+                Ok(session) => session,
+                Err(e) => {
+                    ctx.warn(format!("Secure handshake with {} failed: {}", addr, e));
+                    return;
+                }
+            };
+            check_trust(&ctx, &session).await;
+            let (reader, writer) = stream.into_split();
+            let writer = SecureWriter::new(writer, &session);
+            let reader = SecureReader::new(reader, &session);
+            tokio::select! {
+                result = serve_connection(&peer, reader, writer, addr, ctx.clone()) => {
+                    if let Err(e) = result {
+                        ctx.warn(format!("Error handling TCP connection from {}: {}", addr, e));
+                    }
+                }
+                _ = ctx.shutdown.cancelled() => {}
             }
         });
     }
 }
+
+/// Serve an accepted connection: stream a file or share chunks if it asks for them, otherwise
+/// hand it to the connection manager as the peer's chat stream.
+async fn serve_connection(
+    peer: &Peer,
+    mut reader: SecureReader<OwnedReadHalf>,
+    mut writer: SecureWriter<OwnedWriteHalf>,
+    addr: SocketAddr,
+    ctx: ConnectionContext,
+) -> Result<(), ChatError> {
+    let Some(frame) = reader.read_frame().await? else {
+        return Ok(());
+    };
+    let remote = Remote {
+        addr,
+        peer_id: reader.peer_id().to_string(),
+    };
+    match serde_json::from_slice::<NetworkMessage>(&frame) {
+        Ok(NetworkMessage::FileRequest(request)) => {
+            transfer::serve_file(&ctx, request, writer).await;
+            return Ok(());
+        }
+        Ok(NetworkMessage::ChunkRequest(request)) => {
+            return swarm::serve_chunks(&ctx, request, reader, writer).await;
+        }
+        Ok(msg @ NetworkMessage::Discovery(_)) => {
+            handle_message(&ctx, &remote, msg).await;
+            writer
+                .write_message(&NetworkMessage::Discovery(peer.own_info().await))
+                .await?;
+        }
+        Ok(msg) => handle_message(&ctx, &remote, msg).await,
+        Err(_) => {}
+    }
+    peer.connections.serve_inbound(reader, writer, addr).await
+}
@@ -0,0 +1,136 @@
+//! Liveness module: Marks silent peers as stale and removes them once they time out.
+//!
+//! Every `PeerInfo` carries a `last_seen` timestamp refreshed by heartbeats, discovery and chat
+//! traffic. The reaper periodically scans the peer map: peers silent for longer than
+//! `stale_after` are flagged as stale, and peers silent for longer than `remove_after` are
+//! dropped from the map along with their connection. Each transition is published as a
+//! `ChatEvent`.
+
+use crate::chat::events::{ChatEvent, LeaveReason};
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::peer::PeerInfo;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+
+#[derive(Debug, Clone, Copy)]
+pub struct LivenessConfig {
+    /// How often we broadcast our own heartbeat.
+    pub heartbeat_interval: Duration,
+    /// Silence after which a peer is shown as stale.
+    pub stale_after: Duration,
+    /// Silence after which a peer is removed from the peer map.
+    pub remove_after: Duration,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(10),
+            stale_after: Duration::from_secs(30),
+            remove_after: Duration::from_secs(90),
+        }
+    }
+}
+
+/// What happened to a peer during one reaper pass.
+#[derive(Debug)]
+pub enum LivenessChange {
+    Stale(PeerInfo),
+    Removed(PeerInfo),
+}
+
+pub async fn start_peer_reaper(peer: &Peer) -> Result<(), ChatError> {
+    let mut ticker = interval((peer.liveness.stale_after / 3).max(Duration::from_secs(1)));
+    loop {
+        ticker.tick().await;
+        let changes = {
+            let mut peers = peer.peers.lock().await;
+            reap(&mut peers, &peer.liveness, Instant::now())
+        };
+        for change in changes {
+            let event = match change {
+                LivenessChange::Stale(info) => ChatEvent::PeerStale(info),
+                LivenessChange::Removed(info) => {
+                    peer.connections.disconnect(&info.id).await;
+                    ChatEvent::PeerLeft {
+                        peer: info,
+                        reason: LeaveReason::TimedOut,
+                    }
+                }
+            };
+            let _ = peer.events.send(event);
+        }
+    }
+}
+
+/// Apply the liveness timeouts to `peers` as of `now`, returning the transitions made.
+pub fn reap(
+    peers: &mut HashMap<String, PeerInfo>,
+    config: &LivenessConfig,
+    now: Instant,
+) -> Vec<LivenessChange> {
+    let mut changes = Vec::new();
+    peers.retain(|_, info| {
+        let silent_for = now.saturating_duration_since(info.last_seen);
+        if silent_for >= config.remove_after {
+            changes.push(LivenessChange::Removed(info.clone()));
+            return false;
+        }
+        if silent_for >= config.stale_after && !info.stale {
+            info.stale = true;
+            changes.push(LivenessChange::Stale(info.clone()));
+        }
+        true
+    });
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_reap_marks_stale_then_removes() {
+        let config = LivenessConfig {
+            heartbeat_interval: Duration::from_secs(10),
+            stale_after: Duration::from_secs(30),
+            remove_after: Duration::from_secs(90),
+        };
+        let info = PeerInfo::new(
+            "id1".to_string(),
+            "Alice".to_string(),
+            IpAddr::from_str("192.168.1.2").unwrap(),
+            9000,
+        );
+        let start = info.last_seen;
+        let mut peers = HashMap::from([(info.id.clone(), info)]);
+
+        assert!(reap(&mut peers, &config, start + Duration::from_secs(10)).is_empty());
+        let changes = reap(&mut peers, &config, start + Duration::from_secs(40));
+        assert!(matches!(&changes[..], [LivenessChange::Stale(p)] if p.id == "id1"));
+        assert!(peers["id1"].stale);
+        // Already stale: no repeated notice
+        assert!(reap(&mut peers, &config, start + Duration::from_secs(50)).is_empty());
+        let changes = reap(&mut peers, &config, start + Duration::from_secs(100));
+        assert!(matches!(&changes[..], [LivenessChange::Removed(p)] if p.id == "id1"));
+        assert!(peers.is_empty());
+    }
+
+    #[test]
+    fn test_touch_clears_stale() {
+        let mut info = PeerInfo::new(
+            "id1".to_string(),
+            "Alice".to_string(),
+            IpAddr::from_str("192.168.1.2").unwrap(),
+            9000,
+        );
+        info.stale = true;
+        assert!(info.touch());
+        assert!(!info.stale);
+        assert!(!info.touch());
+    }
+}
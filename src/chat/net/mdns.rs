@@ -0,0 +1,157 @@
+//! mDNS module: Discovery backend that advertises us and browses for peers over mDNS.
+//!
+//! We register a `_chat._udp` service whose TXT records carry our peer ID and channels, and browse
+//! for the same service from other peers. A goodbye packet (records with a TTL of zero), sent when
+//! a peer unregisters its service on shutdown, is reported as the peer being lost.
+
+use crate::chat::events::DiscoveryMethod;
+use crate::chat::net::discovery::{Discovery, Reporter};
+use crate::chat::{channels, Peer};
+use crate::error::ChatError;
+use crate::peer::PeerInfo;
+use futures_util::future::BoxFuture;
+use futures_util::{pin_mut, stream::StreamExt};
+use libmdns;
+use mdns::{Record, RecordKind};
+use std::collections::{BTreeSet, HashMap};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+const SERVICE_NAME: &str = "_chat._udp.local";
+
+/// Advertise our service over mDNS from a blocking thread until the peer's shutdown token is
+/// cancelled; the service is unregistered when the returned task finishes.
+pub async fn start_advertising(peer: &Peer) -> JoinHandle<()> {
+    // DNS labels are capped at 63 bytes, so only a prefix of the 64-char key-based ID
+    let name = format!("{}-{}", peer.name(), &peer.peer_id[..8]);
+    let port = peer.port;
+    let peer_id_txt = format!("peer_id={}", peer.peer_id);
+    let channels_txt = format!(
+        "channels={}",
+        join_channels(peer.channels.lock().await.joined())
+    );
+    let shutdown = peer.shutdown.clone();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let responder = libmdns::Responder::new().unwrap();
+        let service = responder.register(
+            "_chat._udp".to_owned(),
+            name,
+            port,
+            &[&peer_id_txt, &channels_txt],
+        );
+        runtime.block_on(shutdown.cancelled());
+        // Dropping the service sends the goodbye packet that unregisters it
+        drop(service);
+    })
+}
+
+/// Browses for other peers over mDNS. Advertising is started separately by `ChatNode`, which
+/// waits for the goodbye packet to go out before exiting.
+pub struct MdnsDiscovery;
+
+impl Discovery for MdnsDiscovery {
+    fn method(&self) -> DiscoveryMethod {
+        DiscoveryMethod::Mdns
+    }
+
+    fn run(
+        self: Box<Self>,
+        peer: Arc<Peer>,
+        reporter: Reporter,
+    ) -> BoxFuture<'static, Result<(), ChatError>> {
+        Box::pin(browse(peer, reporter))
+    }
+}
+
+async fn browse(peer: Arc<Peer>, reporter: Reporter) -> Result<(), ChatError> {
+    let stream = mdns::discover::all(SERVICE_NAME, Duration::from_secs(15))
+        .map_err(|e| ChatError::Network(e.to_string()))?
+        .listen();
+    pin_mut!(stream);
+    // Peer IDs by service instance name, to tell who a goodbye packet is from
+    let mut instances: HashMap<String, String> = HashMap::new();
+    while let Some(Ok(response)) = stream.next().await {
+        let addr = response.records().filter_map(to_ip_addr).next();
+        let ptr = response.records().find_map(|r| match &r.kind {
+            RecordKind::PTR(name) => Some((name.to_string(), r.ttl)),
+            _ => None,
+        });
+        if let Some((instance, 0)) = &ptr {
+            if let Some(peer_id) = instances.remove(instance) {
+                reporter.lost(peer_id).await;
+            }
+            continue;
+        }
+        let peer_name = ptr
+            .map(|(name, _)| name)
+            .unwrap_or_else(|| "unknown".to_string());
+        let peer_id = txt_value(&response, "peer_id").unwrap_or_else(|| peer_name.clone());
+        let peer_channels = txt_value(&response, "channels").map(|value| split_channels(&value));
+        // Extract port from SRV record if available
+        let peer_port = response
+            .records()
+            .find_map(|r| match &r.kind {
+                RecordKind::SRV { port, .. } => Some(*port),
+                _ => None,
+            })
+            // fallback to our port if not found
+            .unwrap_or(peer.port);
+        // Validate peer_id and port
+        if peer_id.is_empty() || peer_port == 0 {
+            peer.warn("Discovered peer has invalid ID or port".to_string());
+            continue; // Skip invalid peer
+        }
+        // Validate peer_name (non-empty, reasonable length)
+        if peer_name.trim().is_empty() || peer_name.len() > 128 {
+            peer.warn("Discovered peer has invalid name".to_string());
+            continue; // Skip invalid peer name
+        }
+        if let Some(ip) = addr {
+            // Ignore self
+            if peer_id == peer.peer_id {
+                continue;
+            }
+            // Validate IP address (skip loopback and multicast)
+            if ip.is_loopback() || ip.is_multicast() {
+                peer.warn("Discovered peer has invalid IP address".to_string());
+                continue;
+            }
+            // Use discovered port
+            let mut peer_info = PeerInfo::new(peer_id.clone(), peer_name.clone(), ip, peer_port);
+            if let Some(channels) = peer_channels {
+                peer_info.channels = channels;
+            }
+            instances.insert(peer_name, peer_id);
+            reporter.found(peer_info).await;
+        }
+    }
+    Ok(())
+}
+
+/// Find `key=value` in the response's TXT records.
+fn txt_value(response: &mdns::Response, key: &str) -> Option<String> {
+    let prefix = format!("{}=", key);
+    response.records().find_map(|r| match &r.kind {
+        RecordKind::TXT(txts) => txts
+            .iter()
+            .find_map(|txt| txt.strip_prefix(prefix.as_str()).map(str::to_string)),
+        _ => None,
+    })
+}
+
+fn join_channels(channels: &BTreeSet<String>) -> String {
+    channels.iter().cloned().collect::<Vec<_>>().join(",")
+}
+
+fn split_channels(value: &str) -> BTreeSet<String> {
+    value.split(',').filter_map(channels::normalize).collect()
+}
+
+fn to_ip_addr(record: &Record) -> Option<IpAddr> {
+    match record.kind {
+        RecordKind::A(addr) => Some(addr.into()),
+        RecordKind::AAAA(addr) => Some(addr.into()),
+        _ => None,
+    }
+}
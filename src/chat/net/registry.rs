@@ -0,0 +1,142 @@
+//! Registry module: Discovery backend that lists peers as files in a shared directory.
+//!
+//! Every peer writes its `PeerInfo` as JSON to `<registry>/<peer ID>.json` and removes the file
+//! again when it stops. The directory is scanned every `SCAN_INTERVAL`: a new or changed file is
+//! reported as found and a file that disappeared as lost. This needs no network support at all,
+//! which makes it handy for running several instances on one machine or in tests; any directory
+//! all instances can write to (including a network share) works.
+
+use crate::chat::events::DiscoveryMethod;
+use crate::chat::net::discovery::{Discovery, Reporter};
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::peer::PeerInfo;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::time::{sleep, Duration};
+
+/// Time between scans of the registry directory.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct RegistryDiscovery {
+    dir: PathBuf,
+}
+
+impl RegistryDiscovery {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Discovery for RegistryDiscovery {
+    fn method(&self) -> DiscoveryMethod {
+        DiscoveryMethod::Registry
+    }
+
+    fn run(
+        self: Box<Self>,
+        peer: Arc<Peer>,
+        reporter: Reporter,
+    ) -> BoxFuture<'static, Result<(), ChatError>> {
+        Box::pin(async move {
+            fs::create_dir_all(&self.dir).await?;
+            let entry = Entry(self.dir.join(format!("{}.json", peer.peer_id)));
+            // What each listed peer's file said when we last reported it
+            let mut listed: HashMap<String, String> = HashMap::new();
+            loop {
+                // Rewritten on every scan, so a changed name or channel list shows up
+                let own = serde_json::to_string(&peer.own_info().await)?;
+                write_entry(&entry.0, &own).await?;
+                let mut seen = HashMap::new();
+                let mut files = fs::read_dir(&self.dir).await?;
+                while let Some(file) = files.next_entry().await? {
+                    let path = file.path();
+                    if path == entry.0 || path.extension().is_none_or(|ext| ext != "json") {
+                        continue;
+                    }
+                    // Unreadable files are skipped; they may be half-written
+                    let Ok(text) = fs::read_to_string(&path).await else {
+                        continue;
+                    };
+                    let Ok(info) = serde_json::from_str::<PeerInfo>(&text) else {
+                        continue;
+                    };
+                    if listed.get(&info.id) != Some(&text) {
+                        reporter.found(info.clone()).await;
+                    }
+                    seen.insert(info.id, text);
+                }
+                for peer_id in listed.keys() {
+                    if !seen.contains_key(peer_id) {
+                        reporter.lost(peer_id.clone()).await;
+                    }
+                }
+                listed = seen;
+                sleep(SCAN_INTERVAL).await;
+            }
+        })
+    }
+}
+
+/// Our file in the registry, removed when the backend stops.
+struct Entry(PathBuf);
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Replace the file in one step, so a scan never reads half of it.
+async fn write_entry(path: &Path, contents: &str) -> Result<(), ChatError> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::net::discovery::start_discovery;
+    use tokio::time::timeout;
+
+    async fn knows(peer: &Peer, other: &Peer) -> bool {
+        peer.peers.lock().await.contains_key(&other.peer_id)
+    }
+
+    #[tokio::test]
+    async fn test_registry_finds_and_loses_peers() {
+        let dir = std::env::temp_dir().join(format!("p2p_chat_registry_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let alice = Arc::new(Peer::new("Alice".to_string(), 9000));
+        let bob = Arc::new(Peer::new("Bob".to_string(), 9001));
+        let registry = |dir: &PathBuf| -> Vec<Box<dyn Discovery>> {
+            vec![Box::new(RegistryDiscovery::new(dir.clone()))]
+        };
+        tokio::spawn(start_discovery(alice.clone(), registry(&dir)));
+        let bob_discovery = tokio::spawn(start_discovery(bob.clone(), registry(&dir)));
+
+        timeout(Duration::from_secs(10), async {
+            while !knows(&alice, &bob).await || !knows(&bob, &alice).await {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("peers didn't find each other");
+
+        // Stopping Bob's discovery removes his file, and Alice drops him on her next scan
+        bob_discovery.abort();
+        timeout(Duration::from_secs(10), async {
+            while knows(&alice, &bob).await {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("Bob wasn't reported lost");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,71 @@
+//! Static peers module: Discovery backend that connects to a fixed list of addresses.
+//!
+//! Each `host:port` in `[discovery] peers` (or given with `--connect`) is dialed with
+//! `discovery::introduce_to`, which swaps
+//! `Discovery` messages with whoever listens there, so nothing but the address has to be known in
+//! advance. Addresses are tried again every `RETRY_INTERVAL` until they answer, and again after
+//! the peer has left the peer map (e.g. because it timed out). Host names are looked up on every
+//! attempt.
+
+use crate::chat::events::DiscoveryMethod;
+use crate::chat::net::discovery::{introduce_to, Discovery, Reporter};
+use crate::chat::Peer;
+use crate::error::ChatError;
+use futures_util::future::BoxFuture;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// Time between attempts to reach addresses that aren't in the peer map.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct StaticDiscovery {
+    addrs: Vec<String>,
+}
+
+impl StaticDiscovery {
+    pub fn new(addrs: Vec<String>) -> Self {
+        Self { addrs }
+    }
+}
+
+impl Discovery for StaticDiscovery {
+    fn method(&self) -> DiscoveryMethod {
+        DiscoveryMethod::Static
+    }
+
+    fn run(
+        self: Box<Self>,
+        peer: Arc<Peer>,
+        reporter: Reporter,
+    ) -> BoxFuture<'static, Result<(), ChatError>> {
+        Box::pin(async move {
+            // The peer each address turned out to be, and the addresses whose last attempt
+            // failed (so a dead address is only reported once)
+            let mut reached: HashMap<String, String> = HashMap::new();
+            let mut failing: HashSet<String> = HashSet::new();
+            loop {
+                for addr in &self.addrs {
+                    if let Some(peer_id) = reached.get(addr) {
+                        if peer.peers.lock().await.contains_key(peer_id) {
+                            continue;
+                        }
+                    }
+                    match introduce_to(&peer, addr).await {
+                        Ok(info) => {
+                            failing.remove(addr);
+                            reached.insert(addr.clone(), info.id.clone());
+                            reporter.found(info).await;
+                        }
+                        Err(e) => {
+                            if failing.insert(addr.clone()) {
+                                peer.warn(format!("Cannot reach static peer {}: {}", addr, e));
+                            }
+                        }
+                    }
+                }
+                sleep(RETRY_INTERVAL).await;
+            }
+        })
+    }
+}
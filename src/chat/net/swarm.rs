@@ -0,0 +1,595 @@
+//! Swarm module: Announces shared files to every peer and fetches their chunks from any peer that has them.
+//!
+//! `/share` builds a `ShareManifest` for a file and sends it to all peers as `ShareAnnounce`. A
+//! peer that fetches the share opens a dedicated secure stream to each of up to `MAX_SOURCES`
+//! peers holding chunks it lacks, and asks each for one chunk at a time with `ChunkRequest`.
+//! Workers pick chunks at random among those not yet fetched or in flight, so different sources
+//! serve different parts of the file. Every chunk is checked against the manifest before it is
+//! written, and a source that sends a bad chunk is dropped. Each time another tenth has arrived,
+//! and once the file is complete, the fetcher tells everyone which chunks it holds (`ShareHave`),
+//! so later fetchers can pull from it instead of from the original sharer. The finished file is
+//! verified against the share ID, the SHA-256 of the whole file, before it is moved into place.
+//! Announces and `ShareHave`s are only taken from the peer they name, i.e. over a connection
+//! authenticated as the share's author or the chunks' holder.
+
+use crate::chat::events::ChatEvent;
+use crate::chat::net::broadcast::{self, DeliveryResult};
+use crate::chat::swarm::{build_manifest, partial_share_path, verify_chunk, Share};
+use crate::chat::transfer::{file_sha256, safe_file_name, unique_path};
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::network::secure::{SecureReader, SecureWriter};
+use crate::network::tcp::{ConnectionContext, Remote};
+use crate::peer::{ChunkRequest, NetworkMessage, PeerInfo, ShareHave, ShareManifest};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Peers a download pulls chunks from at the same time.
+const MAX_SOURCES: usize = 4;
+/// How long a download waits for a reachable peer holding a missing chunk before giving up.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a peer that failed to serve us is left alone.
+const SOURCE_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Chunks being fetched right now, so two workers don't fetch the same one.
+type Claimed = Arc<std::sync::Mutex<HashSet<u32>>>;
+
+/// Share the file at `path` with every peer. We keep serving it from `path` for as long as we
+/// run, so it must stay in place.
+pub async fn share_file(
+    peer: &Peer,
+    path: &Path,
+) -> Result<(ShareManifest, Vec<DeliveryResult>), ChatError> {
+    let unreadable = |e: std::io::Error| ChatError::Transfer(format!("{}: {}", path.display(), e));
+    let path = fs::canonicalize(path).await.map_err(unreadable)?;
+    let metadata = fs::metadata(&path).await.map_err(unreadable)?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Err(ChatError::Transfer(format!(
+            "{} is not a non-empty file",
+            path.display()
+        )));
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(safe_file_name)
+        .ok_or_else(|| ChatError::Transfer(format!("{} has no usable file name", path.display())))?
+        .to_string();
+    let manifest = build_manifest(&path, file_name, peer.peer_id.clone(), peer.name()).await?;
+    peer.shares.lock().await.seed(manifest.clone(), path);
+    let targets = broadcast::snapshot_peers(peer).await;
+    let msg = NetworkMessage::ShareAnnounce(manifest.clone());
+    let results = broadcast::fan_out(peer, targets, &msg).await;
+    Ok((manifest, results))
+}
+
+/// Record a share announced by `remote` and tell the user about it. Only a share's author may
+/// announce it, since its manifest decides what content passes verification.
+pub async fn handle_announce(
+    ctx: &ConnectionContext,
+    remote: &Remote,
+    mut manifest: ShareManifest,
+) {
+    if manifest.from_id != remote.peer_id {
+        return;
+    }
+    let Some(from) = ctx.peers.lock().await.get(&manifest.from_id).cloned() else {
+        ctx.warn(format!(
+            "Ignoring share announced by unknown peer {}",
+            manifest.from_name
+        ));
+        return;
+    };
+    if ctx.ignored.lock().await.matches_peer(&from.id, &from.name) {
+        return;
+    }
+    if !manifest.is_valid() || safe_file_name(&manifest.file_name).is_none() {
+        ctx.warn(format!("Ignoring malformed share from {}", from.name));
+        return;
+    }
+    manifest.from_name = from.name;
+    if ctx
+        .shares
+        .lock()
+        .await
+        .announced(manifest.clone(), &from.id)
+    {
+        let _ = ctx.events.send(ChatEvent::ShareAvailable(manifest));
+    }
+}
+
+/// Record that `remote` holds some chunks of a share. Nobody can add other peers as sources.
+pub async fn handle_have(ctx: &ConnectionContext, remote: &Remote, have: ShareHave) {
+    if have.peer_id != remote.peer_id {
+        return;
+    }
+    ctx.shares
+        .lock()
+        .await
+        .record_have(&have.share_id, &have.peer_id, &have.chunks);
+}
+
+/// Answer chunk requests on a dedicated stream until the remote closes it. Chunks we don't hold
+/// are answered with an empty payload.
+pub async fn serve_chunks<R, W>(
+    ctx: &ConnectionContext,
+    first: ChunkRequest,
+    mut reader: SecureReader<R>,
+    mut writer: SecureWriter<W>,
+) -> Result<(), ChatError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut request = first;
+    loop {
+        let data = read_chunk(ctx, &request).await.unwrap_or_default();
+        writer.write_payload(&data).await?;
+        let Some(frame) = reader.read_frame().await? else {
+            return Ok(());
+        };
+        request = match serde_json::from_slice(&frame)? {
+            NetworkMessage::ChunkRequest(request) => request,
+            _ => {
+                return Err(ChatError::Network(
+                    "expected a chunk request on a share stream".to_string(),
+                ))
+            }
+        };
+    }
+}
+
+/// The requested chunk, if we hold it.
+async fn read_chunk(ctx: &ConnectionContext, request: &ChunkRequest) -> Option<Vec<u8>> {
+    let (path, offset, len) = {
+        let shares = ctx.shares.lock().await;
+        let share = shares.get(&request.share_id)?;
+        if !share.have.get(request.index as usize).copied()? {
+            return None;
+        }
+        let (offset, len) = share.manifest.chunk_range(request.index)?;
+        (share.path.clone()?, offset, len)
+    };
+    let mut file = fs::File::open(&path).await.ok()?;
+    file.seek(SeekFrom::Start(offset)).await.ok()?;
+    let mut data = vec![0u8; len];
+    file.read_exact(&mut data).await.ok()?;
+    Some(data)
+}
+
+/// Start fetching the share whose ID starts with `query`. Chunks fetched earlier are kept, so
+/// this also continues a download that stopped. Progress and the result are published as events.
+pub async fn fetch_share(peer: &Peer, query: &str) -> Result<ShareManifest, ChatError> {
+    let download_dir = peer.transfers.lock().await.download_dir().to_path_buf();
+    let manifest = {
+        let mut shares = peer.shares.lock().await;
+        let id = shares
+            .find(query)
+            .ok_or_else(|| ChatError::Transfer(format!("no single share matches \"{}\"", query)))?
+            .manifest
+            .id
+            .clone();
+        let share = shares.get_mut(&id).expect("share found above");
+        if share.is_complete() {
+            return Err(ChatError::Transfer(format!(
+                "you already have {}",
+                share.manifest.file_name
+            )));
+        }
+        if share.fetching {
+            return Err(ChatError::Transfer(format!(
+                "{} is already being fetched",
+                share.manifest.file_name
+            )));
+        }
+        share.fetching = true;
+        if share.path.is_none() {
+            share.path = Some(partial_share_path(&download_dir, &share.manifest));
+        }
+        share.manifest.clone()
+    };
+    tokio::spawn(run_fetch(peer.clone(), manifest.clone(), download_dir));
+    Ok(manifest)
+}
+
+async fn run_fetch(peer: Peer, manifest: ShareManifest, download_dir: PathBuf) {
+    let result = tokio::select! {
+        result = fetch(&peer, &manifest, &download_dir) => result,
+        _ = peer.shutdown.cancelled() => Err(ChatError::Transfer("shutting down".to_string())),
+    };
+    if let Some(share) = peer.shares.lock().await.get_mut(&manifest.id) {
+        share.fetching = false;
+    }
+    let event = match result {
+        Ok(path) => ChatEvent::ShareComplete {
+            share_id: manifest.id,
+            file_name: manifest.file_name,
+            path,
+        },
+        Err(e) => ChatEvent::ShareFailed {
+            share_id: manifest.id,
+            file_name: manifest.file_name,
+            reason: e.to_string(),
+        },
+    };
+    let _ = peer.events.send(event);
+}
+
+/// Run workers against the peers that can help until every chunk has arrived, then verify the
+/// file and move it into `download_dir`. Returns where it was saved.
+async fn fetch(
+    peer: &Peer,
+    manifest: &ShareManifest,
+    download_dir: &Path,
+) -> Result<PathBuf, ChatError> {
+    let partial = share_path(peer, &manifest.id).await?;
+    fs::create_dir_all(download_dir).await?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&partial)
+        .await?;
+    if file.metadata().await?.len() != manifest.size {
+        file.set_len(manifest.size).await?;
+    }
+    drop(file);
+
+    let claimed = Claimed::default();
+    let mut workers = JoinSet::new();
+    let mut active = HashSet::new();
+    let mut backoff: HashMap<String, Instant> = HashMap::new();
+    let mut idle_since = Instant::now();
+    loop {
+        let (complete, candidates) = {
+            let shares = peer.shares.lock().await;
+            let share = shares
+                .get(&manifest.id)
+                .ok_or_else(|| ChatError::Transfer("the share was forgotten".to_string()))?;
+            let candidates: Vec<String> = share
+                .sources
+                .keys()
+                .filter(|id| share.can_help(id))
+                .cloned()
+                .collect();
+            (share.is_complete(), candidates)
+        };
+        if complete {
+            break;
+        }
+        {
+            let peers = peer.peers.lock().await;
+            for source in candidates {
+                if active.len() >= MAX_SOURCES {
+                    break;
+                }
+                let resting = backoff
+                    .get(&source)
+                    .is_some_and(|until| Instant::now() < *until);
+                if active.contains(&source) || resting {
+                    continue;
+                }
+                let Some(info) = peers.get(&source).cloned() else {
+                    continue;
+                };
+                active.insert(source);
+                workers.spawn(fetch_from(
+                    peer.clone(),
+                    manifest.clone(),
+                    partial.clone(),
+                    info,
+                    claimed.clone(),
+                ));
+            }
+        }
+        if !active.is_empty() {
+            idle_since = Instant::now();
+        } else if idle_since.elapsed() > SOURCE_TIMEOUT {
+            return Err(ChatError::Transfer(
+                "no reachable peer has the missing chunks".to_string(),
+            ));
+        }
+        tokio::select! {
+            Some(joined) = workers.join_next() => {
+                let (source, result) = joined.map_err(|e| ChatError::Unknown(e.to_string()))?;
+                active.remove(&source.id);
+                if let Err(e) = result {
+                    peer.warn(format!(
+                        "Fetching {} from {} failed: {}",
+                        manifest.file_name, source.name, e
+                    ));
+                    backoff.insert(source.id, Instant::now() + SOURCE_BACKOFF);
+                }
+            }
+            _ = sleep(Duration::from_secs(1)) => {}
+        }
+    }
+    workers.shutdown().await;
+
+    if file_sha256(&partial).await? != manifest.id {
+        // Every chunk matched, so the manifest itself was inconsistent; start over next time
+        if let Some(share) = peer.shares.lock().await.get_mut(&manifest.id) {
+            share.have.fill(false);
+        }
+        return Err(ChatError::Transfer(
+            "the assembled file doesn't match the share ID".to_string(),
+        ));
+    }
+    let path = unique_path(download_dir, &manifest.file_name, &manifest.id);
+    fs::rename(&partial, &path).await?;
+    let have = {
+        let mut shares = peer.shares.lock().await;
+        let share = shares
+            .get_mut(&manifest.id)
+            .ok_or_else(|| ChatError::Transfer("the share was forgotten".to_string()))?;
+        share.path = Some(path.clone());
+        have_message(peer, share)
+    };
+    announce_have(peer, have).await;
+    Ok(path)
+}
+
+async fn share_path(peer: &Peer, share_id: &str) -> Result<PathBuf, ChatError> {
+    peer.shares
+        .lock()
+        .await
+        .get(share_id)
+        .and_then(|share| share.path.clone())
+        .ok_or_else(|| ChatError::Transfer("the share has no download location".to_string()))
+}
+
+/// Fetch chunks from one source until it has nothing left we need. Returns the source along
+/// with the outcome, so the caller knows which worker finished.
+async fn fetch_from(
+    peer: Peer,
+    manifest: ShareManifest,
+    path: PathBuf,
+    source: PeerInfo,
+    claimed: Claimed,
+) -> (PeerInfo, Result<(), ChatError>) {
+    let result = fetch_chunks(&peer, &manifest, &path, &source, &claimed).await;
+    (source, result)
+}
+
+async fn fetch_chunks(
+    peer: &Peer,
+    manifest: &ShareManifest,
+    path: &Path,
+    source: &PeerInfo,
+    claimed: &Claimed,
+) -> Result<(), ChatError> {
+    let (mut reader, mut writer) = peer.connections.open_stream(source).await?;
+    let mut file = OpenOptions::new().write(true).open(path).await?;
+    loop {
+        let Some(index) = claim_chunk(peer, manifest, &source.id, claimed).await else {
+            return Ok(());
+        };
+        let result = fetch_chunk(
+            peer,
+            manifest,
+            source,
+            index,
+            (&mut reader, &mut writer),
+            &mut file,
+        )
+        .await;
+        claimed
+            .lock()
+            .expect("claimed lock poisoned")
+            .remove(&index);
+        result?;
+    }
+}
+
+/// Pick a random chunk that we lack, `source_id` holds and no other worker is fetching.
+async fn claim_chunk(
+    peer: &Peer,
+    manifest: &ShareManifest,
+    source_id: &str,
+    claimed: &Claimed,
+) -> Option<u32> {
+    let shares = peer.shares.lock().await;
+    let share = shares.get(&manifest.id)?;
+    let mut claimed = claimed.lock().expect("claimed lock poisoned");
+    let candidates: Vec<u32> = share
+        .sources
+        .get(source_id)?
+        .iter()
+        .copied()
+        .filter(|&index| !share.have[index as usize] && !claimed.contains(&index))
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = candidates[rand::random::<usize>() % candidates.len()];
+    claimed.insert(index);
+    Some(index)
+}
+
+async fn fetch_chunk(
+    peer: &Peer,
+    manifest: &ShareManifest,
+    source: &PeerInfo,
+    index: u32,
+    (reader, writer): (
+        &mut SecureReader<OwnedReadHalf>,
+        &mut SecureWriter<OwnedWriteHalf>,
+    ),
+    file: &mut fs::File,
+) -> Result<(), ChatError> {
+    let request = ChunkRequest {
+        share_id: manifest.id.clone(),
+        index,
+    };
+    writer
+        .write_message(&NetworkMessage::ChunkRequest(request))
+        .await?;
+    let data = reader
+        .read_frame()
+        .await?
+        .ok_or_else(|| ChatError::Network("stream closed mid-transfer".to_string()))?;
+    if data.is_empty() || !verify_chunk(manifest, index, &data) {
+        let mut shares = peer.shares.lock().await;
+        let Some(share) = shares.get_mut(&manifest.id) else {
+            return Ok(());
+        };
+        if data.is_empty() {
+            // It doesn't hold this chunk after all
+            if let Some(chunks) = share.sources.get_mut(&source.id) {
+                chunks.remove(&index);
+            }
+            return Ok(());
+        }
+        share.sources.remove(&source.id);
+        return Err(ChatError::Transfer(format!(
+            "chunk {} failed verification",
+            index
+        )));
+    }
+    let (offset, _) = manifest.chunk_range(index).expect("verified chunk");
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(&data).await?;
+    file.flush().await?;
+
+    let have = {
+        let mut shares = peer.shares.lock().await;
+        let Some(share) = shares.get_mut(&manifest.id) else {
+            return Ok(());
+        };
+        share.have[index as usize] = true;
+        let (have, total) = (share.have_count(), share.have.len());
+        // Report each tenth; completion is reported once the whole file is verified
+        if have == total || have * 10 / total == (have - 1) * 10 / total {
+            return Ok(());
+        }
+        let _ = peer.events.send(ChatEvent::ShareProgress {
+            share_id: manifest.id.clone(),
+            file_name: manifest.file_name.clone(),
+            have,
+            total,
+            sources: share.sources.len(),
+        });
+        have_message(peer, share)
+    };
+    announce_have(peer, have).await;
+    Ok(())
+}
+
+fn have_message(peer: &Peer, share: &Share) -> NetworkMessage {
+    NetworkMessage::ShareHave(ShareHave {
+        share_id: share.manifest.id.clone(),
+        peer_id: peer.peer_id.clone(),
+        chunks: share.have_list(),
+    })
+}
+
+/// Tell every peer which chunks we can serve, without waiting for the sends.
+async fn announce_have(peer: &Peer, msg: NetworkMessage) {
+    for target in broadcast::snapshot_peers(peer).await {
+        peer.connections.send_in_background(target, msg.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::net::testing::{introduce, listening_peer};
+    use crate::chat::transfer::short_id;
+    use tokio::sync::broadcast::Receiver;
+    use tokio::time::timeout;
+
+    async fn send(peer: &Peer, to: &Peer, msg: &NetworkMessage) {
+        let info = peer.peers.lock().await[&to.peer_id].clone();
+        peer.connections.send(&info, msg).await.unwrap();
+    }
+
+    async fn completed(events: &mut Receiver<ChatEvent>) -> PathBuf {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    ChatEvent::ShareComplete { path, .. } => return path,
+                    ChatEvent::ShareFailed { reason, .. } => panic!("fetch failed: {}", reason),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for the share")
+    }
+
+    #[tokio::test]
+    async fn test_fetch_from_a_peer_other_than_the_sharer() {
+        let dir = std::env::temp_dir().join(format!("p2p_chat_swarm_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("artifact.bin");
+        let contents: Vec<u8> = (0..700_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let alice = listening_peer("Alice", dir.join("alice")).await;
+        let bob = listening_peer("Bob", dir.join("bob")).await;
+        let carol = listening_peer("Carol", dir.join("carol")).await;
+        for (a, b) in [(&alice, &bob), (&alice, &carol), (&bob, &carol)] {
+            introduce(a, b).await;
+            introduce(b, a).await;
+        }
+        let mut bob_events = bob.events.subscribe();
+        let mut carol_events = carol.events.subscribe();
+
+        // Broadcasts skip loopback peers, so deliver the announcements ourselves
+        let (manifest, _) = alice.share_file(&source).await.unwrap();
+        assert_eq!(manifest.chunks.len(), 3);
+        let announce = NetworkMessage::ShareAnnounce(manifest.clone());
+        for other in [&bob, &carol] {
+            send(&alice, other, &announce).await;
+        }
+        timeout(Duration::from_secs(10), async {
+            while carol.shares.lock().await.get(&manifest.id).is_none() {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        bob.fetch_share(short_id(&manifest.id)).await.unwrap();
+        let saved = completed(&mut bob_events).await;
+        assert_eq!(std::fs::read(saved).unwrap(), contents);
+
+        // Once Bob says he has every chunk, Carol can fetch without Alice
+        let have = have_message(&bob, bob.shares.lock().await.get(&manifest.id).unwrap());
+        send(&bob, &carol, &have).await;
+        timeout(Duration::from_secs(10), async {
+            while !carol
+                .shares
+                .lock()
+                .await
+                .get(&manifest.id)
+                .unwrap()
+                .can_help(&bob.peer_id)
+            {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        carol.peers.lock().await.remove(&alice.peer_id);
+        carol.fetch_share(&manifest.id).await.unwrap();
+        let saved = completed(&mut carol_events).await;
+        assert_eq!(std::fs::read(saved).unwrap(), contents);
+        assert!(carol
+            .shares
+            .lock()
+            .await
+            .get(&manifest.id)
+            .unwrap()
+            .is_complete());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,130 @@
+//! History sync module: Lets a peer that joins late catch up on channel messages it missed.
+//!
+//! When two peers meet for the first time each sends a `SyncRequest` summarising its history:
+//! per joined channel, the sequence numbers it holds from each sender. The other side answers with
+//! a `SyncResponse` carrying the channel messages whose sender and sequence number aren't in the
+//! summary, however old they are, and the requester verifies and stores them, de-duplicated by
+//! message ID. Since both sides pull from each other, peers converge on the same backlog. Direct
+//! messages are never synced.
+
+use crate::history::HistoryEntry;
+use crate::identity::verify_message;
+use crate::network::tcp::{ConnectionContext, Remote};
+use crate::peer::{Message, NetworkMessage, PeerInfo, SyncRequest};
+
+/// Most messages sent in one `SyncResponse`; older ones are left out.
+pub const MAX_SYNC_MESSAGES: usize = 200;
+
+/// Our history summary for the channels we are in.
+pub async fn sync_request(ctx: &ConnectionContext) -> NetworkMessage {
+    let joined = ctx.channels.lock().await.joined().clone();
+    NetworkMessage::SyncRequest(SyncRequest {
+        from_id: ctx.peer_id.clone(),
+        held: ctx.history.lock().await.held_per_channel(&joined),
+    })
+}
+
+/// Ask `target` for the messages we are missing, in the background.
+pub fn request_sync(ctx: &ConnectionContext, target: PeerInfo) {
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        if let Some(connections) = ctx.connections.upgrade() {
+            connections.send_in_background(target, sync_request(&ctx).await);
+        }
+    });
+}
+
+/// Reply to `remote`'s `SyncRequest` with the messages its summary says it may be missing. The
+/// answer goes to the peer the request came from, whatever its `from_id` says.
+pub async fn answer_sync(ctx: &ConnectionContext, remote: &Remote, request: SyncRequest) {
+    let Some(target) = ctx.peers.lock().await.get(&remote.peer_id).cloned() else {
+        return;
+    };
+    let missing = ctx
+        .history
+        .lock()
+        .await
+        .missing_for(&request.held, MAX_SYNC_MESSAGES);
+    if missing.is_empty() {
+        return;
+    }
+    if let Some(connections) = ctx.connections.upgrade() {
+        connections.send_in_background(target, NetworkMessage::SyncResponse(missing));
+    }
+}
+
+/// Store the validly signed messages from a `SyncResponse` that we don't have yet, for channels
+/// we are in. Returns how many were added.
+pub async fn apply_sync(ctx: &ConnectionContext, messages: Vec<Message>) -> usize {
+    let joined = ctx.channels.lock().await.joined().clone();
+    let mut history = ctx.history.lock().await;
+    let mut added = 0;
+    for message in messages {
+        if !joined.contains(&message.channel) || !verify_message(&message) {
+            continue;
+        }
+        let entry = HistoryEntry {
+            outgoing: message.from_id == ctx.peer_id,
+            message,
+            direct: false,
+        };
+        match history.append(entry) {
+            Ok(true) => added += 1,
+            Ok(false) => {}
+            Err(e) => ctx.warn(format!("Failed to write message history: {}", e)),
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Peer;
+    use crate::identity::Identity;
+
+    fn context() -> ConnectionContext {
+        Peer::new("Me".to_string(), 9000).connection_context()
+    }
+
+    fn signed(identity: &Identity, content: &str, channel: &str) -> Message {
+        let mut message = Message {
+            id: Message::new_id(),
+            from_id: identity.peer_id(),
+            from_name: "Alice".to_string(),
+            content: content.to_string(),
+            timestamp: 1234567890,
+            channel: channel.to_string(),
+            seq: 1,
+            to_id: String::new(),
+            signature: String::new(),
+        };
+        identity.sign(&mut message);
+        message
+    }
+
+    #[tokio::test]
+    async fn test_apply_sync_dedupes_and_filters() {
+        let ctx = context();
+        let alice = Identity::generate();
+        let hello = signed(&alice, "hello", "general");
+        let mut forged = signed(&alice, "forged", "general");
+        forged.content = "tampered".to_string();
+        let batch = vec![
+            hello.clone(),
+            forged,
+            signed(&alice, "elsewhere", "rust"),
+            hello.clone(),
+        ];
+        assert_eq!(apply_sync(&ctx, batch).await, 1);
+        // Receiving the same backlog again adds nothing
+        assert_eq!(apply_sync(&ctx, vec![hello]).await, 0);
+        assert_eq!(ctx.history.lock().await.len(), 1);
+
+        if let NetworkMessage::SyncRequest(request) = sync_request(&ctx).await {
+            assert_eq!(request.held["general"][&alice.peer_id()], [(1, 1)]);
+        } else {
+            panic!("expected a sync request");
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Testing module: Fixtures shared by the tests that run real peers against each other.
+//!
+//! Peers listen on free loopback ports. Since loopback addresses are never broadcast to or
+//! learnt through discovery, tests add each other's `PeerInfo` by hand with `introduce`.
+
+use crate::chat::net::listener::start_tcp_listener;
+use crate::chat::Peer;
+use crate::peer::PeerInfo;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+/// A peer listening on a free local port, saving downloads to `download_dir`.
+pub async fn listening_peer(name: &str, download_dir: PathBuf) -> Peer {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let peer = Peer::new(name.to_string(), listener.local_addr().unwrap().port());
+    peer.transfers.lock().await.set_download_dir(download_dir);
+    let serving = peer.clone();
+    tokio::spawn(async move { start_tcp_listener(&serving, listener).await });
+    peer
+}
+
+/// Add `other` to `peer`'s peers at its loopback address.
+pub async fn introduce(peer: &Peer, other: &Peer) {
+    let info = PeerInfo::new(
+        other.peer_id.clone(),
+        other.name(),
+        Ipv4Addr::LOCALHOST.into(),
+        other.port,
+    );
+    peer.peers.lock().await.insert(info.id.clone(), info);
+}
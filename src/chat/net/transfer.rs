@@ -0,0 +1,461 @@
+//! File transfer module: Offers files to peers and streams accepted ones over a dedicated connection.
+//!
+//! `/send` hashes the file and sends a `FileOffer` over the regular chat connection. Once the
+//! recipient accepts, it opens a separate secure stream to the sender and asks for the file with a
+//! `FileRequest` carrying the offer ID and the number of bytes it already has. The sender answers
+//! with the rest of the file as raw encrypted chunks and closes the stream, so chat traffic never
+//! waits behind a large file. The recipient appends to a partial file, reports progress every
+//! tenth of the way, and only moves the file into place once its SHA-256 matches the offer. A
+//! download that breaks off keeps its partial file and resumes from there when accepted again.
+//! Offers and declines are only taken from the offer's sender and recipient respectively.
+
+use crate::chat::events::{ChatEvent, TransferDirection};
+use crate::chat::transfer::{
+    file_sha256, format_size, safe_file_name, short_id, OutgoingFile, FILE_CHUNK_LEN,
+};
+use crate::chat::Peer;
+use crate::error::ChatError;
+use crate::network::secure::SecureWriter;
+use crate::network::tcp::{ConnectionContext, Remote};
+use crate::peer::{FileOffer, FileRequest, Message, NetworkMessage, PeerInfo};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+
+/// Publishes `TransferProgress` each time another tenth of a file has been moved.
+struct Progress {
+    events: broadcast::Sender<ChatEvent>,
+    offer_id: String,
+    file_name: String,
+    direction: TransferDirection,
+    done: u64,
+    total: u64,
+    next_tenth: u64,
+}
+
+impl Progress {
+    fn new(
+        events: broadcast::Sender<ChatEvent>,
+        offer: &FileOffer,
+        direction: TransferDirection,
+        done: u64,
+    ) -> Self {
+        let total = offer.size;
+        Self {
+            events,
+            offer_id: offer.id.clone(),
+            file_name: offer.file_name.clone(),
+            direction,
+            done,
+            total,
+            next_tenth: tenths(done, total) + 1,
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        let tenth = tenths(self.done, self.total);
+        // The last tenth is reported by `TransferComplete`
+        if tenth >= self.next_tenth && self.done < self.total {
+            self.next_tenth = tenth + 1;
+            let _ = self.events.send(ChatEvent::TransferProgress {
+                offer_id: self.offer_id.clone(),
+                file_name: self.file_name.clone(),
+                direction: self.direction,
+                done: self.done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+fn tenths(done: u64, total: u64) -> u64 {
+    (done * 10).checked_div(total).unwrap_or(10)
+}
+
+/// Offer the file at `path` to `target`. The file is read again when they accept, so it must stay
+/// in place until then.
+pub async fn offer_file(
+    peer: &Peer,
+    target: PeerInfo,
+    path: &Path,
+) -> Result<FileOffer, ChatError> {
+    let unreadable = |e: std::io::Error| ChatError::Transfer(format!("{}: {}", path.display(), e));
+    let path = fs::canonicalize(path).await.map_err(unreadable)?;
+    let metadata = fs::metadata(&path).await.map_err(unreadable)?;
+    if !metadata.is_file() {
+        return Err(ChatError::Transfer(format!(
+            "{} is not a file",
+            path.display()
+        )));
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(safe_file_name)
+        .ok_or_else(|| ChatError::Transfer(format!("{} has no usable file name", path.display())))?
+        .to_string();
+    let offer = FileOffer {
+        id: Message::new_id(),
+        from_id: peer.peer_id.clone(),
+        from_name: peer.name(),
+        file_name,
+        size: metadata.len(),
+        sha256: file_sha256(&path).await?,
+    };
+    peer.transfers.lock().await.add_outgoing(OutgoingFile {
+        offer: offer.clone(),
+        path,
+        to: target.clone(),
+    });
+    let msg = NetworkMessage::FileOffer(offer.clone());
+    if let Err(e) = peer.connections.send(&target, &msg).await {
+        peer.transfers.lock().await.remove_outgoing(&offer.id);
+        return Err(e);
+    }
+    Ok(offer)
+}
+
+/// Record an offer received from `remote` and tell the user about it. Offers naming any other
+/// sender are dropped, so nobody can make us download from a peer in someone else's name.
+pub async fn handle_offer(ctx: &ConnectionContext, remote: &Remote, mut offer: FileOffer) {
+    if offer.from_id != remote.peer_id {
+        return;
+    }
+    let Some(from) = ctx.peers.lock().await.get(&offer.from_id).cloned() else {
+        // We need the sender's address to download from it
+        ctx.warn(format!(
+            "Ignoring file offer from unknown peer {}",
+            offer.from_name
+        ));
+        return;
+    };
+    if ctx.ignored.lock().await.matches_peer(&from.id, &from.name) {
+        return;
+    }
+    let valid_id = offer.id.len() == 32 && offer.id.bytes().all(|b| b.is_ascii_hexdigit());
+    if !valid_id || safe_file_name(&offer.file_name).is_none() {
+        ctx.warn(format!("Ignoring malformed file offer from {}", from.name));
+        return;
+    }
+    offer.from_name = from.name;
+    if ctx.transfers.lock().await.add_incoming(offer.clone()) {
+        let _ = ctx.events.send(ChatEvent::FileOffered(offer));
+    }
+}
+
+/// The recipient of one of our offers turned it down. Only the recipient may do so.
+pub async fn handle_decline(ctx: &ConnectionContext, remote: &Remote, offer_id: &str) {
+    let mut transfers = ctx.transfers.lock().await;
+    if transfers.outgoing(offer_id).map(|file| &file.to.id) != Some(&remote.peer_id) {
+        return;
+    }
+    let Some(file) = transfers.remove_outgoing(offer_id) else {
+        return;
+    };
+    drop(transfers);
+    let _ = ctx.events.send(ChatEvent::FileDeclined {
+        file_name: file.offer.file_name,
+        peer_name: file.to.name,
+    });
+}
+
+/// Answer a `FileRequest` on a dedicated stream by sending the rest of the file.
+pub async fn serve_file<W>(ctx: &ConnectionContext, request: FileRequest, writer: SecureWriter<W>)
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(file) = ctx.transfers.lock().await.outgoing(&request.id).cloned() else {
+        ctx.warn(format!(
+            "Ignoring request for unknown file offer {}",
+            short_id(&request.id)
+        ));
+        return;
+    };
+    let event = match stream_file(ctx, &file, request.offset, writer).await {
+        Ok(()) => ChatEvent::TransferComplete {
+            offer_id: file.offer.id,
+            file_name: file.offer.file_name,
+            direction: TransferDirection::Sending,
+            path: file.path,
+        },
+        Err(e) => ChatEvent::TransferFailed {
+            offer_id: file.offer.id,
+            file_name: file.offer.file_name,
+            direction: TransferDirection::Sending,
+            reason: e.to_string(),
+        },
+    };
+    let _ = ctx.events.send(event);
+}
+
+async fn stream_file<W>(
+    ctx: &ConnectionContext,
+    file: &OutgoingFile,
+    offset: u64,
+    mut writer: SecureWriter<W>,
+) -> Result<(), ChatError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let size = file.offer.size;
+    if offset > size {
+        return Err(ChatError::Transfer(format!(
+            "requested offset {} is past the end of the file",
+            offset
+        )));
+    }
+    let mut source = fs::File::open(&file.path).await?;
+    source.seek(SeekFrom::Start(offset)).await?;
+    let mut progress = Progress::new(
+        ctx.events.clone(),
+        &file.offer,
+        TransferDirection::Sending,
+        offset,
+    );
+    let mut buf = vec![0u8; FILE_CHUNK_LEN];
+    let mut remaining = size - offset;
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = source.read(&mut buf[..want]).await?;
+        if n == 0 {
+            return Err(ChatError::Transfer(
+                "the file got shorter since it was offered".to_string(),
+            ));
+        }
+        writer.write_payload(&buf[..n]).await?;
+        remaining -= n as u64;
+        progress.advance(n as u64);
+    }
+    writer.shutdown().await
+}
+
+/// Start downloading the received offer whose ID starts with `query`, resuming a partial
+/// download if there is one. Progress and the result are published as events.
+pub async fn accept_offer(peer: &Peer, query: &str) -> Result<FileOffer, ChatError> {
+    let offer = {
+        let mut transfers = peer.transfers.lock().await;
+        let offer = transfers
+            .find_incoming(query)
+            .ok_or_else(|| no_offer(query))?
+            .offer
+            .clone();
+        if !transfers.start_download(&offer.id) {
+            return Err(ChatError::Transfer(format!(
+                "{} is already downloading",
+                offer.file_name
+            )));
+        }
+        offer
+    };
+    let Some(from) = peer.peers.lock().await.get(&offer.from_id).cloned() else {
+        peer.transfers
+            .lock()
+            .await
+            .finish_download(&offer.id, false);
+        return Err(ChatError::Transfer(format!(
+            "{} is not reachable right now",
+            offer.from_name
+        )));
+    };
+    tokio::spawn(download(peer.clone(), offer.clone(), from));
+    Ok(offer)
+}
+
+/// Turn down the received offer whose ID starts with `query` and drop any partial download.
+pub async fn decline_offer(peer: &Peer, query: &str) -> Result<FileOffer, ChatError> {
+    let (offer, partial) = {
+        let mut transfers = peer.transfers.lock().await;
+        let file = transfers
+            .find_incoming(query)
+            .ok_or_else(|| no_offer(query))?;
+        if file.downloading {
+            return Err(ChatError::Transfer(format!(
+                "{} is already downloading",
+                file.offer.file_name
+            )));
+        }
+        let offer = file.offer.clone();
+        transfers.remove_incoming(&offer.id);
+        let partial = transfers.partial_path(&offer);
+        (offer, partial)
+    };
+    let _ = fs::remove_file(partial).await;
+    if let Some(from) = peer.peers.lock().await.get(&offer.from_id).cloned() {
+        peer.connections
+            .send_in_background(from, NetworkMessage::FileDecline(offer.id.clone()));
+    }
+    Ok(offer)
+}
+
+fn no_offer(query: &str) -> ChatError {
+    ChatError::Transfer(format!("no single file offer matches \"{}\"", query))
+}
+
+async fn download(peer: Peer, offer: FileOffer, from: PeerInfo) {
+    let result = tokio::select! {
+        result = receive(&peer, &offer, &from) => result,
+        _ = peer.shutdown.cancelled() => {
+            Err(ChatError::Transfer("shutting down".to_string()))
+        }
+    };
+    peer.transfers
+        .lock()
+        .await
+        .finish_download(&offer.id, result.is_ok());
+    let event = match result {
+        Ok(path) => ChatEvent::TransferComplete {
+            offer_id: offer.id,
+            file_name: offer.file_name,
+            direction: TransferDirection::Receiving,
+            path,
+        },
+        Err(e) => ChatEvent::TransferFailed {
+            offer_id: offer.id,
+            file_name: offer.file_name,
+            direction: TransferDirection::Receiving,
+            reason: e.to_string(),
+        },
+    };
+    let _ = peer.events.send(event);
+}
+
+/// Fetch whatever the partial file is still missing, verify the whole file and move it into the
+/// download directory. Returns where it was saved.
+async fn receive(peer: &Peer, offer: &FileOffer, from: &PeerInfo) -> Result<PathBuf, ChatError> {
+    let (dir, partial) = {
+        let transfers = peer.transfers.lock().await;
+        (
+            transfers.download_dir().to_path_buf(),
+            transfers.partial_path(offer),
+        )
+    };
+    fs::create_dir_all(&dir).await?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial)
+        .await?;
+    let mut done = file.metadata().await?.len();
+    if done > offer.size {
+        file.set_len(0).await?;
+        done = 0;
+    }
+    if done < offer.size {
+        let (mut reader, mut writer) = peer.connections.open_stream(from).await?;
+        let request = FileRequest {
+            id: offer.id.clone(),
+            offset: done,
+        };
+        writer
+            .write_message(&NetworkMessage::FileRequest(request))
+            .await?;
+        let mut progress = Progress::new(
+            peer.events.clone(),
+            offer,
+            TransferDirection::Receiving,
+            done,
+        );
+        while done < offer.size {
+            let Some(chunk) = reader.read_frame().await? else {
+                return Err(ChatError::Transfer(format!(
+                    "connection closed after {} of {}",
+                    format_size(done),
+                    format_size(offer.size)
+                )));
+            };
+            if done + chunk.len() as u64 > offer.size {
+                return Err(ChatError::Transfer(
+                    "received more data than was offered".to_string(),
+                ));
+            }
+            file.write_all(&chunk).await?;
+            done += chunk.len() as u64;
+            progress.advance(chunk.len() as u64);
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+    }
+    drop(file);
+    if file_sha256(&partial).await? != offer.sha256 {
+        let _ = fs::remove_file(&partial).await;
+        return Err(ChatError::Transfer(
+            "checksum mismatch, the download was discarded".to_string(),
+        ));
+    }
+    let path = peer.transfers.lock().await.final_path(offer);
+    fs::rename(&partial, &path).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::net::testing::{introduce, listening_peer};
+    use tokio::time::{timeout, Duration};
+
+    async fn next_event<T>(
+        events: &mut broadcast::Receiver<ChatEvent>,
+        mut pick: impl FnMut(ChatEvent) -> Option<T>,
+    ) -> T {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(found) = pick(events.recv().await.unwrap()) {
+                    return found;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for event")
+    }
+
+    #[tokio::test]
+    async fn test_offer_accept_and_resume() {
+        let dir = std::env::temp_dir().join(format!("p2p_chat_transfer_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("data.bin");
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let alice = listening_peer("Alice", dir.join("alice")).await;
+        let bob = listening_peer("Bob", dir.join("downloads")).await;
+        introduce(&alice, &bob).await;
+        introduce(&bob, &alice).await;
+        let mut events = bob.events.subscribe();
+
+        let offer = alice.send_file("Bob", &source).await.unwrap();
+        let offered = next_event(&mut events, |event| match event {
+            ChatEvent::FileOffered(offer) => Some(offer),
+            _ => None,
+        })
+        .await;
+        assert_eq!(offered.id, offer.id);
+        assert_eq!(offered.size, contents.len() as u64);
+
+        // Pretend an earlier attempt got the first 100 kB
+        let partial = bob.transfers.lock().await.partial_path(&offered);
+        std::fs::create_dir_all(dir.join("downloads")).unwrap();
+        std::fs::write(&partial, &contents[..100_000]).unwrap();
+
+        bob.accept_file(short_id(&offer.id)).await.unwrap();
+        let saved = next_event(&mut events, |event| match event {
+            ChatEvent::TransferComplete { path, .. } => Some(path),
+            ChatEvent::TransferFailed { reason, .. } => panic!("transfer failed: {}", reason),
+            _ => None,
+        })
+        .await;
+        assert_eq!(saved, dir.join("downloads").join("data.bin"));
+        assert_eq!(std::fs::read(&saved).unwrap(), contents);
+        assert!(!partial.exists());
+        assert!(bob
+            .transfers
+            .lock()
+            .await
+            .find_incoming(&offer.id)
+            .is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
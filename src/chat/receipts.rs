@@ -0,0 +1,250 @@
+//! Receipts module: Tracks delivery and read acknowledgements for the messages we send.
+//!
+//! Every chat or direct message we send is remembered together with the peers it was addressed
+//! to. Each recipient starts out `Pending`, becomes `Delivered` once its handler has decoded and
+//! displayed the message, and `Read` once its user has interacted with the chat afterwards.
+//! Recipients that stay pending are retried by `net::delivery` until they acknowledge or run out
+//! of attempts. The module also keeps the queue of incoming messages we still owe a read receipt.
+
+use crate::peer::{AckKind, NetworkMessage};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many sent messages are remembered; older ones are forgotten.
+const MAX_TRACKED: usize = 100;
+/// How many read receipts we owe at most; older ones are never sent.
+const MAX_UNREAD: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeliveryStatus {
+    Pending,
+    /// Never acknowledged, even after every retry.
+    Failed,
+    Delivered,
+    Read,
+}
+
+impl std::fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Read => "read",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub name: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    last_attempt: Instant,
+}
+
+/// A message we sent and the state of each of its recipients.
+#[derive(Debug, Clone)]
+pub struct TrackedMessage {
+    pub message_id: String,
+    pub summary: String,
+    pub message: NetworkMessage,
+    /// peer ID -> recipient state
+    pub recipients: BTreeMap<String, Recipient>,
+}
+
+/// A recipient that never acknowledged a message.
+#[derive(Debug, Clone)]
+pub struct FailedDelivery {
+    pub message_id: String,
+    pub peer_id: String,
+    pub peer_name: String,
+}
+
+/// Result of one retry pass, see `ReceiptTracker::due_retries`.
+#[derive(Debug, Default)]
+pub struct RetryPlan {
+    pub resend: Vec<(String, NetworkMessage)>,
+    pub failed: Vec<FailedDelivery>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReceiptTracker {
+    sent: VecDeque<TrackedMessage>,
+    /// (sender ID, message ID) of displayed messages we haven't sent a read receipt for yet
+    unread: VecDeque<(String, String)>,
+}
+
+impl ReceiptTracker {
+    /// Start tracking `message`, sent just now to each of `recipients` (peer ID, name).
+    pub fn track(
+        &mut self,
+        message_id: &str,
+        summary: &str,
+        message: NetworkMessage,
+        recipients: impl IntoIterator<Item = (String, String)>,
+    ) {
+        let now = Instant::now();
+        let recipients = recipients
+            .into_iter()
+            .map(|(id, name)| {
+                let recipient = Recipient {
+                    name,
+                    status: DeliveryStatus::Pending,
+                    attempts: 1,
+                    last_attempt: now,
+                };
+                (id, recipient)
+            })
+            .collect();
+        if self.sent.len() == MAX_TRACKED {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(TrackedMessage {
+            message_id: message_id.to_string(),
+            summary: summary.to_string(),
+            message,
+            recipients,
+        });
+    }
+
+    /// Apply an acknowledgement from `peer_id`. Returns the recipient's name and new status if
+    /// it changed anything; a read receipt never goes back to merely delivered.
+    pub fn acknowledge(
+        &mut self,
+        message_id: &str,
+        peer_id: &str,
+        kind: AckKind,
+    ) -> Option<(String, DeliveryStatus)> {
+        let recipient = self
+            .sent
+            .iter_mut()
+            .find(|m| m.message_id == message_id)
+            .and_then(|m| m.recipients.get_mut(peer_id))?;
+        let status = match kind {
+            AckKind::Delivered => DeliveryStatus::Delivered,
+            AckKind::Read => DeliveryStatus::Read,
+        };
+        if status <= recipient.status {
+            return None;
+        }
+        recipient.status = status;
+        Some((recipient.name.clone(), status))
+    }
+
+    /// Recipients still pending `ack_timeout` after their last attempt. Those with attempts left
+    /// are returned in `resend` as (peer ID, message); those that have used up `max_attempts` are
+    /// marked failed and returned in `failed`.
+    pub fn due_retries(&mut self, ack_timeout: Duration, max_attempts: u32) -> RetryPlan {
+        let now = Instant::now();
+        let mut plan = RetryPlan::default();
+        for tracked in &mut self.sent {
+            for (peer_id, recipient) in &mut tracked.recipients {
+                if recipient.status != DeliveryStatus::Pending
+                    || now.duration_since(recipient.last_attempt) < ack_timeout
+                {
+                    continue;
+                }
+                if recipient.attempts >= max_attempts {
+                    recipient.status = DeliveryStatus::Failed;
+                    plan.failed.push(FailedDelivery {
+                        message_id: tracked.message_id.clone(),
+                        peer_id: peer_id.clone(),
+                        peer_name: recipient.name.clone(),
+                    });
+                    continue;
+                }
+                recipient.attempts += 1;
+                recipient.last_attempt = now;
+                plan.resend.push((peer_id.clone(), tracked.message.clone()));
+            }
+        }
+        plan
+    }
+
+    /// The last `n` tracked messages, oldest first.
+    pub fn recent(&self, n: usize) -> impl Iterator<Item = &TrackedMessage> {
+        self.sent.iter().skip(self.sent.len().saturating_sub(n))
+    }
+
+    /// Remember that a message from `sender_id` was shown to our user.
+    pub fn mark_unread(&mut self, sender_id: &str, message_id: &str) {
+        if self.unread.len() == MAX_UNREAD {
+            self.unread.pop_front();
+        }
+        self.unread
+            .push_back((sender_id.to_string(), message_id.to_string()));
+    }
+
+    /// Take every message we owe a read receipt for.
+    pub fn take_unread(&mut self) -> Vec<(String, String)> {
+        self.unread.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> ReceiptTracker {
+        let mut tracker = ReceiptTracker::default();
+        tracker.track(
+            "m1",
+            "hello",
+            NetworkMessage::Heartbeat("me".to_string()),
+            [
+                ("bob".to_string(), "Bob".to_string()),
+                ("carol".to_string(), "Carol".to_string()),
+            ],
+        );
+        tracker
+    }
+
+    fn status(tracker: &ReceiptTracker, peer_id: &str) -> DeliveryStatus {
+        tracker.recent(1).next().unwrap().recipients[peer_id].status
+    }
+
+    #[test]
+    fn test_acknowledgements_only_move_forward() {
+        let mut tracker = tracker();
+        assert_eq!(
+            tracker.acknowledge("m1", "bob", AckKind::Read),
+            Some(("Bob".to_string(), DeliveryStatus::Read))
+        );
+        assert_eq!(tracker.acknowledge("m1", "bob", AckKind::Delivered), None);
+        assert_eq!(status(&tracker, "bob"), DeliveryStatus::Read);
+        // Unknown message or a peer it wasn't sent to
+        assert_eq!(tracker.acknowledge("m2", "bob", AckKind::Delivered), None);
+        assert_eq!(
+            tracker.acknowledge("m1", "mallory", AckKind::Delivered),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pending_recipients_retried_then_failed() {
+        let mut tracker = tracker();
+        tracker.acknowledge("m1", "bob", AckKind::Delivered);
+        let plan = tracker.due_retries(Duration::ZERO, 2);
+        assert_eq!(plan.resend.len(), 1);
+        assert_eq!(plan.resend[0].0, "carol");
+        let plan = tracker.due_retries(Duration::ZERO, 2);
+        assert!(plan.resend.is_empty());
+        assert_eq!(plan.failed[0].peer_name, "Carol");
+        assert_eq!(status(&tracker, "carol"), DeliveryStatus::Failed);
+        assert_eq!(status(&tracker, "bob"), DeliveryStatus::Delivered);
+    }
+
+    #[test]
+    fn test_unread_queue_is_bounded() {
+        let mut tracker = ReceiptTracker::default();
+        for i in 0..MAX_UNREAD + 10 {
+            tracker.mark_unread("bob", &format!("m{}", i));
+        }
+        let unread = tracker.take_unread();
+        assert_eq!(unread.len(), MAX_UNREAD);
+        assert_eq!(unread[0].1, "m10");
+        assert!(tracker.take_unread().is_empty());
+    }
+}
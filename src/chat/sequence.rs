@@ -0,0 +1,97 @@
+//! Sequence module: Numbers our outgoing channel messages and checks the numbering of incoming ones.
+//!
+//! Every sender counts its messages per channel, starting at 1. A receiver remembers the highest
+//! number it has seen from each sender in each channel, so a jump in the numbering means messages
+//! were lost on the way. The first message seen from a sender in a channel only sets the baseline,
+//! since we can't know what was said before we joined.
+
+use crate::history::HistoryStore;
+use std::collections::HashMap;
+
+/// Next sequence number to use per channel for our own messages.
+#[derive(Debug, Default)]
+pub struct SequenceCounter {
+    last: HashMap<String, u64>,
+}
+
+impl SequenceCounter {
+    /// Continue the numbering recorded in our message history.
+    pub fn from_history(history: &HistoryStore) -> Self {
+        Self {
+            last: history.own_sequences(),
+        }
+    }
+
+    pub fn next(&mut self, channel: &str) -> u64 {
+        let seq = self.last.entry(channel.to_string()).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+}
+
+/// How an incoming sequence number relates to what we have seen from that sender before.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SequenceCheck {
+    /// First message from this sender in this channel.
+    First,
+    /// Exactly the next expected number.
+    InOrder,
+    /// Numbers were skipped: `missed` messages never reached us.
+    Gap { missed: u64 },
+    /// At or below a number already seen, e.g. a late arrival or a sender that lost its history.
+    Behind,
+}
+
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    /// (sender ID, channel) -> highest sequence number seen
+    seen: HashMap<(String, String), u64>,
+}
+
+impl SequenceTracker {
+    pub fn observe(&mut self, from_id: &str, channel: &str, seq: u64) -> SequenceCheck {
+        let key = (from_id.to_string(), channel.to_string());
+        let Some(last) = self.seen.get_mut(&key) else {
+            self.seen.insert(key, seq);
+            return SequenceCheck::First;
+        };
+        if seq <= *last {
+            return SequenceCheck::Behind;
+        }
+        let missed = seq - *last - 1;
+        *last = seq;
+        if missed == 0 {
+            SequenceCheck::InOrder
+        } else {
+            SequenceCheck::Gap { missed }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_is_per_channel() {
+        let mut counter = SequenceCounter::default();
+        assert_eq!(counter.next("general"), 1);
+        assert_eq!(counter.next("general"), 2);
+        assert_eq!(counter.next("rust"), 1);
+    }
+
+    #[test]
+    fn test_tracker_detects_gaps() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.observe("a", "general", 5), SequenceCheck::First);
+        assert_eq!(tracker.observe("a", "general", 6), SequenceCheck::InOrder);
+        assert_eq!(
+            tracker.observe("a", "general", 9),
+            SequenceCheck::Gap { missed: 2 }
+        );
+        assert_eq!(tracker.observe("a", "general", 7), SequenceCheck::Behind);
+        // Other senders and channels are tracked separately
+        assert_eq!(tracker.observe("b", "general", 1), SequenceCheck::First);
+        assert_eq!(tracker.observe("a", "rust", 1), SequenceCheck::First);
+    }
+}
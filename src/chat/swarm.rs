@@ -0,0 +1,263 @@
+//! Swarm module: Content-addressed file shares that every peer holding a chunk can serve.
+//!
+//! `/share` splits a file into fixed-size chunks and describes it with a `ShareManifest`: the
+//! SHA-256 of the whole file, which doubles as the share ID, and one SHA-256 per chunk. Every peer
+//! remembers the shares it has heard of, which chunks it holds itself and which chunks other peers
+//! said they hold, so a download can pull different chunks from different peers at once. The
+//! network side lives in `chat::net::swarm`.
+
+use crate::chat::transfer::short_id;
+use crate::error::ChatError;
+use crate::peer::ShareManifest;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Bytes per chunk of a new share.
+pub const SHARE_CHUNK_LEN: u32 = 256 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Share {
+    pub manifest: ShareManifest,
+    /// Our copy of the data: the shared file itself, a partial download or the finished one.
+    /// `None` until we start fetching.
+    pub path: Option<PathBuf>,
+    /// Which chunks we hold and have verified.
+    pub have: Vec<bool>,
+    /// Chunks other peers said they hold, by peer ID.
+    pub sources: HashMap<String, BTreeSet<u32>>,
+    /// Whether a download is running right now.
+    pub fetching: bool,
+}
+
+impl Share {
+    pub fn have_count(&self) -> usize {
+        self.have.iter().filter(|&&have| have).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|&have| have)
+    }
+
+    /// The indices of the chunks we hold, as announced in `ShareHave`.
+    pub fn have_list(&self) -> Vec<u32> {
+        (0..self.have.len() as u32)
+            .filter(|&index| self.have[index as usize])
+            .collect()
+    }
+
+    /// Whether `peer_id` holds any chunk we are still missing.
+    pub fn can_help(&self, peer_id: &str) -> bool {
+        self.sources
+            .get(peer_id)
+            .is_some_and(|chunks| chunks.iter().any(|&i| !self.have[i as usize]))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Shares {
+    shares: HashMap<String, Share>,
+}
+
+impl Shares {
+    /// Add a share of a local file we hold completely.
+    pub fn seed(&mut self, manifest: ShareManifest, path: PathBuf) {
+        let have = vec![true; manifest.chunks.len()];
+        self.shares.insert(
+            manifest.id.clone(),
+            Share {
+                manifest,
+                path: Some(path),
+                have,
+                sources: HashMap::new(),
+                fetching: false,
+            },
+        );
+    }
+
+    /// Record a share announced by `from_id`, who holds all of it. Returns `true` if the share
+    /// is new to us.
+    pub fn announced(&mut self, manifest: ShareManifest, from_id: &str) -> bool {
+        let all: BTreeSet<u32> = (0..manifest.chunks.len() as u32).collect();
+        if let Some(share) = self.shares.get_mut(&manifest.id) {
+            share.sources.insert(from_id.to_string(), all);
+            return false;
+        }
+        let have = vec![false; manifest.chunks.len()];
+        self.shares.insert(
+            manifest.id.clone(),
+            Share {
+                manifest,
+                path: None,
+                have,
+                sources: HashMap::from([(from_id.to_string(), all)]),
+                fetching: false,
+            },
+        );
+        true
+    }
+
+    /// Record which chunks of a share `peer_id` holds. Shares we haven't heard of are ignored.
+    pub fn record_have(&mut self, share_id: &str, peer_id: &str, chunks: &[u32]) {
+        let Some(share) = self.shares.get_mut(share_id) else {
+            return;
+        };
+        let count = share.have.len() as u32;
+        let chunks: BTreeSet<u32> = chunks.iter().copied().filter(|&i| i < count).collect();
+        share.sources.insert(peer_id.to_string(), chunks);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Share> {
+        self.shares.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Share> {
+        self.shares.get_mut(id)
+    }
+
+    /// The share whose ID starts with `query`, if exactly one does.
+    pub fn find(&self, query: &str) -> Option<&Share> {
+        if query.is_empty() {
+            return None;
+        }
+        let mut matches = self
+            .shares
+            .values()
+            .filter(|s| s.manifest.id.starts_with(query));
+        match (matches.next(), matches.next()) {
+            (Some(share), None) => Some(share),
+            _ => None,
+        }
+    }
+
+    /// Every share we know, sorted by file name.
+    pub fn list(&self) -> Vec<&Share> {
+        let mut shares: Vec<_> = self.shares.values().collect();
+        shares.sort_by(|a, b| a.manifest.file_name.cmp(&b.manifest.file_name));
+        shares
+    }
+}
+
+/// Where the chunks of a share are collected until the whole file has been verified.
+pub fn partial_share_path(download_dir: &Path, manifest: &ShareManifest) -> PathBuf {
+    download_dir.join(format!(
+        ".{}.{}.share.part",
+        manifest.file_name,
+        short_id(&manifest.id)
+    ))
+}
+
+/// Whether `data` is chunk `index` of the share.
+pub fn verify_chunk(manifest: &ShareManifest, index: u32, data: &[u8]) -> bool {
+    let expected = manifest.chunk_range(index).map(|(_, len)| len);
+    expected == Some(data.len())
+        && manifest.chunks[index as usize] == hex::encode(Sha256::digest(data))
+}
+
+/// Read a file once, hashing each chunk and the whole file, and describe it as a share from us.
+pub async fn build_manifest(
+    path: &Path,
+    file_name: String,
+    from_id: String,
+    from_name: String,
+) -> Result<ShareManifest, ChatError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut whole = Sha256::new();
+    let mut chunks = Vec::new();
+    let mut size = 0u64;
+    let mut buf = vec![0u8; SHARE_CHUNK_LEN as usize];
+    loop {
+        // Fill the buffer completely, so every chunk but the last is exactly `SHARE_CHUNK_LEN`
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = file.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        whole.update(&buf[..filled]);
+        chunks.push(hex::encode(Sha256::digest(&buf[..filled])));
+        size += filled as u64;
+        if filled < buf.len() {
+            break;
+        }
+    }
+    Ok(ShareManifest {
+        id: hex::encode(whole.finalize()),
+        from_id,
+        from_name,
+        file_name,
+        size,
+        chunk_len: SHARE_CHUNK_LEN,
+        chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manifest_and_chunk_verification() {
+        let path = std::env::temp_dir().join(format!("p2p_chat_share_{}", std::process::id()));
+        let contents: Vec<u8> = (0..(SHARE_CHUNK_LEN * 2 + 10))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, &contents).unwrap();
+        let manifest = build_manifest(&path, "data.bin".into(), "me".into(), "Me".into())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(manifest.is_valid());
+        assert_eq!(manifest.size, contents.len() as u64);
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(manifest.id, hex::encode(Sha256::digest(&contents)));
+        assert_eq!(
+            manifest.chunk_range(2),
+            Some((SHARE_CHUNK_LEN as u64 * 2, 10))
+        );
+        assert_eq!(manifest.chunk_range(3), None);
+        let last = &contents[SHARE_CHUNK_LEN as usize * 2..];
+        assert!(verify_chunk(&manifest, 2, last));
+        assert!(!verify_chunk(&manifest, 1, last));
+        assert!(!verify_chunk(&manifest, 2, b"tampered!!"));
+
+        let mut truncated = manifest.clone();
+        truncated.chunks.pop();
+        assert!(!truncated.is_valid());
+    }
+
+    #[test]
+    fn test_sources_and_progress() {
+        let manifest = ShareManifest {
+            id: "ab".repeat(32),
+            from_id: "alice".to_string(),
+            from_name: "Alice".to_string(),
+            file_name: "data.bin".to_string(),
+            size: 30,
+            chunk_len: 10,
+            chunks: vec!["00".repeat(32); 3],
+        };
+        let mut shares = Shares::default();
+        assert!(shares.announced(manifest.clone(), "alice"));
+        assert!(!shares.announced(manifest.clone(), "alice"));
+        shares.record_have(&manifest.id, "bob", &[1, 7]);
+        shares.record_have("unknown", "bob", &[0]);
+
+        let share = shares.get_mut(&manifest.id).unwrap();
+        assert_eq!(share.sources["bob"], BTreeSet::from([1]));
+        assert!(share.can_help("bob"));
+        share.have[1] = true;
+        assert!(!share.can_help("bob"));
+        assert!(share.can_help("alice"));
+        assert_eq!(share.have_list(), [1]);
+        assert!(!share.is_complete());
+        assert!(shares.find("abab").is_some());
+    }
+}
@@ -0,0 +1,281 @@
+//! Transfer module: Bookkeeping for the file offers we made and the ones we received.
+//!
+//! An outgoing offer remembers which local file it refers to, so the recipient can download it
+//! (or resume a download) for as long as we run. An incoming offer stays listed until it has been
+//! downloaded and verified or declined; a download that breaks off leaves its partial file in the
+//! download directory, and accepting the offer again continues where it stopped. The network side
+//! lives in `chat::net::transfer`.
+
+use crate::error::ChatError;
+use crate::peer::{FileOffer, PeerInfo};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Bytes read from disk and sent per encrypted message.
+pub const FILE_CHUNK_LEN: usize = 64 * 1024;
+
+/// An offer we sent, and the file it refers to.
+#[derive(Debug, Clone)]
+pub struct OutgoingFile {
+    pub offer: FileOffer,
+    pub path: PathBuf,
+    pub to: PeerInfo,
+}
+
+/// An offer we received.
+#[derive(Debug, Clone)]
+pub struct IncomingFile {
+    pub offer: FileOffer,
+    /// Whether a download is running right now.
+    pub downloading: bool,
+}
+
+#[derive(Debug)]
+pub struct FileTransfers {
+    download_dir: PathBuf,
+    outgoing: HashMap<String, OutgoingFile>,
+    incoming: HashMap<String, IncomingFile>,
+}
+
+impl Default for FileTransfers {
+    fn default() -> Self {
+        Self::new(default_download_dir())
+    }
+}
+
+/// Where received files are saved unless configured otherwise.
+pub fn default_download_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(dirs::data_dir)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("p2p_chat")
+}
+
+/// The file name to save an offered file under, or `None` if it isn't a plain file name (a path,
+/// `..`, a hidden file) and the offer must be rejected.
+pub fn safe_file_name(name: &str) -> Option<&str> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\', '\0'])
+        && Path::new(name).file_name() == Some(name.as_ref());
+    valid.then_some(name)
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+pub async fn file_sha256(path: &Path) -> Result<String, ChatError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; FILE_CHUNK_LEN];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// A byte count for humans, e.g. "1.5 MB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Find the one entry whose ID starts with `query`.
+fn find_by_prefix<'a, T>(entries: &'a HashMap<String, T>, query: &str) -> Option<&'a T> {
+    if query.is_empty() {
+        return None;
+    }
+    let mut matches = entries
+        .iter()
+        .filter(|(id, _)| id.starts_with(query))
+        .map(|(_, entry)| entry);
+    match (matches.next(), matches.next()) {
+        (Some(entry), None) => Some(entry),
+        _ => None,
+    }
+}
+
+impl FileTransfers {
+    pub fn new(download_dir: PathBuf) -> Self {
+        Self {
+            download_dir,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn set_download_dir(&mut self, dir: PathBuf) {
+        self.download_dir = dir;
+    }
+
+    pub fn add_outgoing(&mut self, file: OutgoingFile) {
+        self.outgoing.insert(file.offer.id.clone(), file);
+    }
+
+    pub fn outgoing(&self, id: &str) -> Option<&OutgoingFile> {
+        self.outgoing.get(id)
+    }
+
+    pub fn remove_outgoing(&mut self, id: &str) -> Option<OutgoingFile> {
+        self.outgoing.remove(id)
+    }
+
+    /// Record an offer we received. Returns `false` if we already know it.
+    pub fn add_incoming(&mut self, offer: FileOffer) -> bool {
+        if self.incoming.contains_key(&offer.id) {
+            return false;
+        }
+        self.incoming.insert(
+            offer.id.clone(),
+            IncomingFile {
+                offer,
+                downloading: false,
+            },
+        );
+        true
+    }
+
+    /// The received offer whose ID starts with `query`, if exactly one does.
+    pub fn find_incoming(&self, query: &str) -> Option<&IncomingFile> {
+        find_by_prefix(&self.incoming, query)
+    }
+
+    /// Mark a download as running. Returns `false` if it already is (or the offer is unknown).
+    pub fn start_download(&mut self, id: &str) -> bool {
+        match self.incoming.get_mut(id) {
+            Some(file) if !file.downloading => {
+                file.downloading = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A download ended: forget the offer once it succeeded, keep it for a retry otherwise.
+    pub fn finish_download(&mut self, id: &str, completed: bool) {
+        if completed {
+            self.incoming.remove(id);
+        } else if let Some(file) = self.incoming.get_mut(id) {
+            file.downloading = false;
+        }
+    }
+
+    pub fn remove_incoming(&mut self, id: &str) -> Option<IncomingFile> {
+        self.incoming.remove(id)
+    }
+
+    /// Our offers and the ones we received, each sorted by file name.
+    pub fn list(&self) -> (Vec<&OutgoingFile>, Vec<&IncomingFile>) {
+        let mut outgoing: Vec<_> = self.outgoing.values().collect();
+        outgoing.sort_by(|a, b| a.offer.file_name.cmp(&b.offer.file_name));
+        let mut incoming: Vec<_> = self.incoming.values().collect();
+        incoming.sort_by(|a, b| a.offer.file_name.cmp(&b.offer.file_name));
+        (outgoing, incoming)
+    }
+
+    /// Where the bytes of an incoming offer are collected until the checksum has been verified.
+    pub fn partial_path(&self, offer: &FileOffer) -> PathBuf {
+        self.download_dir
+            .join(format!(".{}.{}.part", offer.file_name, short_id(&offer.id)))
+    }
+
+    /// Where a verified download is saved.
+    pub fn final_path(&self, offer: &FileOffer) -> PathBuf {
+        unique_path(&self.download_dir, &offer.file_name, &offer.id)
+    }
+}
+
+/// `dir/file_name`, or if a file by that name exists, the name with a prefix of `id` added.
+pub fn unique_path(dir: &Path, file_name: &str, id: &str) -> PathBuf {
+    let path = dir.join(file_name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(file_name);
+    let stem = name.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
+    let renamed = match name.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}-{}.{}", stem, short_id(id), ext),
+        None => format!("{}-{}", stem, short_id(id)),
+    };
+    dir.join(renamed)
+}
+
+/// The prefix of an offer ID shown to users and accepted by `/accept`.
+pub fn short_id(id: &str) -> &str {
+    id.get(..8).unwrap_or(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(id: &str, file_name: &str) -> FileOffer {
+        FileOffer {
+            id: id.to_string(),
+            from_id: "alice".to_string(),
+            from_name: "Alice".to_string(),
+            file_name: file_name.to_string(),
+            size: 10,
+            sha256: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_safe_file_name() {
+        assert_eq!(safe_file_name("report.pdf"), Some("report.pdf"));
+        assert_eq!(safe_file_name("../etc/passwd"), None);
+        assert_eq!(safe_file_name("dir/file"), None);
+        assert_eq!(safe_file_name(".."), None);
+        assert_eq!(safe_file_name(".bashrc"), None);
+        assert_eq!(safe_file_name(""), None);
+    }
+
+    #[test]
+    fn test_incoming_offers_by_prefix() {
+        let mut transfers = FileTransfers::new(PathBuf::from("/downloads"));
+        assert!(transfers.add_incoming(offer("aaaa1111", "a.txt")));
+        assert!(transfers.add_incoming(offer("aaaa2222", "b.txt")));
+        assert!(!transfers.add_incoming(offer("aaaa2222", "b.txt")));
+        assert!(transfers.find_incoming("aaaa").is_none());
+        assert_eq!(
+            transfers.find_incoming("aaaa2").unwrap().offer.file_name,
+            "b.txt"
+        );
+
+        assert!(transfers.start_download("aaaa1111"));
+        assert!(!transfers.start_download("aaaa1111"));
+        transfers.finish_download("aaaa1111", false);
+        assert!(transfers.start_download("aaaa1111"));
+        transfers.finish_download("aaaa1111", true);
+        assert!(transfers.find_incoming("aaaa1").is_none());
+        assert_eq!(
+            transfers.partial_path(&offer("aaaa2222", "b.txt")),
+            PathBuf::from("/downloads/.b.txt.aaaa2222.part")
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
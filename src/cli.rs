@@ -1,4 +1,7 @@
-use clap::{Parser, Subcommand};
+use crate::config::{default_config_path, ColorMode, Config, DiscoveryBackend};
+use crate::error::ChatError;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "p2p_chat")]
@@ -12,11 +15,187 @@ pub struct Cli {
 pub enum Commands {
     /// Start the Chat (discover peers and listen for messages)
     Start {
-        /// Port to listen on for TCP connections
-        #[arg(short, long, default_value = "9999")]
-        port: u16,
-        /// Your display name
-        #[arg(short, long, default_value = "Anonymous")]
-        name: String,
+        #[command(flatten)]
+        settings: SettingsArgs,
+        /// Use the full-screen terminal UI (requires building with the `tui` feature)
+        #[arg(long)]
+        tui: bool,
     },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration: the config file with command line flags applied
+    Show {
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+}
+
+/// Settings that can come from the config file; flags given here override the file.
+#[derive(Args, Clone)]
+pub struct SettingsArgs {
+    /// TOML config file (default: p2p_chat/config.toml in the user's config directory)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Port to listen on for TCP connections [default: 9999]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Your display name [default: Anonymous]
+    #[arg(short, long)]
+    pub name: Option<String>,
+    /// Path to the identity key file (created on first run)
+    #[arg(long)]
+    pub identity: Option<PathBuf>,
+    /// Discovery backends to run, comma-separated: mdns, beacon, static, registry [default: mdns,beacon]
+    #[arg(long, value_delimiter = ',', value_parser = parse_discovery)]
+    pub discovery: Option<Vec<DiscoveryBackend>>,
+    /// Don't advertise or browse for peers over mDNS
+    #[arg(long)]
+    pub no_mdns: bool,
+    /// Connect to the peer at host:port on startup, and again whenever it drops out (repeatable;
+    /// adds the static discovery backend)
+    #[arg(long, value_name = "HOST:PORT")]
+    pub connect: Vec<String>,
+    /// Directory for the registry discovery backend
+    #[arg(long)]
+    pub registry: Option<PathBuf>,
+    /// Seconds between our own heartbeats [default: 10]
+    #[arg(long)]
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds without a heartbeat before a peer is shown as stale [default: 30]
+    #[arg(long)]
+    pub stale_after: Option<u64>,
+    /// Seconds without a heartbeat before a peer is removed [default: 90]
+    #[arg(long)]
+    pub remove_after: Option<u64>,
+    /// When to use colors: auto, always or never [default: auto]
+    #[arg(long, value_parser = parse_color)]
+    pub color: Option<ColorMode>,
+}
+
+impl SettingsArgs {
+    /// The config file to read: `--config` if given, otherwise the default path if it exists.
+    pub fn config_path(&self) -> Option<PathBuf> {
+        match &self.config {
+            Some(path) => Some(path.clone()),
+            None => Some(default_config_path()).filter(|path| path.exists()),
+        }
+    }
+
+    /// Read the config file (if any) and apply the flags on top of it.
+    pub fn resolve(&self) -> Result<Config, ChatError> {
+        let mut config = match self.config_path() {
+            Some(path) => Config::load(&path)?,
+            None => Config::default(),
+        };
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(name) = &self.name {
+            config.name = name.clone();
+        }
+        if let Some(identity) = &self.identity {
+            config.identity = identity.clone();
+        }
+        if let Some(methods) = &self.discovery {
+            config.discovery.methods = methods.clone();
+        }
+        if self.no_mdns {
+            config
+                .discovery
+                .methods
+                .retain(|method| *method != DiscoveryBackend::Mdns);
+        }
+        if !self.connect.is_empty() {
+            config.discovery.peers.extend(self.connect.iter().cloned());
+            if !config.discovery.methods.contains(&DiscoveryBackend::Static) {
+                config.discovery.methods.push(DiscoveryBackend::Static);
+            }
+        }
+        if let Some(registry) = &self.registry {
+            config.discovery.registry = registry.clone();
+        }
+        if let Some(seconds) = self.heartbeat_interval {
+            config.timing.heartbeat_interval = seconds;
+        }
+        if let Some(seconds) = self.stale_after {
+            config.timing.stale_after = seconds;
+        }
+        if let Some(seconds) = self.remove_after {
+            config.timing.remove_after = seconds;
+        }
+        if let Some(color) = self.color {
+            config.color = color;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_color(value: &str) -> Result<ColorMode, String> {
+    match value {
+        "auto" => Ok(ColorMode::Auto),
+        "always" => Ok(ColorMode::Always),
+        "never" => Ok(ColorMode::Never),
+        _ => Err("expected auto, always or never".to_string()),
+    }
+}
+
+fn parse_discovery(value: &str) -> Result<DiscoveryBackend, String> {
+    DiscoveryBackend::parse(value)
+        .ok_or_else(|| "expected mdns, beacon, static or registry".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let file = std::env::temp_dir().join(format!("p2p_chat_config_{}", std::process::id()));
+        std::fs::write(
+            &file,
+            "name = \"Alice\"\nport = 7000\n[timing]\nstale_after = 20\n",
+        )
+        .unwrap();
+        let path = file.to_str().unwrap().to_string();
+        let cli = Cli::try_parse_from([
+            "p2p_chat",
+            "config",
+            "show",
+            "--config",
+            &path,
+            "--port",
+            "8000",
+            "--color",
+            "never",
+            "--discovery",
+            "beacon",
+            "--connect",
+            "10.0.0.7:9999",
+        ])
+        .unwrap();
+        let Commands::Config {
+            command: ConfigCommand::Show { settings },
+        } = cli.command
+        else {
+            panic!("expected config show");
+        };
+        let config = settings.resolve().unwrap();
+        assert_eq!(config.name, "Alice");
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.timing.stale_after, 20);
+        assert_eq!(config.color, ColorMode::Never);
+        assert_eq!(
+            config.discovery.methods,
+            [DiscoveryBackend::Beacon, DiscoveryBackend::Static]
+        );
+        assert_eq!(config.discovery.peers, ["10.0.0.7:9999"]);
+        let _ = std::fs::remove_file(&file);
+    }
 }
@@ -0,0 +1,263 @@
+//! Config module: Settings read from a TOML file, with built-in defaults for anything left out.
+//!
+//! The file is looked up at `--config <path>`, or else at `default_config_path()` (under
+//! `$XDG_CONFIG_HOME` on Linux) if it exists. Command line flags override values from the file,
+//! and `config show` prints the result. The name, channels, ignore list and download directory
+//! can also change while the chat is running: on SIGHUP the binary reads the file again and hands
+//! it to `Peer::apply_config`. Everything else takes effect on the next start.
+//!
+//! ```toml
+//! name = "Alice"
+//! port = 9999
+//! identity = "/home/alice/.local/share/p2p_chat/identity.key"
+//! color = "auto"
+//! downloads = "/home/alice/Downloads/p2p_chat"
+//! channels = ["rust", "ops"]
+//! ignore = ["Mallory"]
+//!
+//! [discovery]
+//! methods = ["mdns", "beacon", "static"]
+//! peers = ["192.168.1.20:9999", "build-box.lan:9999"]
+//! registry = "/home/alice/.local/share/p2p_chat/registry"
+//!
+//! [timing]
+//! heartbeat_interval = 10
+//! stale_after = 30
+//! remove_after = 90
+//! ```
+
+use crate::chat::net::liveness::LivenessConfig;
+use crate::chat::transfer::default_download_dir;
+use crate::error::ChatError;
+use crate::identity::default_identity_path;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Display name announced to other peers.
+    pub name: String,
+    /// TCP port to listen on.
+    pub port: u16,
+    /// Identity key file; known peers, history and outbox are stored next to it.
+    pub identity: PathBuf,
+    /// When the terminal front-end uses colors.
+    pub color: ColorMode,
+    /// Directory received files are saved to.
+    pub downloads: PathBuf,
+    /// Channels to join besides `#general`.
+    pub channels: Vec<String>,
+    /// Peer names or IDs whose messages are dropped.
+    pub ignore: Vec<String>,
+    pub discovery: DiscoveryConfig,
+    pub timing: TimingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: "Anonymous".to_string(),
+            port: 9999,
+            identity: default_identity_path(),
+            color: ColorMode::default(),
+            downloads: default_download_dir(),
+            channels: Vec::new(),
+            ignore: Vec::new(),
+            discovery: DiscoveryConfig::default(),
+            timing: TimingConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Colors when writing to a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Which discovery mechanisms to run, and their settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Discovery backends to run; see `chat::net::discovery`.
+    pub methods: Vec<DiscoveryBackend>,
+    /// `host:port` addresses the `static` backend connects to.
+    pub peers: Vec<String>,
+    /// Directory the `registry` backend lists peers in.
+    pub registry: PathBuf,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            methods: vec![DiscoveryBackend::Mdns, DiscoveryBackend::Beacon],
+            peers: Vec::new(),
+            registry: default_registry_dir(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryBackend {
+    /// Advertise and browse over mDNS.
+    Mdns,
+    /// Broadcast and listen for UDP beacons.
+    Beacon,
+    /// Connect to the addresses in `peers`.
+    Static,
+    /// List ourselves in, and read peers from, a shared directory.
+    Registry,
+}
+
+impl DiscoveryBackend {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mdns" => Some(Self::Mdns),
+            "beacon" => Some(Self::Beacon),
+            "static" => Some(Self::Static),
+            "registry" => Some(Self::Registry),
+            _ => None,
+        }
+    }
+}
+
+/// Heartbeat and liveness timing, in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    pub heartbeat_interval: u64,
+    /// Silence after which a peer is shown as stale.
+    pub stale_after: u64,
+    /// Silence after which a peer is removed.
+    pub remove_after: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        let liveness = LivenessConfig::default();
+        Self {
+            heartbeat_interval: liveness.heartbeat_interval.as_secs(),
+            stale_after: liveness.stale_after.as_secs(),
+            remove_after: liveness.remove_after.as_secs(),
+        }
+    }
+}
+
+/// Directory used by the `registry` discovery backend when none is configured, shared by every
+/// instance run by the same user.
+pub fn default_registry_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("p2p_chat")
+        .join("registry")
+}
+
+/// Location of the config file used when `--config` isn't given, under the user's config
+/// directory.
+pub fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("p2p_chat")
+        .join("config.toml")
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ChatError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ChatError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        Self::parse(&text)
+            .map_err(|e| ChatError::Config(format!("{}: {}", path.display(), e.message())))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// The configuration as a TOML document.
+    pub fn to_toml(&self) -> Result<String, ChatError> {
+        toml::to_string(self).map_err(|e| ChatError::Serialization(e.to_string()))
+    }
+
+    pub fn liveness(&self) -> Result<LivenessConfig, ChatError> {
+        let timing = &self.timing;
+        if timing.heartbeat_interval == 0 || timing.stale_after == 0 {
+            return Err(ChatError::Config(
+                "heartbeat_interval and stale_after must be at least 1 second".to_string(),
+            ));
+        }
+        if timing.remove_after < timing.stale_after {
+            return Err(ChatError::Config(
+                "remove_after must not be shorter than stale_after".to_string(),
+            ));
+        }
+        Ok(LivenessConfig {
+            heartbeat_interval: Duration::from_secs(timing.heartbeat_interval),
+            stale_after: Duration::from_secs(timing.stale_after),
+            remove_after: Duration::from_secs(timing.remove_after),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            name = "Alice"
+            channels = ["rust"]
+            ignore = ["Mallory"]
+            color = "never"
+
+            [timing]
+            stale_after = 20
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.name, "Alice");
+        assert_eq!(config.channels, ["rust"]);
+        assert_eq!(config.ignore, ["Mallory"]);
+        assert_eq!(config.color, ColorMode::Never);
+        // Unset values keep their defaults, also inside a table
+        assert_eq!(config.port, 9999);
+        assert_eq!(
+            config.discovery.methods,
+            [DiscoveryBackend::Mdns, DiscoveryBackend::Beacon]
+        );
+        assert_eq!(config.timing.stale_after, 20);
+        assert_eq!(config.timing.remove_after, 90);
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("nmae = \"typo\"").is_err());
+        assert!(Config::parse("color = \"pink\"").is_err());
+        let config = Config::parse("[discovery]\nmethods = [\"beacon\", \"registry\"]").unwrap();
+        assert_eq!(
+            config.discovery.methods,
+            [DiscoveryBackend::Beacon, DiscoveryBackend::Registry]
+        );
+        assert!(Config::parse("[discovery]\nmethods = [\"carrier-pigeon\"]").is_err());
+    }
+
+    #[test]
+    fn test_round_trip_and_liveness() {
+        let mut config = Config::default();
+        config.channels.push("ops".to_string());
+        let text = config.to_toml().unwrap();
+        assert_eq!(Config::parse(&text).unwrap(), config);
+        assert_eq!(
+            config.liveness().unwrap().stale_after,
+            Duration::from_secs(30)
+        );
+        config.timing.remove_after = 10;
+        assert!(matches!(config.liveness(), Err(ChatError::Config(_))));
+    }
+}
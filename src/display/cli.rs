@@ -0,0 +1,556 @@
+//! CLI display module: Handles user input, command parsing, and broadcasting messages or exit signals to peers.
+//!
+//! This module provides the functionality for the command-line interface (CLI) of the application,
+//! allowing users to interact with the Chat network. It handles user commands such as
+//! listing peers, sending messages, and quitting the application; saying goodbye to peers is
+//! left to `main` once the handler returns.
+//! Commands are run by `run_command`, which returns the lines to show instead of printing them,
+//! so the full-screen UI in `display::tui` shares the same command set.
+
+use chrono::{Local, TimeZone};
+use p2p_chat::chat::net::broadcast::DeliveryResult;
+use p2p_chat::chat::net::delivery::send_read_receipts;
+use p2p_chat::chat::receipts::DeliveryStatus;
+use p2p_chat::chat::transfer::{format_size, short_id};
+use p2p_chat::chat::Peer;
+use p2p_chat::error::ChatError;
+use p2p_chat::history::HistoryEntry;
+use p2p_chat::known_peers::{fingerprint, safety_number};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// What a front-end should do after running a command.
+#[derive(Debug)]
+pub enum CommandOutcome {
+    /// Show these lines to the user.
+    Output(Vec<String>),
+    /// The user asked to quit.
+    Quit,
+}
+
+/// The command reference shown on startup.
+pub fn help_lines() -> Vec<String> {
+    [
+        "📋 Commands:",
+        "  /list    - List discovered peers",
+        "  /msg <message> - Send message to all peers",
+        "  /dm <peer> <message> - Send a private message to one peer",
+        "  /join #room - Join a channel and make it active",
+        "  /leave   - Leave the active channel",
+        "  /rooms   - List your channels and others seen on the network",
+        "  /history [n] - Show the last n messages (default 20)",
+        "  /status [n] - Show delivery status of your last n messages (default 10)",
+        "  /verify <peer> - Show a peer's fingerprint and safety number",
+        "  /connect <host:port> - Add a peer that discovery doesn't find",
+        "  /send <peer> <path> - Offer a file to one peer",
+        "  /accept <id> - Download (or resume) a file offered to you",
+        "  /decline <id> - Turn down a file offered to you",
+        "  /transfers - List file offers you made and received",
+        "  /share <path> - Share a file with everyone",
+        "  /fetch <id> - Download (or resume) a shared file from whoever has it",
+        "  /shares  - List shared files and how much of each you have",
+        "  /help    - Show this list",
+        "  /quit    - Quit the application",
+        "  Just type any message to broadcast it!",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+fn delivery_lines(results: &[DeliveryResult]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let successful_sends = results.iter().filter(|r| r.is_delivered()).count();
+    for result in results {
+        if let Err(e) = &result.outcome {
+            lines.push(format!(
+                "⚠️  Could not deliver to {} ({}): {}; queued until they are back",
+                result.peer.name, result.peer.id, e
+            ));
+        }
+    }
+    if successful_sends > 0 {
+        lines.push(format!(
+            "📤 Message sent to {} peer(s); use /status to see who has it",
+            successful_sends
+        ));
+    } else {
+        lines.push("📭 No peers available to receive the message".to_string());
+    }
+    lines
+}
+
+/// Number of history entries replayed on startup and by a bare `/history`.
+pub const STARTUP_HISTORY: usize = 20;
+
+pub async fn history_lines(peer: &Peer, n: usize) -> Vec<String> {
+    let history = peer.history.lock().await;
+    if history.is_empty() {
+        return Vec::new();
+    }
+    let recent = history.recent(n);
+    let mut lines = vec![format!("🕘 Last {} message(s):", recent.len())];
+    lines.extend(
+        recent
+            .iter()
+            .map(|entry| format!("  {}", format_history_entry(entry))),
+    );
+    lines
+}
+
+fn format_history_entry(entry: &HistoryEntry) -> String {
+    let message = &entry.message;
+    let when = Local
+        .timestamp_opt(message.timestamp as i64, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+    let place = if entry.direct {
+        "[DM]".to_string()
+    } else {
+        format!("[#{}]", message.channel)
+    };
+    let sender = if entry.outgoing {
+        "You"
+    } else {
+        message.from_name.as_str()
+    };
+    format!("[{}] {} {}: {}", when, place, sender, message.content)
+}
+
+/// Number of sent messages shown by a bare `/status`.
+const STATUS_MESSAGES: usize = 10;
+
+async fn status_lines(peer: &Peer, n: usize) -> Vec<String> {
+    let receipts = peer.receipts.lock().await;
+    let lines: Vec<String> = receipts
+        .recent(n)
+        .map(|tracked| {
+            let recipients: Vec<String> = tracked
+                .recipients
+                .values()
+                .map(|r| format!("{} {} {}", r.name, status_icon(r.status), r.status))
+                .collect();
+            let recipients = if recipients.is_empty() {
+                "no recipients".to_string()
+            } else {
+                recipients.join(", ")
+            };
+            format!("  {} — {}", tracked.summary, recipients)
+        })
+        .collect();
+    if lines.is_empty() {
+        return vec!["📭 You haven't sent any messages yet.".to_string()];
+    }
+    lines
+}
+
+fn status_icon(status: DeliveryStatus) -> &'static str {
+    match status {
+        DeliveryStatus::Pending => "…",
+        DeliveryStatus::Failed => "✗",
+        DeliveryStatus::Delivered => "✓",
+        DeliveryStatus::Read => "✓✓",
+    }
+}
+
+async fn peer_lines(peer: &Peer) -> Vec<String> {
+    let peers = peer.peers.lock().await;
+    let outbox = peer.outbox.lock().await;
+    if peers.is_empty() {
+        return vec!["📭 No peers discovered yet.".to_string()];
+    }
+    let mut lines = vec!["👥 Discovered peers:".to_string()];
+    for peer in peers.values() {
+        if !peer.is_valid() {
+            lines.push(format!("  - Invalid peer: {:?}", peer));
+            continue;
+        }
+        let status = if peer.stale { " [stale]" } else { "" };
+        let queued = match outbox.queued(&peer.id) {
+            0 => String::new(),
+            n => format!(" [{} queued]", n),
+        };
+        lines.push(format!(
+            "  - {} ({}) at {}:{}{}{}",
+            peer.name, peer.id, peer.ip, peer.port, status, queued
+        ));
+    }
+    lines
+}
+
+async fn room_lines(peer: &Peer) -> Vec<String> {
+    let state = peer.channels.lock().await.clone();
+    let mut members: BTreeMap<String, usize> = BTreeMap::new();
+    for info in peer.peers.lock().await.values() {
+        for channel in &info.channels {
+            *members.entry(channel.clone()).or_default() += 1;
+        }
+    }
+    let mut lines = vec!["📚 Your channels:".to_string()];
+    for channel in state.joined() {
+        let marker = if channel == state.active() { "*" } else { " " };
+        let count = members.get(channel).copied().unwrap_or(0);
+        lines.push(format!("  {} #{} ({} peer(s))", marker, channel, count));
+    }
+    let others: Vec<_> = members
+        .iter()
+        .filter(|(channel, _)| !state.is_joined(channel))
+        .collect();
+    if !others.is_empty() {
+        lines.push("🌐 Other channels on the network:".to_string());
+        for (channel, count) in others {
+            lines.push(format!("    #{} ({} peer(s))", channel, count));
+        }
+    }
+    lines
+}
+
+async fn verify_lines(peer: &Peer, query: &str) -> Vec<String> {
+    let Some(info) = peer.find_peer(query).await else {
+        return vec![format!(
+            "❓ No single peer matches \"{}\". Use /list to see peers.",
+            query
+        )];
+    };
+    let known = peer.known_peers.lock().await.is_known(&info.id);
+    let mut lines = vec![
+        format!("🔐 Verifying {} ({})", info.name, info.id),
+        format!("  Their fingerprint: {}", fingerprint(&info.id)),
+        format!("  Your fingerprint:  {}", fingerprint(&peer.peer_id)),
+        format!(
+            "  Safety number:     {}",
+            safety_number(&peer.peer_id, &info.id)
+        ),
+    ];
+    if !known {
+        lines.push("  ⚠️  This key is not in your known peers store.".to_string());
+    }
+    lines.push(format!(
+        "  Compare the safety number with {} in person or over another channel.",
+        info.name
+    ));
+    lines
+}
+
+async fn transfer_lines(peer: &Peer) -> Vec<String> {
+    let transfers = peer.transfers.lock().await;
+    let (outgoing, incoming) = transfers.list();
+    if outgoing.is_empty() && incoming.is_empty() {
+        return vec!["📭 No file offers yet.".to_string()];
+    }
+    let mut lines = Vec::new();
+    if !outgoing.is_empty() {
+        lines.push("📤 Offered by you:".to_string());
+        for file in outgoing {
+            lines.push(format!(
+                "  [{}] {} ({}) to {}",
+                short_id(&file.offer.id),
+                file.offer.file_name,
+                format_size(file.offer.size),
+                file.to.name
+            ));
+        }
+    }
+    if !incoming.is_empty() {
+        lines.push("📥 Offered to you:".to_string());
+        for file in incoming {
+            let state = if file.downloading {
+                " [downloading]"
+            } else {
+                ""
+            };
+            lines.push(format!(
+                "  [{}] {} ({}) from {}{}",
+                short_id(&file.offer.id),
+                file.offer.file_name,
+                format_size(file.offer.size),
+                file.offer.from_name,
+                state
+            ));
+        }
+    }
+    lines
+}
+
+async fn share_lines(peer: &Peer) -> Vec<String> {
+    let shares = peer.shares.lock().await;
+    let shares = shares.list();
+    if shares.is_empty() {
+        return vec!["📭 Nobody has shared a file yet.".to_string()];
+    }
+    let mut lines = vec!["📦 Shared files:".to_string()];
+    for share in shares {
+        let manifest = &share.manifest;
+        let state = if share.is_complete() {
+            "complete".to_string()
+        } else {
+            let fetching = if share.fetching { ", fetching" } else { "" };
+            format!(
+                "{}/{} chunks{}",
+                share.have_count(),
+                share.have.len(),
+                fetching
+            )
+        };
+        lines.push(format!(
+            "  [{}] {} ({}) from {}: {}, {} other source(s)",
+            short_id(&manifest.id),
+            manifest.file_name,
+            format_size(manifest.size),
+            manifest.from_name,
+            state,
+            share.sources.len()
+        ));
+    }
+    lines
+}
+
+/// Parse the optional count argument of commands like `/history [n]`.
+fn count_arg(arg: &str, default: usize) -> Option<usize> {
+    if arg.is_empty() {
+        Some(default)
+    } else {
+        arg.parse().ok()
+    }
+}
+
+/// Run one line of user input: a command, or a message for the active channel.
+pub async fn run_command(peer: &Peer, input: &str) -> CommandOutcome {
+    let input = input.trim();
+    let lines = if input.is_empty() {
+        Vec::new()
+    } else if input.len() > 512 {
+        // Validate input length
+        vec!["Input too long. Please keep messages under 512 characters.".to_string()]
+    } else {
+        // Split off the command word, so e.g. `/dmitri` is a message rather than `/dm`
+        let (command, args) = match input.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (input, ""),
+        };
+        match (command, args) {
+            ("/quit", "") => return CommandOutcome::Quit,
+            ("/help", "") => help_lines(),
+            ("/list", "") => peer_lines(peer).await,
+            ("/leave", "") => match peer.leave_channel().await {
+                Some(left) => vec![format!(
+                    "👋 Left #{}; now in #{}",
+                    left,
+                    peer.channels.lock().await.active()
+                )],
+                None => vec!["You can't leave your only channel.".to_string()],
+            },
+            ("/rooms", "") => room_lines(peer).await,
+            ("/transfers", "") => transfer_lines(peer).await,
+            ("/shares", "") => share_lines(peer).await,
+            ("/history", arg) => match count_arg(arg, STARTUP_HISTORY) {
+                Some(n) => history_lines(peer, n).await,
+                None => vec!["Usage: /history [n]".to_string()],
+            },
+            ("/status", arg) => match count_arg(arg, STATUS_MESSAGES) {
+                Some(n) => status_lines(peer, n).await,
+                None => vec!["Usage: /status [n]".to_string()],
+            },
+            ("/join", name) => match peer.join_channel(name).await {
+                Ok(channel) => vec![format!("🚪 Now talking in #{}", channel)],
+                Err(e) => vec![format!("{}. Usage: /join #room", e)],
+            },
+            ("/dm", args) => match args.split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => {
+                    match peer.send_direct(to, text.trim()).await {
+                        Ok(result) => delivery_lines(std::slice::from_ref(&result)),
+                        Err(e) => vec![format!("Failed to send direct message: {}", e)],
+                    }
+                }
+                _ => vec!["Usage: /dm <name|id> <message>".to_string()],
+            },
+            ("/send", args) => match args.split_once(' ') {
+                Some((to, path)) if !path.trim().is_empty() => {
+                    match peer.send_file(to, Path::new(path.trim())).await {
+                        Ok(offer) => vec![format!(
+                            "📎 Offered {} ({}) to {}; it is sent once they accept",
+                            offer.file_name,
+                            format_size(offer.size),
+                            to
+                        )],
+                        Err(e) => vec![format!("Failed to offer file: {}", e)],
+                    }
+                }
+                _ => vec!["Usage: /send <name|id> <path>".to_string()],
+            },
+            ("/accept", id) => match peer.accept_file(id).await {
+                Ok(offer) => vec![format!(
+                    "📥 Downloading {} from {}…",
+                    offer.file_name, offer.from_name
+                )],
+                Err(e) => vec![format!("{}. Usage: /accept <id>", e)],
+            },
+            ("/decline", id) => match peer.decline_file(id).await {
+                Ok(offer) => vec![format!(
+                    "🚫 Declined {} from {}",
+                    offer.file_name, offer.from_name
+                )],
+                Err(e) => vec![format!("{}. Usage: /decline <id>", e)],
+            },
+            ("/share", path) => match peer.share_file(Path::new(path)).await {
+                Ok((manifest, results)) => {
+                    let mut lines: Vec<String> = results
+                        .iter()
+                        .filter_map(|r| r.outcome.as_ref().err().map(|e| (r, e)))
+                        .map(|(r, e)| format!("⚠️  Could not announce to {}: {}", r.peer.name, e))
+                        .collect();
+                    lines.push(format!(
+                        "📦 Shared {} ({}) as {} with {} peer(s); keep the file in place",
+                        manifest.file_name,
+                        format_size(manifest.size),
+                        short_id(&manifest.id),
+                        results.iter().filter(|r| r.is_delivered()).count()
+                    ));
+                    lines
+                }
+                Err(e) => vec![format!("Failed to share file: {}", e)],
+            },
+            ("/fetch", id) => match peer.fetch_share(id).await {
+                Ok(manifest) => vec![format!(
+                    "📥 Fetching {} ({}) from every peer that has it…",
+                    manifest.file_name,
+                    format_size(manifest.size)
+                )],
+                Err(e) => vec![format!("{}. Usage: /fetch <id>", e)],
+            },
+            ("/connect", addr) => {
+                if addr.is_empty() {
+                    vec!["Usage: /connect <host:port>".to_string()]
+                } else {
+                    match peer.connect(addr).await {
+                        Ok(info) => vec![format!(
+                            "🔗 {} ({}) is reachable at {}:{}",
+                            info.name,
+                            short_id(&info.id),
+                            info.ip,
+                            info.port
+                        )],
+                        Err(e) => vec![format!("Failed to connect to {}: {}", addr, e)],
+                    }
+                }
+            }
+            ("/verify", query) => {
+                if query.is_empty() {
+                    vec!["Usage: /verify <name|id>".to_string()]
+                } else {
+                    verify_lines(peer, query).await
+                }
+            }
+            _ => {
+                let message_content = match (command, args) {
+                    ("/msg", text) if !text.is_empty() => text,
+                    _ => input,
+                };
+                match peer.broadcast_message(message_content).await {
+                    Ok(results) => delivery_lines(&results),
+                    Err(e) => vec![format!("Failed to send message: {}", e)],
+                }
+            }
+        }
+    };
+    CommandOutcome::Output(lines)
+}
+
+/// Read stdin line by line on a plain thread. Unlike `tokio::io::stdin`, a read still blocked
+/// when the user quits (e.g. via Ctrl+C) doesn't keep the runtime from shutting down.
+fn read_stdin_lines() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+pub async fn start_cli_handler(peer: &Peer) -> Result<(), ChatError> {
+    println!();
+    for line in help_lines() {
+        println!("{}", line);
+    }
+    println!();
+    for line in history_lines(peer, STARTUP_HISTORY).await {
+        println!("{}", line);
+    }
+
+    let mut lines = read_stdin_lines();
+    loop {
+        print!("💬 ");
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        let Some(line) = lines.recv().await else {
+            break;
+        };
+        // The user is at the keyboard, so everything displayed so far has been seen
+        send_read_receipts(peer).await;
+        match run_command(peer, &line).await {
+            CommandOutcome::Output(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            CommandOutcome::Quit => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_name_validation() {
+        let p1 = Peer::new("".to_string(), 9000);
+        assert_eq!(p1.name(), "Anonymous");
+
+        let invalid_name_length = 1000;
+        let long_name = "a".repeat(invalid_name_length);
+        let p2 = Peer::new(long_name, 9000);
+        assert_eq!(p2.name(), "Anonymous");
+
+        let valid_name = "Bob".to_string();
+        let p3 = Peer::new(valid_name.clone(), 9000);
+        assert_eq!(p3.name(), valid_name);
+    }
+
+    #[tokio::test]
+    async fn test_run_command_outputs() {
+        let peer = Peer::new("Alice".to_string(), 9000);
+        assert!(matches!(
+            run_command(&peer, "/quit").await,
+            CommandOutcome::Quit
+        ));
+        let CommandOutcome::Output(lines) = run_command(&peer, "/join #rust").await else {
+            panic!("expected output");
+        };
+        assert_eq!(lines, ["🚪 Now talking in #rust"]);
+        let CommandOutcome::Output(lines) = run_command(&peer, "/history x").await else {
+            panic!("expected output");
+        };
+        assert_eq!(lines, ["Usage: /history [n]"]);
+        // Only the whole first word picks the command
+        run_command(&peer, "/joined #go").await;
+        assert_eq!(peer.channels.lock().await.active(), "rust");
+    }
+
+    #[test]
+    fn test_chat_port_validation() {
+        let p1 = Peer::new("Alice".to_string(), 0);
+        assert_eq!(p1.port, 8080);
+        let p2 = Peer::new("Alice".to_string(), 1234);
+        assert_eq!(p2.port, 1234);
+    }
+}
@@ -1,20 +1,24 @@
 //! Message display module: Responsible for displaying incoming messages to the user in the CLI.
 //!
-//! This module contains the `start_message_display` function, which listens for messages
-//! on a broadcast channel and prints them to the standard output. It is designed to be
+//! This module contains the `start_message_display` function, which listens for `ChatEvent`s
+//! on the peer's broadcast channel and prints them to the standard output, with warnings going
+//! to standard error. It is designed to be
 //! run asynchronously, and it expects a reference to a `Peer` instance, which
 //! manages the underlying message sending and receiving.
 
-use crate::chat::Peer;
-use crate::error::ChatError;
+use p2p_chat::chat::events::ChatEvent;
+use p2p_chat::chat::Peer;
+use p2p_chat::error::ChatError;
 use tokio::sync::broadcast;
 
 pub async fn start_message_display(peer: &Peer) -> Result<(), ChatError> {
-    let mut receiver = peer.message_sender.subscribe();
+    let mut receiver = peer.events.subscribe();
     loop {
         match receiver.recv().await {
-            Ok(message) => {
-                println!("\n📨 {}", message);
+            Ok(ChatEvent::Warning(warning)) => eprintln!("{}", warning),
+            Ok(event) if !event.is_shown() => {}
+            Ok(event) => {
+                println!("\n📨 {}", event);
                 print!("💬 ");
                 std::io::Write::flush(&mut std::io::stdout()).unwrap();
             }
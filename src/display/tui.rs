@@ -0,0 +1,419 @@
+//! TUI display module: Full-screen terminal interface built on ratatui and crossterm.
+//!
+//! An alternative to the line-based CLI for terminals, enabled with the `tui` feature and
+//! `start --tui`. The screen is split into a scrollable message pane, a sidebar listing the peers
+//! in `Peer::peers` with their online status, and a persistent input line with cursor editing and
+//! input history. Incoming messages are drawn into the message pane instead of being printed, so
+//! they never overwrite what the user is typing. Commands are shared with the CLI through
+//! `cli::run_command`.
+
+use crate::display::cli::{
+    help_lines, history_lines, run_command, CommandOutcome, STARTUP_HISTORY,
+};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures_util::StreamExt;
+use p2p_chat::chat::events::ChatEvent;
+use p2p_chat::chat::net::delivery::send_read_receipts;
+use p2p_chat::chat::Peer;
+use p2p_chat::error::ChatError;
+use p2p_chat::peer::PeerInfo;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::Stdout;
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+
+/// Lines kept in the message pane.
+const MAX_SCROLLBACK: usize = 1000;
+/// Entries kept in the input history.
+const MAX_INPUT_HISTORY: usize = 100;
+const SIDEBAR_WIDTH: u16 = 28;
+
+/// The line being typed, with a cursor counted in characters.
+#[derive(Debug, Default)]
+struct InputLine {
+    text: String,
+    cursor: usize,
+}
+
+impl InputLine {
+    fn byte_index(&self) -> usize {
+        self.text
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.text.len(), |(i, _)| i)
+    }
+
+    fn insert(&mut self, c: char) {
+        let at = self.byte_index();
+        self.text.insert(at, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let at = self.byte_index();
+            self.text.remove(at);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            let at = self.byte_index();
+            self.text.remove(at);
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    fn set(&mut self, text: String) {
+        self.text = text;
+        self.end();
+    }
+
+    fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+}
+
+/// Previously submitted lines, browsed with Up and Down.
+#[derive(Debug, Default)]
+struct InputHistory {
+    entries: VecDeque<String>,
+    /// Position while browsing; `None` when editing a fresh line.
+    browsing: Option<usize>,
+    /// The unsubmitted line put aside when browsing started.
+    draft: String,
+}
+
+impl InputHistory {
+    fn push(&mut self, line: String) {
+        self.browsing = None;
+        if self.entries.back() == Some(&line) {
+            return;
+        }
+        if self.entries.len() == MAX_INPUT_HISTORY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line);
+    }
+
+    fn previous(&mut self, current: &str) -> Option<String> {
+        let index = match self.browsing {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(i) => i - 1,
+        };
+        self.browsing = Some(index);
+        Some(self.entries[index].clone())
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let index = self.browsing?;
+        if index + 1 < self.entries.len() {
+            self.browsing = Some(index + 1);
+            Some(self.entries[index + 1].clone())
+        } else {
+            self.browsing = None;
+            Some(std::mem::take(&mut self.draft))
+        }
+    }
+}
+
+struct App {
+    messages: VecDeque<String>,
+    /// How many lines the message pane is scrolled up from the bottom.
+    scroll_back: u16,
+    input: InputLine,
+    history: InputHistory,
+    peers: Vec<PeerInfo>,
+    channel: String,
+}
+
+impl App {
+    fn push(&mut self, line: String) {
+        if self.messages.len() == MAX_SCROLLBACK {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(line);
+    }
+
+    async fn refresh(&mut self, peer: &Peer) {
+        let mut peers: Vec<PeerInfo> = peer.peers.lock().await.values().cloned().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        self.peers = peers;
+        self.channel = peer.channels.lock().await.active().to_string();
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [messages_area, sidebar] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+
+        let lines: Vec<Line> = self
+            .messages
+            .iter()
+            .map(|m| Line::raw(m.as_str()))
+            .collect();
+        let messages = Paragraph::new(lines).wrap(Wrap { trim: false });
+        let inner_height = messages_area.height.saturating_sub(2);
+        let total = messages.line_count(messages_area.width.saturating_sub(2)) as u16;
+        let bottom = total.saturating_sub(inner_height);
+        let offset = bottom.saturating_sub(self.scroll_back);
+        let title = if offset < bottom {
+            format!(" #{} (scrolled, End to return) ", self.channel)
+        } else {
+            format!(" #{} ", self.channel)
+        };
+        frame.render_widget(
+            messages
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .scroll((offset, 0)),
+            messages_area,
+        );
+
+        let online = self.peers.iter().filter(|p| !p.stale).count();
+        let items: Vec<ListItem> = self
+            .peers
+            .iter()
+            .map(|p| {
+                let (dot, color) = if p.stale {
+                    ("◌", Color::Yellow)
+                } else {
+                    ("●", Color::Green)
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled(dot, Style::default().fg(color)),
+                    Span::raw(format!(" {}", p.name)),
+                ]))
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items).block(Block::default().borders(Borders::ALL).title(format!(
+                " Peers {}/{} ",
+                online,
+                self.peers.len()
+            ))),
+            sidebar,
+        );
+
+        frame.render_widget(
+            Paragraph::new(self.input.text.as_str()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" Message (Enter to send, /help for commands) ".dim()),
+            ),
+            input_area,
+        );
+        let before_cursor: String = self.input.text.chars().take(self.input.cursor).collect();
+        let cursor_x = input_area.x + 1 + Line::raw(before_cursor).width() as u16;
+        frame.set_cursor_position(Position::new(
+            cursor_x.min(input_area.right().saturating_sub(2)),
+            input_area.y + 1,
+        ));
+    }
+}
+
+/// What to do after a key press.
+enum KeyAction {
+    None,
+    Submit(String),
+    Quit,
+}
+
+fn handle_key(app: &mut App, key: KeyEvent) -> KeyAction {
+    if key.kind == KeyEventKind::Release {
+        return KeyAction::None;
+    }
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return KeyAction::Quit,
+        KeyCode::Char('u') if ctrl => {
+            app.input.take();
+        }
+        KeyCode::Char('a') if ctrl => app.input.home(),
+        KeyCode::Char('e') if ctrl => app.input.end(),
+        KeyCode::Char(c) => app.input.insert(c),
+        KeyCode::Backspace => app.input.backspace(),
+        KeyCode::Delete => app.input.delete(),
+        KeyCode::Left => app.input.left(),
+        KeyCode::Right => app.input.right(),
+        KeyCode::Home => app.input.home(),
+        KeyCode::End if app.input.text.is_empty() => app.scroll_back = 0,
+        KeyCode::End => app.input.end(),
+        KeyCode::Up => {
+            if let Some(line) = app.history.previous(&app.input.text) {
+                app.input.set(line);
+            }
+        }
+        KeyCode::Down => {
+            if let Some(line) = app.history.next() {
+                app.input.set(line);
+            }
+        }
+        KeyCode::PageUp => app.scroll_back = app.scroll_back.saturating_add(10),
+        KeyCode::PageDown => app.scroll_back = app.scroll_back.saturating_sub(10),
+        KeyCode::Enter => {
+            let line = app.input.take();
+            if !line.trim().is_empty() {
+                app.history.push(line.clone());
+                app.scroll_back = 0;
+                return KeyAction::Submit(line);
+            }
+        }
+        _ => {}
+    }
+    KeyAction::None
+}
+
+type Term = Terminal<CrosstermBackend<Stdout>>;
+
+fn enter_terminal() -> Result<Term, ChatError> {
+    enable_raw_mode()?;
+    execute!(std::io::stdout(), EnterAlternateScreen)?;
+    Ok(Terminal::new(CrosstermBackend::new(std::io::stdout()))?)
+}
+
+fn leave_terminal(terminal: &mut Term) {
+    let _ = disable_raw_mode();
+    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = terminal.show_cursor();
+}
+
+pub async fn start_tui(peer: &Peer) -> Result<(), ChatError> {
+    let mut terminal = enter_terminal()?;
+    let result = run(peer, &mut terminal).await;
+    leave_terminal(&mut terminal);
+    result
+}
+
+/// Drive the UI until the user quits or the terminal closes.
+async fn run(peer: &Peer, terminal: &mut Term) -> Result<(), ChatError> {
+    let mut app = App {
+        messages: VecDeque::new(),
+        scroll_back: 0,
+        input: InputLine::default(),
+        history: InputHistory::default(),
+        peers: Vec::new(),
+        channel: String::new(),
+    };
+    for line in help_lines() {
+        app.push(line);
+    }
+    for line in history_lines(peer, STARTUP_HISTORY).await {
+        app.push(line);
+    }
+    let mut receiver = peer.events.subscribe();
+    let mut events = EventStream::new();
+    // Peers go stale and come back without any message, so redraw the sidebar regularly
+    let mut tick = interval(Duration::from_secs(1));
+
+    loop {
+        app.refresh(peer).await;
+        terminal.draw(|frame| app.draw(frame))?;
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                let Event::Key(key) = event? else {
+                    continue;
+                };
+                match handle_key(&mut app, key) {
+                    KeyAction::None => {}
+                    KeyAction::Quit => return Ok(()),
+                    KeyAction::Submit(line) => {
+                        // The user is at the keyboard, so everything displayed so far has been seen
+                        send_read_receipts(peer).await;
+                        match run_command(peer, &line).await {
+                            CommandOutcome::Output(lines) => {
+                                app.push(format!("💬 {}", line));
+                                for line in lines {
+                                    app.push(line);
+                                }
+                            }
+                            CommandOutcome::Quit => return Ok(()),
+                        }
+                    }
+                }
+            }
+            event = receiver.recv() => match event {
+                Ok(ChatEvent::Warning(warning)) => eprintln!("{}", warning),
+                Ok(event) if !event.is_shown() => {}
+                Ok(event) => app.push(format!("📨 {}", event)),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    app.push(format!("⚠️  {} message(s) were dropped from the display", n));
+                }
+            },
+            _ = tick.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_line_editing() {
+        let mut input = InputLine::default();
+        for c in "héllo".chars() {
+            input.insert(c);
+        }
+        input.left();
+        input.left();
+        input.backspace();
+        assert_eq!(input.text, "hélo");
+        input.home();
+        input.delete();
+        input.insert('H');
+        assert_eq!(input.text, "Hélo");
+        assert_eq!(input.take(), "Hélo");
+        assert_eq!(input.cursor, 0);
+    }
+
+    #[test]
+    fn test_input_history_browsing() {
+        let mut history = InputHistory::default();
+        history.push("one".to_string());
+        history.push("two".to_string());
+        assert_eq!(history.previous("draft").as_deref(), Some("two"));
+        assert_eq!(history.previous("two").as_deref(), Some("one"));
+        assert_eq!(history.previous("one"), None);
+        assert_eq!(history.next().as_deref(), Some("two"));
+        assert_eq!(history.next().as_deref(), Some("draft"));
+        assert_eq!(history.next(), None);
+    }
+}
@@ -8,6 +8,16 @@ pub enum ChatError {
     Network(String),
     #[error("Serialization error: {0}")]
     Serialization(String),
+    #[error("No peer matches \"{0}\"")]
+    PeerNotFound(String),
+    #[error("Invalid channel name \"{0}\"")]
+    InvalidChannel(String),
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+    #[error("File transfer error: {0}")]
+    Transfer(String),
+    #[error("Config error: {0}")]
+    Config(String),
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
@@ -23,3 +33,9 @@ impl From<serde_json::Error> for ChatError {
         ChatError::Serialization(e.to_string())
     }
 }
+
+impl From<snow::Error> for ChatError {
+    fn from(e: snow::Error) -> Self {
+        ChatError::Crypto(e.to_string())
+    }
+}
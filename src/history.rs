@@ -0,0 +1,348 @@
+//! History module: Append-only local store of every chat message sent or received.
+//!
+//! Messages are written to a JSON Lines file, one `HistoryEntry` per line, so the log survives
+//! restarts and can be inspected with ordinary tools. The whole log is loaded into memory on
+//! startup; a malformed line (e.g. from a crash mid-write) is skipped rather than failing the load.
+//! In memory, entries are kept ordered by message timestamp and indexed by message ID, so
+//! messages pulled in later by history sync slot into place and are never stored twice. History
+//! sync compares holdings by sender sequence numbers, see `held_per_channel` and `missing_for`.
+
+use crate::error::ChatError;
+use crate::peer::{Message, SeqRanges};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub message: Message,
+    /// `true` for messages we sent, `false` for messages we received.
+    pub outgoing: bool,
+    /// `true` for direct (private) messages.
+    #[serde(default)]
+    pub direct: bool,
+}
+
+pub struct HistoryStore {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    ids: HashSet<String>,
+}
+
+impl HistoryStore {
+    /// A store that lives only for this session.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: Vec::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Open the log at `path`, loading existing entries. A missing file is created on first write.
+    pub fn open(path: PathBuf) -> Result<Self, ChatError> {
+        let mut store = Self {
+            path: Some(path),
+            ..Self::in_memory()
+        };
+        if let Some(path) = &store.path {
+            if path.exists() {
+                for line in fs::read_to_string(path)?.lines() {
+                    if let Ok(entry) = serde_json::from_str(line) {
+                        store.insert(entry);
+                    }
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    /// Record `entry`, unless a message with the same ID is already stored. Returns whether it
+    /// was added.
+    pub fn append(&mut self, entry: HistoryEntry) -> Result<bool, ChatError> {
+        if self.contains(&entry.message.id) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        self.insert(entry);
+        Ok(true)
+    }
+
+    fn insert(&mut self, entry: HistoryEntry) {
+        // Entries logged before messages carried IDs can't be de-duplicated
+        if !entry.message.id.is_empty() && !self.ids.insert(entry.message.id.clone()) {
+            return;
+        }
+        let timestamp = entry.message.timestamp;
+        let at = self
+            .entries
+            .partition_point(|e| e.message.timestamp <= timestamp);
+        self.entries.insert(at, entry);
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.ids.contains(message_id)
+    }
+
+    /// The highest sequence number we have used in each channel, so numbering can continue
+    /// after a restart.
+    pub fn own_sequences(&self) -> HashMap<String, u64> {
+        let mut sequences = HashMap::new();
+        for entry in self.entries.iter().filter(|e| e.outgoing && !e.direct) {
+            let seq = sequences.entry(entry.message.channel.clone()).or_insert(0);
+            *seq = (*seq).max(entry.message.seq);
+        }
+        sequences
+    }
+
+    /// The sequence numbers we have from each sender in each of `channels`, for a sync summary.
+    /// Messages without a sequence number (logged before they were numbered) are left out.
+    pub fn held_per_channel(&self, channels: &BTreeSet<String>) -> BTreeMap<String, SeqRanges> {
+        let mut held: BTreeMap<String, BTreeMap<String, BTreeSet<u64>>> = channels
+            .iter()
+            .map(|channel| (channel.clone(), BTreeMap::new()))
+            .collect();
+        for entry in self
+            .entries
+            .iter()
+            .filter(|e| !e.direct && e.message.seq > 0)
+        {
+            if let Some(senders) = held.get_mut(&entry.message.channel) {
+                senders
+                    .entry(entry.message.from_id.clone())
+                    .or_default()
+                    .insert(entry.message.seq);
+            }
+        }
+        held.into_iter()
+            .map(|(channel, senders)| {
+                let ranges = senders
+                    .into_iter()
+                    .map(|(sender, seqs)| (sender, to_ranges(seqs)))
+                    .collect();
+                (channel, ranges)
+            })
+            .collect()
+    }
+
+    /// Channel messages a peer with the given `held` summary doesn't have, in the channels it
+    /// lists, capped at the `limit` most recent.
+    pub fn missing_for(&self, held: &BTreeMap<String, SeqRanges>, limit: usize) -> Vec<Message> {
+        let missing: Vec<&HistoryEntry> = self
+            .entries
+            .iter()
+            .filter(|e| !e.direct && e.message.seq > 0)
+            .filter(|e| {
+                held.get(&e.message.channel).is_some_and(|senders| {
+                    !senders.get(&e.message.from_id).is_some_and(|ranges| {
+                        ranges
+                            .iter()
+                            .any(|(first, last)| (*first..=*last).contains(&e.message.seq))
+                    })
+                })
+            })
+            .collect();
+        missing[missing.len().saturating_sub(limit)..]
+            .iter()
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    /// The last `n` entries, oldest first.
+    pub fn recent(&self, n: usize) -> &[HistoryEntry] {
+        &self.entries[self.entries.len().saturating_sub(n)..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Flush the log file to disk, e.g. before exiting.
+    pub fn sync(&self) -> Result<(), ChatError> {
+        match &self.path {
+            Some(path) if path.exists() => Ok(fs::File::open(path)?.sync_all()?),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Collapse sorted sequence numbers into inclusive ranges, e.g. 1, 2, 3, 5 into (1, 3), (5, 5).
+fn to_ranges(seqs: BTreeSet<u64>) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for seq in seqs {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == seq => *last = seq,
+            _ => ranges.push((seq, seq)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(content: &str, outgoing: bool) -> HistoryEntry {
+        entry_at(content, outgoing, 1234567890, "general")
+    }
+
+    fn entry_seq(
+        content: &str,
+        from_id: &str,
+        seq: u64,
+        timestamp: u64,
+        channel: &str,
+    ) -> HistoryEntry {
+        let mut entry = entry_at(content, false, timestamp, channel);
+        entry.message.from_id = from_id.to_string();
+        entry.message.seq = seq;
+        entry
+    }
+
+    fn entry_at(content: &str, outgoing: bool, timestamp: u64, channel: &str) -> HistoryEntry {
+        HistoryEntry {
+            message: Message {
+                id: format!("{}:{}", channel, content),
+                from_id: "id1".to_string(),
+                from_name: "Alice".to_string(),
+                content: content.to_string(),
+                timestamp,
+                channel: channel.to_string(),
+                seq: 0,
+                to_id: String::new(),
+                signature: String::new(),
+            },
+            outgoing,
+            direct: false,
+        }
+    }
+
+    #[test]
+    fn test_recent_returns_tail_in_order() {
+        let mut store = HistoryStore::in_memory();
+        for i in 0..5 {
+            store.append(entry(&i.to_string(), i % 2 == 0)).unwrap();
+        }
+        let recent: Vec<_> = store
+            .recent(2)
+            .iter()
+            .map(|e| e.message.content.as_str())
+            .collect();
+        assert_eq!(recent, ["3", "4"]);
+        assert_eq!(store.recent(100).len(), 5);
+    }
+
+    #[test]
+    fn test_log_survives_reopen_and_skips_bad_lines() {
+        let path = std::env::temp_dir().join(format!("p2p_chat_history_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = HistoryStore::open(path.clone()).unwrap();
+        store.append(entry("hello", true)).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{truncated\n")
+            .unwrap();
+        store.append(entry("world", false)).unwrap();
+
+        let reopened = HistoryStore::open(path.clone()).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.recent(1)[0].message.content, "world");
+        assert!(!reopened.recent(1)[0].outgoing);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_duplicates_ignored_and_order_by_timestamp() {
+        let mut store = HistoryStore::in_memory();
+        assert!(store
+            .append(entry_at("late", false, 20, "general"))
+            .unwrap());
+        assert!(store
+            .append(entry_at("early", false, 10, "general"))
+            .unwrap());
+        assert!(!store
+            .append(entry_at("late", false, 20, "general"))
+            .unwrap());
+        let contents: Vec<_> = store
+            .recent(10)
+            .iter()
+            .map(|e| e.message.content.as_str())
+            .collect();
+        assert_eq!(contents, ["early", "late"]);
+    }
+
+    #[test]
+    fn test_sync_summary_and_missing_messages() {
+        let mut ours = HistoryStore::in_memory();
+        ours.append(entry_seq("a", "alice", 1, 10, "general"))
+            .unwrap();
+        ours.append(entry_seq("b", "alice", 2, 11, "general"))
+            .unwrap();
+        ours.append(entry_seq("d", "alice", 4, 13, "general"))
+            .unwrap();
+        let channels = BTreeSet::from(["general".to_string(), "rust".to_string()]);
+        let held = ours.held_per_channel(&channels);
+        assert_eq!(held["general"]["alice"], [(1, 2), (4, 4)]);
+        assert!(held["rust"].is_empty());
+
+        let mut theirs = HistoryStore::in_memory();
+        for (content, seq) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+            theirs
+                .append(entry_seq(content, "alice", seq, 9 + seq, "general"))
+                .unwrap();
+        }
+        theirs.append(entry_seq("e", "bob", 1, 5, "rust")).unwrap();
+        theirs
+            .append(entry_seq("f", "bob", 1, 50, "random"))
+            .unwrap();
+        let missing: Vec<_> = theirs
+            .missing_for(&held, 100)
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(missing, ["e", "c"]);
+        assert_eq!(theirs.missing_for(&held, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_sync_finds_older_message_behind_newer_one() {
+        // We have Alice's newest message but missed an older one, and Bob's from before it
+        let mut ours = HistoryStore::in_memory();
+        ours.append(entry_seq("new", "alice", 2, 100, "general"))
+            .unwrap();
+        let mut theirs = HistoryStore::in_memory();
+        theirs
+            .append(entry_seq("old", "alice", 1, 10, "general"))
+            .unwrap();
+        theirs
+            .append(entry_seq("bob", "bob", 1, 50, "general"))
+            .unwrap();
+        theirs
+            .append(entry_seq("new", "alice", 2, 100, "general"))
+            .unwrap();
+        let held = ours.held_per_channel(&BTreeSet::from(["general".to_string()]));
+        let missing: Vec<_> = theirs
+            .missing_for(&held, 100)
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(missing, ["old", "bob"]);
+    }
+}
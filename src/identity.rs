@@ -0,0 +1,198 @@
+//! Identity module: Long-term Ed25519 identity keys and message signing for the P2P Chat.
+//!
+//! Each installation keeps one Ed25519 signing key on disk, so a peer's identity survives
+//! restarts. The `peer_id` is the hex-encoded public key, which makes it self-certifying:
+//! anyone holding a `Message` can check its signature against the key embedded in `from_id`
+//! without any prior exchange.
+
+use crate::error::ChatError;
+use crate::peer::Message;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const IDENTITY_FILE: &str = "identity.key";
+/// Prefixed to a Noise static key before signing it, so the signature can't pass for anything else.
+const NOISE_KEY_CONTEXT: &[u8] = b"p2p_chat noise static key:";
+
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// Create a throwaway identity that is never written to disk.
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Load the identity stored at `path`, creating and saving a new one if the file is missing.
+    pub fn load_or_create(path: &Path) -> Result<Self, ChatError> {
+        if path.exists() {
+            let bytes = fs::read(path)?;
+            let secret: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+                ChatError::Crypto(format!("identity file {} is corrupt", path.display()))
+            })?;
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+        let identity = Self::generate();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(path, identity.signing_key.as_bytes())?;
+        Ok(identity)
+    }
+
+    /// Our peer ID: the hex-encoded Ed25519 public key.
+    pub fn peer_id(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Our static X25519 keypair for the Noise handshake, as `(private, public)`: the identity key
+    /// converted to its X25519 form, so it stays the same across restarts.
+    pub fn noise_keypair(&self) -> ([u8; 32], [u8; 32]) {
+        (
+            self.signing_key.to_scalar_bytes(),
+            self.signing_key.verifying_key().to_montgomery().to_bytes(),
+        )
+    }
+
+    /// Sign a Noise static public key, vouching that it speaks for our peer ID.
+    pub fn sign_noise_key(&self, key: &[u8]) -> String {
+        let signature = self.signing_key.sign(&[NOISE_KEY_CONTEXT, key].concat());
+        hex::encode(signature.to_bytes())
+    }
+
+    /// Sign `message` in place, filling in its `signature` field.
+    pub fn sign(&self, message: &mut Message) {
+        let signature = self.signing_key.sign(&message.signing_bytes());
+        message.signature = hex::encode(signature.to_bytes());
+    }
+}
+
+/// Default location of the identity key, under the user's data directory.
+pub fn default_identity_path() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("p2p_chat")
+        .join(IDENTITY_FILE)
+}
+
+/// Parse a peer ID back into the public key it encodes.
+pub fn verifying_key(peer_id: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(peer_id).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Check that `message` was signed by the key its `from_id` claims.
+pub fn verify_message(message: &Message) -> bool {
+    let Some(key) = verifying_key(&message.from_id) else {
+        return false;
+    };
+    let Some(signature) = hex::decode(&message.signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    key.verify(&message.signing_bytes(), &signature).is_ok()
+}
+
+/// Check that `signature` is `peer_id`'s signature over the Noise static key `key`.
+pub fn verify_noise_key(peer_id: &str, key: &[u8], signature: &str) -> bool {
+    let Some(verifying_key) = verifying_key(peer_id) else {
+        return false;
+    };
+    let Some(signature) = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    verifying_key
+        .verify(&[NOISE_KEY_CONTEXT, key].concat(), &signature)
+        .is_ok()
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), ChatError> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), ChatError> {
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(identity: &Identity, content: &str) -> Message {
+        let mut message = Message {
+            id: Message::new_id(),
+            from_id: identity.peer_id(),
+            from_name: "Alice".to_string(),
+            content: content.to_string(),
+            timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 0,
+            to_id: String::new(),
+            signature: String::new(),
+        };
+        identity.sign(&mut message);
+        message
+    }
+
+    #[test]
+    fn test_signed_message_verifies() {
+        let identity = Identity::generate();
+        assert!(verify_message(&message(&identity, "hello")));
+    }
+
+    #[test]
+    fn test_tampered_or_spoofed_message_rejected() {
+        let alice = Identity::generate();
+        let mut tampered = message(&alice, "hello");
+        tampered.content = "goodbye".to_string();
+        assert!(!verify_message(&tampered));
+
+        let mallory = Identity::generate();
+        let mut spoofed = message(&mallory, "hello");
+        spoofed.from_id = alice.peer_id();
+        assert!(!verify_message(&spoofed));
+
+        let mut redirected = message(&alice, "hello");
+        redirected.channel.clear();
+        redirected.to_id = mallory.peer_id();
+        assert!(!verify_message(&redirected));
+
+        let mut unsigned = message(&alice, "hello");
+        unsigned.signature.clear();
+        assert!(!verify_message(&unsigned));
+    }
+
+    #[test]
+    fn test_identity_persists_across_loads() {
+        let dir = std::env::temp_dir().join(format!("p2p_chat_identity_{}", std::process::id()));
+        let path = dir.join(IDENTITY_FILE);
+        let _ = fs::remove_dir_all(&dir);
+        let first = Identity::load_or_create(&path).unwrap();
+        let second = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.peer_id(), second.peer_id());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
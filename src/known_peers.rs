@@ -0,0 +1,197 @@
+//! Known peers module: Trust-on-first-use store of peer keys, in the spirit of SSH `known_hosts`.
+//!
+//! The first time a peer completes a handshake with us, the static key it presented is recorded
+//! under its peer ID. Every later handshake is checked against that record, and a known ID showing
+//! up with a different key is reported as a mismatch so the user can check out-of-band, e.g. by
+//! comparing the safety number printed by `/verify`. Since the handshake only succeeds if the key
+//! is signed by the ID's identity key, a changed key means whoever holds that identity key is
+//! using it differently now; the recorded key is kept and the peer stays untrusted until restart.
+
+use crate::error::ChatError;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Result of checking a peer against the store.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrustStatus {
+    /// Never seen before; now recorded.
+    New,
+    /// Already recorded with this key.
+    Known,
+    /// The peer ID is recorded with a different key (hex-encoded).
+    Mismatch { expected_key: String },
+}
+
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    /// peer ID -> hex-encoded key first seen for that ID
+    entries: BTreeMap<String, String>,
+    /// Peers that presented a key other than the recorded one this session.
+    mismatched: BTreeSet<String>,
+}
+
+impl KnownPeers {
+    /// A store that lives only for this session.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: BTreeMap::new(),
+            mismatched: BTreeSet::new(),
+        }
+    }
+
+    /// Load the store from `path`. A missing file is treated as empty and created on first write.
+    pub fn load(path: PathBuf) -> Result<Self, ChatError> {
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            for line in fs::read_to_string(&path)?.lines() {
+                // Older stores recorded a name instead of a key; those peers are learned again
+                if let Some((id, key)) = line.trim().split_once(' ') {
+                    if hex::decode(key).is_ok_and(|bytes| bytes.len() == 32) {
+                        entries.insert(id.to_string(), key.to_string());
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            path: Some(path),
+            entries,
+            mismatched: BTreeSet::new(),
+        })
+    }
+
+    /// Check the `key` that `peer_id` presented in a handshake, recording it on first use.
+    pub fn check(&mut self, peer_id: &str, key: &[u8]) -> Result<TrustStatus, ChatError> {
+        let key = hex::encode(key);
+        match self.entries.get(peer_id) {
+            Some(expected) if *expected == key => return Ok(TrustStatus::Known),
+            Some(expected) => {
+                self.mismatched.insert(peer_id.to_string());
+                return Ok(TrustStatus::Mismatch {
+                    expected_key: expected.clone(),
+                });
+            }
+            None => {}
+        }
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{} {}", peer_id, key)?;
+        }
+        self.entries.insert(peer_id.to_string(), key);
+        Ok(TrustStatus::New)
+    }
+
+    pub fn is_known(&self, peer_id: &str) -> bool {
+        self.entries.contains_key(peer_id)
+    }
+
+    /// Whether `peer_id` has not presented a mismatched key this session.
+    pub fn is_trusted(&self, peer_id: &str) -> bool {
+        !self.mismatched.contains(peer_id)
+    }
+
+    /// Flush the store's file to disk, e.g. before exiting.
+    pub fn sync(&self) -> Result<(), ChatError> {
+        match &self.path {
+            Some(path) if path.exists() => Ok(fs::File::open(path)?.sync_all()?),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Short, human-readable form of a peer ID: the first 16 hex digits in groups of four.
+pub fn fingerprint(peer_id: &str) -> String {
+    peer_id
+        .chars()
+        .take(16)
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|c| c.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Safety number for a pair of peers. Both sides compute the same digits regardless of order,
+/// so reading them to each other over another channel confirms neither key was substituted.
+pub fn safety_number(our_id: &str, their_id: &str) -> String {
+    let (first, second) = if our_id <= their_id {
+        (our_id, their_id)
+    } else {
+        (their_id, our_id)
+    };
+    let digest = Sha256::new()
+        .chain_update(first.as_bytes())
+        .chain_update(second.as_bytes())
+        .finalize();
+    digest[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", n % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_use_then_mismatch() {
+        let mut store = KnownPeers::in_memory();
+        assert_eq!(store.check("aaaa", &[1; 32]).unwrap(), TrustStatus::New);
+        assert_eq!(store.check("aaaa", &[1; 32]).unwrap(), TrustStatus::Known);
+        assert!(store.is_trusted("aaaa"));
+        // A known ID with another key is reported, and the first key stays on record
+        assert_eq!(
+            store.check("aaaa", &[2; 32]).unwrap(),
+            TrustStatus::Mismatch {
+                expected_key: hex::encode([1; 32])
+            }
+        );
+        assert!(!store.is_trusted("aaaa"));
+        assert_eq!(store.entries["aaaa"], hex::encode([1; 32]));
+        // Another ID, even under a known name, is just a new peer
+        assert_eq!(store.check("bbbb", &[2; 32]).unwrap(), TrustStatus::New);
+    }
+
+    #[test]
+    fn test_store_persists() {
+        let path = std::env::temp_dir().join(format!("p2p_chat_known_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = KnownPeers::load(path.clone()).unwrap();
+        store.check("aaaa", &[1; 32]).unwrap();
+        let mut reloaded = KnownPeers::load(path.clone()).unwrap();
+        assert!(reloaded.is_known("aaaa"));
+        assert!(matches!(
+            reloaded.check("aaaa", &[2; 32]).unwrap(),
+            TrustStatus::Mismatch { .. }
+        ));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_safety_number_is_symmetric() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let number = safety_number(&a, &b);
+        assert_eq!(number, safety_number(&b, &a));
+        assert_eq!(number.split(' ').count(), 6);
+        assert_ne!(number, safety_number(&a, &"c".repeat(64)));
+    }
+
+    #[test]
+    fn test_fingerprint_format() {
+        assert_eq!(fingerprint("0123456789abcdef0011"), "0123:4567:89ab:cdef");
+    }
+}
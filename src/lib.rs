@@ -1,8 +1,14 @@
-//! Library entry point for p2p_Chat. Exports core modules for use in integration tests and other binaries.
+//! Library entry point for p2p_Chat. Exports core modules for use in integration tests and other binaries;
+//! `node::ChatNode` is the starting point for embedding the chat.
 
 pub mod chat;
 pub mod cli;
+pub mod config;
 pub mod error;
+pub mod history;
+pub mod identity;
+pub mod known_peers;
 pub mod network;
+pub mod node;
+pub mod outbox;
 pub mod peer;
-pub mod signal;
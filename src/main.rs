@@ -1,20 +1,25 @@
 //! Main entry point: Sets up CLI, initializes the Chat, and starts the async runtime.
 //!
 //! This module is responsible for parsing command line arguments using Clap,
-//! and starting the Chat service which facilitates peer-to-peer
-//! communication over a network.
-
-use std::sync::Arc;
-mod chat;
-mod cli;
-mod error;
-mod network;
-mod peer;
+//! starting a `ChatNode` which facilitates peer-to-peer communication over a network,
+//! and running the terminal front-end on top of it. Everything that reads the keyboard or
+//! prints to the terminal lives in this binary; the library only publishes events.
+
+mod display {
+    pub mod cli;
+    pub mod message_display;
+    #[cfg(feature = "tui")]
+    pub mod tui;
+}
 mod signal;
 
-use chat::Peer;
 use clap::Parser;
-use cli::*;
+use colored::*;
+use p2p_chat::chat::Peer;
+use p2p_chat::cli::*;
+use p2p_chat::config::{default_config_path, ColorMode};
+use p2p_chat::error::ChatError;
+use p2p_chat::node::ChatNode;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -22,16 +27,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Only handle CLI commands
     match cli.command {
-        Commands::Start { port, name } => {
-            let chat = Peer::new(name, port);
-            let chat_arc = Arc::new(chat);
-            let chat_signal = chat_arc.clone();
-            tokio::spawn(async move {
-                crate::signal::handle_signals(chat_signal).await;
-            });
-            chat_arc.start().await?;
+        Commands::Start { settings, tui } => {
+            if tui && !cfg!(feature = "tui") {
+                return Err("this build has no terminal UI; rebuild with `--features tui`".into());
+            }
+            let config = settings.resolve()?;
+            set_color(config.color);
+            let node = ChatNode::builder()
+                .storage(config.identity.clone())
+                .config(config)
+                .build()?;
+            let peer = node.peer().clone();
+            println!("{}", "🎙️  Starting P2P Chat...".bright_cyan().bold());
+            println!("👤 Your ID: {}", peer.peer_id.bright_yellow());
+            println!("📡 Your Name: {}", peer.name().bright_green());
+            println!(
+                "🔌 Listening on port: {}",
+                peer.port.to_string().bright_blue()
+            );
+            let mut handle = node.start().await?;
+            tokio::spawn(signal::handle_signals(peer.clone(), move || {
+                settings.resolve()
+            }));
+
+            // SIGINT or SIGTERM cancels the shutdown token, which stops the services and ends `closed`
+            let result = tokio::select! {
+                result = run_user_interface(&peer, tui) => result,
+                result = handle.closed() => result,
+            };
+            for result in handle.shutdown().await? {
+                if result.is_delivered() {
+                    println!(
+                        "Quit broadcasted to {} ({})",
+                        result.peer.name, result.peer.id
+                    );
+                }
+            }
+            println!("\u{1F44B} Now Goodbye!");
+            result?;
+        }
+        Commands::Config {
+            command: ConfigCommand::Show { settings },
+        } => {
+            let config = settings.resolve()?;
+            match settings.config_path() {
+                Some(path) => println!("# config file: {}", path.display()),
+                None => println!(
+                    "# no config file (defaults; would be read from {})",
+                    default_config_path().display()
+                ),
+            }
+            print!("{}", config.to_toml()?);
         }
     }
 
     Ok(())
 }
+
+/// Apply the configured color mode to everything printed with `colored`.
+fn set_color(mode: ColorMode) {
+    match mode {
+        ColorMode::Auto => colored::control::unset_override(),
+        ColorMode::Always => colored::control::set_override(true),
+        ColorMode::Never => colored::control::set_override(false),
+    }
+}
+
+/// Run the front-end until the user quits: the full-screen UI if requested, otherwise the CLI
+/// and message display.
+async fn run_user_interface(peer: &Peer, tui: bool) -> Result<(), ChatError> {
+    #[cfg(feature = "tui")]
+    if tui {
+        return display::tui::start_tui(peer).await;
+    }
+    let _ = tui;
+    tokio::select! {
+        result = display::cli::start_cli_handler(peer) => result,
+        result = display::message_display::start_message_display(peer) => result,
+    }
+}
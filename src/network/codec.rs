@@ -0,0 +1,125 @@
+//! Wire codec module: Length-prefixed framing for traffic over TCP.
+//!
+//! Every message on a TCP stream is sent as a 4-byte big-endian length header followed by
+//! exactly that many bytes of payload (Noise handshake messages or encrypted chunks). This lets
+//! readers decode messages of arbitrary size, reassemble messages split across segments, and
+//! read several messages from one connection.
+
+use crate::error::ChatError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound on a single frame's payload, to stop a bad header from allocating unbounded memory.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Write an already-serialized payload as a single frame.
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> Result<(), ChatError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(ChatError::Serialization(format!(
+            "frame of {} bytes exceeds maximum of {}",
+            payload.len(),
+            MAX_FRAME_LEN
+        )));
+    }
+    writer
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one frame's payload. Returns `Ok(None)` when the peer closed the stream cleanly
+/// between frames.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>, ChatError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ChatError::Network(format!(
+            "frame of {} bytes exceeds maximum of {}",
+            len, MAX_FRAME_LEN
+        )));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{Message, NetworkMessage};
+
+    async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, msg: &NetworkMessage) {
+        write_frame(writer, &serde_json::to_vec(msg).unwrap())
+            .await
+            .unwrap();
+    }
+
+    async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Option<NetworkMessage> {
+        let frame = read_frame(reader).await.unwrap()?;
+        Some(serde_json::from_slice(&frame).unwrap())
+    }
+
+    fn chat(content: &str) -> NetworkMessage {
+        NetworkMessage::Chat(Message {
+            id: Message::new_id(),
+            from_id: "id1".to_string(),
+            from_name: "Alice".to_string(),
+            content: content.to_string(),
+            timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 0,
+            to_id: String::new(),
+            signature: String::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_large_message() {
+        let big = "x".repeat(64 * 1024);
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move {
+            write_message(&mut a, &chat(&big)).await;
+        });
+        match read_message(&mut b).await {
+            Some(NetworkMessage::Chat(m)) => assert_eq!(m.content.len(), 64 * 1024),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_messages_per_stream() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &chat("one")).await;
+        write_message(&mut buf, &NetworkMessage::Exit("id1".to_string())).await;
+        let mut reader = &buf[..];
+        assert!(matches!(
+            read_message(&mut reader).await,
+            Some(NetworkMessage::Chat(_))
+        ));
+        assert!(matches!(
+            read_message(&mut reader).await,
+            Some(NetworkMessage::Exit(_))
+        ));
+        assert!(read_message(&mut reader).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_header_rejected() {
+        let header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        let mut reader = &header[..];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+}
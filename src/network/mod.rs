@@ -1 +1,3 @@
+pub mod codec;
+pub mod secure;
 pub mod tcp;
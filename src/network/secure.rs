@@ -0,0 +1,393 @@
+//! Secure channel module: Noise handshake and AEAD encryption for peer-to-peer TCP streams.
+//!
+//! Every TCP connection starts with a `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake: both sides
+//! exchange ephemeral and static X25519 keys and derive a pair of ChaCha20-Poly1305 session keys.
+//! The static key alone says nothing about who the peer is, so each side also sends its peer ID
+//! and its identity key's signature over its static key inside the encrypted handshake messages.
+//! A handshake whose static key isn't signed by the identity it claims fails, and the connection
+//! is dropped; otherwise the `Session` carries the peer ID the remote proved to be. After that,
+//! every `NetworkMessage` travels as ciphertext inside the length-prefixed frames from
+//! `network::codec`.
+//!
+//! A Noise transport message is limited to 64 KiB, so each plaintext message is split into
+//! chunks. The first byte of every decrypted chunk says whether more chunks follow.
+
+use crate::error::ChatError;
+use crate::identity::{verify_noise_key, Identity};
+use crate::network::codec::{read_frame, write_frame, MAX_FRAME_LEN};
+use crate::peer::NetworkMessage;
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_MAX_MSG_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+/// Plaintext bytes per chunk, leaving room for the continuation flag and the AEAD tag.
+const CHUNK_LEN: usize = NOISE_MAX_MSG_LEN - TAG_LEN - 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CHUNK_MORE: u8 = 1;
+const CHUNK_LAST: u8 = 0;
+
+/// Our static Noise keypair, presented to peers during the handshake, and the proof that it
+/// belongs to our identity.
+pub struct NoiseKeys {
+    private: Vec<u8>,
+    public: Vec<u8>,
+    credential: Credential,
+}
+
+/// Sent inside the encrypted handshake messages: who we are, and that identity's signature over
+/// our static key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Credential {
+    peer_id: String,
+    signature: String,
+}
+
+impl NoiseKeys {
+    pub fn new(identity: &Identity) -> Self {
+        let (private, public) = identity.noise_keypair();
+        Self {
+            private: private.to_vec(),
+            public: public.to_vec(),
+            credential: Credential {
+                peer_id: identity.peer_id(),
+                signature: identity.sign_noise_key(&public),
+            },
+        }
+    }
+
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+/// A completed handshake.
+pub struct Session {
+    pub transport: Arc<StatelessTransportState>,
+    /// The peer ID whose identity key signed the remote's static key.
+    pub peer_id: String,
+}
+
+fn params() -> snow::params::NoiseParams {
+    NOISE_PARAMS.parse().expect("valid Noise parameters")
+}
+
+/// Run the initiator side of the handshake on a freshly connected stream.
+pub async fn handshake_initiator<S>(stream: &mut S, keys: &NoiseKeys) -> Result<Session, ChatError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let state = Builder::new(params())
+        .local_private_key(&keys.private)
+        .build_initiator()?;
+    timeout(HANDSHAKE_TIMEOUT, run_handshake(stream, state, keys, true))
+        .await
+        .map_err(|_| ChatError::Crypto("handshake timed out".to_string()))?
+}
+
+/// Run the responder side of the handshake on an accepted stream.
+pub async fn handshake_responder<S>(stream: &mut S, keys: &NoiseKeys) -> Result<Session, ChatError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let state = Builder::new(params())
+        .local_private_key(&keys.private)
+        .build_responder()?;
+    timeout(HANDSHAKE_TIMEOUT, run_handshake(stream, state, keys, false))
+        .await
+        .map_err(|_| ChatError::Crypto("handshake timed out".to_string()))?
+}
+
+async fn run_handshake<S>(
+    stream: &mut S,
+    mut state: HandshakeState,
+    keys: &NoiseKeys,
+    mut our_turn: bool,
+) -> Result<Session, ChatError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+    let credential = serde_json::to_vec(&keys.credential)?;
+    let mut remote: Option<Credential> = None;
+    let mut first = true;
+    while !state.is_handshake_finished() {
+        if our_turn {
+            // The first message goes out before anything is encrypted, so our credential waits
+            // for the next one
+            let payload: &[u8] = if first { &[] } else { &credential };
+            let len = state.write_message(payload, &mut buf)?;
+            write_frame(stream, &buf[..len]).await?;
+        } else {
+            let frame = read_frame(stream)
+                .await?
+                .ok_or_else(|| ChatError::Crypto("connection closed during handshake".into()))?;
+            let len = state.read_message(&frame, &mut buf)?;
+            if len > 0 {
+                remote = Some(serde_json::from_slice(&buf[..len])?);
+            }
+        }
+        first = false;
+        our_turn = !our_turn;
+    }
+    let remote =
+        remote.ok_or_else(|| ChatError::Crypto("peer didn't say who it is".to_string()))?;
+    let remote_static = state
+        .get_remote_static()
+        .ok_or_else(|| ChatError::Crypto("peer sent no static key".to_string()))?;
+    if !verify_noise_key(&remote.peer_id, remote_static, &remote.signature) {
+        return Err(ChatError::Crypto(format!(
+            "static key isn't signed by the identity it claims ({})",
+            remote.peer_id
+        )));
+    }
+    Ok(Session {
+        transport: Arc::new(state.into_stateless_transport_mode()?),
+        peer_id: remote.peer_id,
+    })
+}
+
+/// Encrypting half of a secure connection.
+pub struct SecureWriter<W> {
+    inner: W,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    pub fn new(inner: W, session: &Session) -> Self {
+        Self {
+            inner,
+            transport: session.transport.clone(),
+            nonce: 0,
+        }
+    }
+
+    pub async fn write_message(&mut self, msg: &NetworkMessage) -> Result<(), ChatError> {
+        self.write_payload(&serde_json::to_vec(msg)?).await
+    }
+
+    /// Encrypt and send raw bytes as one message, e.g. a chunk of a file transfer.
+    pub async fn write_payload(&mut self, payload: &[u8]) -> Result<(), ChatError> {
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(ChatError::Serialization(format!(
+                "message of {} bytes exceeds maximum of {}",
+                payload.len(),
+                MAX_FRAME_LEN
+            )));
+        }
+        let mut plain = Vec::with_capacity(CHUNK_LEN + 1);
+        let mut cipher = vec![0u8; NOISE_MAX_MSG_LEN];
+        let mut chunks = payload.chunks(CHUNK_LEN).peekable();
+        // An empty payload still needs one (final) chunk
+        if chunks.peek().is_none() {
+            return self.write_chunk(&[CHUNK_LAST], &mut cipher).await;
+        }
+        while let Some(chunk) = chunks.next() {
+            plain.clear();
+            plain.push(if chunks.peek().is_some() {
+                CHUNK_MORE
+            } else {
+                CHUNK_LAST
+            });
+            plain.extend_from_slice(chunk);
+            self.write_chunk(&plain, &mut cipher).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self, plain: &[u8], cipher: &mut [u8]) -> Result<(), ChatError> {
+        let len = self.transport.write_message(self.nonce, plain, cipher)?;
+        self.nonce += 1;
+        write_frame(&mut self.inner, &cipher[..len]).await
+    }
+
+    /// Close the underlying stream for writing.
+    pub async fn shutdown(&mut self) -> Result<(), ChatError> {
+        self.inner.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Decrypting half of a secure connection.
+pub struct SecureReader<R> {
+    inner: R,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    peer_id: String,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    pub fn new(inner: R, session: &Session) -> Self {
+        Self {
+            inner,
+            transport: session.transport.clone(),
+            nonce: 0,
+            peer_id: session.peer_id.clone(),
+        }
+    }
+
+    /// The peer ID the remote proved during the handshake.
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// Read and decrypt one whole message payload. Returns `Ok(None)` on a clean end of stream.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, ChatError> {
+        let mut payload = Vec::new();
+        let mut plain = vec![0u8; NOISE_MAX_MSG_LEN];
+        loop {
+            let Some(frame) = read_frame(&mut self.inner).await? else {
+                if payload.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ChatError::Network("stream closed mid-message".to_string()));
+            };
+            let len = self
+                .transport
+                .read_message(self.nonce, &frame, &mut plain)?;
+            self.nonce += 1;
+            let Some((&flag, chunk)) = plain[..len].split_first() else {
+                return Err(ChatError::Crypto("empty chunk".to_string()));
+            };
+            if payload.len() + chunk.len() > MAX_FRAME_LEN {
+                return Err(ChatError::Network(format!(
+                    "message exceeds maximum of {} bytes",
+                    MAX_FRAME_LEN
+                )));
+            }
+            payload.extend_from_slice(chunk);
+            if flag == CHUNK_LAST {
+                return Ok(Some(payload));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::Message;
+
+    async fn connected_pair() -> (
+        (
+            SecureReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>,
+            SecureWriter<tokio::io::WriteHalf<tokio::io::DuplexStream>>,
+        ),
+        (
+            SecureReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>,
+            SecureWriter<tokio::io::WriteHalf<tokio::io::DuplexStream>>,
+        ),
+    ) {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let keys_a = NoiseKeys::new(&alice);
+        let keys_b = NoiseKeys::new(&bob);
+        let (sa, sb) = tokio::join!(
+            handshake_initiator(&mut a, &keys_a),
+            handshake_responder(&mut b, &keys_b)
+        );
+        let (sa, sb) = (sa.unwrap(), sb.unwrap());
+        assert_eq!(sa.transport.get_remote_static(), Some(keys_b.public()));
+        assert_eq!(sb.transport.get_remote_static(), Some(keys_a.public()));
+        assert_eq!(sa.peer_id, bob.peer_id());
+        assert_eq!(sb.peer_id, alice.peer_id());
+        let (ra, wa) = tokio::io::split(a);
+        let (rb, wb) = tokio::io::split(b);
+        (
+            (SecureReader::new(ra, &sa), SecureWriter::new(wa, &sa)),
+            (SecureReader::new(rb, &sb), SecureWriter::new(wb, &sb)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_roundtrip_both_directions() {
+        let ((mut ra, mut wa), (mut rb, mut wb)) = connected_pair().await;
+        let big = "secret ".repeat(30_000);
+        let msg = NetworkMessage::Chat(Message {
+            id: Message::new_id(),
+            from_id: "id1".to_string(),
+            from_name: "Alice".to_string(),
+            content: big.clone(),
+            timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 0,
+            to_id: String::new(),
+            signature: String::new(),
+        });
+        let writer = tokio::spawn(async move {
+            wa.write_message(&msg).await.unwrap();
+            wa
+        });
+        let frame = rb.read_frame().await.unwrap().unwrap();
+        match serde_json::from_slice(&frame).unwrap() {
+            NetworkMessage::Chat(m) => assert_eq!(m.content, big),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        writer.await.unwrap();
+
+        wb.write_message(&NetworkMessage::Exit("id2".to_string()))
+            .await
+            .unwrap();
+        let frame = ra.read_frame().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&frame).unwrap(),
+            NetworkMessage::Exit(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ciphertext_hides_plaintext() {
+        let keys = NoiseKeys::new(&Identity::generate());
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let responder_keys = NoiseKeys::new(&Identity::generate());
+        let (sa, sb) = tokio::join!(
+            handshake_initiator(&mut a, &keys),
+            handshake_responder(&mut b, &responder_keys)
+        );
+        sb.unwrap();
+        let mut captured = Vec::new();
+        let mut writer = SecureWriter::new(&mut captured, &sa.unwrap());
+        writer
+            .write_message(&NetworkMessage::Exit("plaintext-marker".to_string()))
+            .await
+            .unwrap();
+        let needle = b"plaintext-marker";
+        assert!(!captured.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_static_key_rejected() {
+        // Mallory presents her own static key along with Alice's ID and signature
+        let alice = NoiseKeys::new(&Identity::generate());
+        let mallory = NoiseKeys::new(&Identity::generate());
+        let impostor = NoiseKeys {
+            private: mallory.private.clone(),
+            public: mallory.public.clone(),
+            credential: alice.credential.clone(),
+        };
+        let bob = NoiseKeys::new(&Identity::generate());
+        let (impostor, bob) = (&impostor, &bob);
+
+        // Each side drops its stream when done, so the other isn't left waiting
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (_, sb) = tokio::join!(
+            async move { handshake_initiator(&mut a, impostor).await },
+            async move { handshake_responder(&mut b, bob).await }
+        );
+        assert!(matches!(sb, Err(ChatError::Crypto(_))));
+
+        // The same goes for a responder claiming someone else
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (sa, _) = tokio::join!(
+            async move { handshake_initiator(&mut a, bob).await },
+            async move { handshake_responder(&mut b, impostor).await }
+        );
+        assert!(matches!(sa, Err(ChatError::Crypto(_))));
+    }
+}
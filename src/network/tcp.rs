@@ -2,76 +2,559 @@
 //!
 //! This module is responsible for managing TCP connections with peers,
 //! handling incoming messages, and broadcasting outgoing messages.
+//! Incoming bytes are decrypted by `network::secure` and decoded with the length-prefixed
+//! framing from `network::codec`.
+//! Claims about where a peer can be reached, that it is leaving or that it got a message
+//! (`Discovery`, `Exit`, `Ack`) are only taken from the peer itself, i.e. over a connection whose
+//! handshake proved that peer ID. Messages are authenticated before anything is acknowledged.
 //! It utilizes Tokio's asynchronous runtime for non-blocking I/O operations.
 
+use crate::chat::channels::ChannelState;
+use crate::chat::events::{ChatEvent, DiscoveryMethod, LeaveReason};
+use crate::chat::ignore::IgnoreList;
+use crate::chat::net::connection::ConnectionManager;
+use crate::chat::net::{delivery, swarm, sync, transfer};
+use crate::chat::receipts::ReceiptTracker;
+use crate::chat::sequence::{SequenceCheck, SequenceTracker};
+use crate::chat::swarm::Shares;
+use crate::chat::transfer::FileTransfers;
 use crate::error::ChatError;
-use crate::peer::{NetworkMessage, PeerInfo};
-use chrono::Utc;
-use colored::*;
+use crate::history::{HistoryEntry, HistoryStore};
+use crate::identity::verify_message;
+use crate::known_peers::{fingerprint, KnownPeers, TrustStatus};
+use crate::network::secure::{SecureReader, Session};
+use crate::outbox::Outbox;
+use crate::peer::{AckKind, Message, NetworkMessage, PeerInfo};
 use serde_json;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::sync::{Arc, Weak};
+use tokio::io::AsyncRead;
 use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
 
-pub async fn handle_tcp_connection(
-    stream: TcpStream,
-    _addr: SocketAddr,
-    peers: Arc<Mutex<HashMap<String, PeerInfo>>>,
-    message_sender: broadcast::Sender<String>,
-    peer_id: String,
-) -> Result<(), ChatError> {
-    let mut buf = [0; 1024];
-
-    while let Ok(_n) = stream.readable().await {
-        match stream.try_read(&mut buf) {
-            Ok(0) => break, // Connection closed
-            Ok(n) => {
-                if let Ok(network_msg) = serde_json::from_slice::<NetworkMessage>(&buf[..n]) {
-                    match network_msg {
-                        NetworkMessage::Chat(message) => {
-                            let display_msg =
-                                format!("{} says: {}", message.from_name, message.content);
-                            let _ = message_sender.send(display_msg);
-                        }
-                        NetworkMessage::Exit(peer_id) => {
-                            let mut peers = peers.lock().await;
-                            if peers.remove(&peer_id).is_some() {
-                                let timestamp = Utc::now().format("%H:%M:%S");
-                                println!(
-                                    "[{}] {} Peer {} exited and was removed from the list.",
-                                    timestamp.to_string().dimmed(),
-                                    "❌".bright_red(),
-                                    peer_id.bright_yellow()
-                                );
-                            }
-                        }
-                        NetworkMessage::Discovery(peer_info) => {
-                            if peer_info.id == peer_id {
-                                // Ignore our own Discovery messages
-                                return Ok(());
-                            }
-                            // Validate discovered peer before adding
-                            if !peer_info.is_valid() {
-                                eprintln!("Invalid peer info received via TCP: {:?}", peer_info);
-                                return Ok(());
-                            }
-                            let mut peers = peers.lock().await;
-                            if !peers.contains_key(&peer_info.id) {
-                                println!(
-                                    "🔗 Discovered peer via TCP: {} at {}",
-                                    peer_info.name, peer_info.ip
-                                );
-                            }
-                            peers.insert(peer_info.id.clone(), peer_info);
-                        }
-                        NetworkMessage::Heartbeat(_) => {}
-                    }
+/// Shared state every connection handler needs to apply incoming messages.
+#[derive(Clone)]
+pub struct ConnectionContext {
+    pub peers: Arc<Mutex<HashMap<String, PeerInfo>>>,
+    pub events: broadcast::Sender<ChatEvent>,
+    pub peer_id: String,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub channels: Arc<Mutex<ChannelState>>,
+    pub history: Arc<Mutex<HistoryStore>>,
+    pub sequences: Arc<Mutex<SequenceTracker>>,
+    pub receipts: Arc<Mutex<ReceiptTracker>>,
+    pub outbox: Arc<Mutex<Outbox>>,
+    /// Used to answer peers (e.g. history sync) from inside a connection handler. Weak because
+    /// the manager itself owns a copy of this context.
+    pub connections: Weak<ConnectionManager>,
+    /// Cancelled when the node shuts down; connection handlers stop reading.
+    pub shutdown: CancellationToken,
+    pub ignored: Arc<Mutex<IgnoreList>>,
+    pub transfers: Arc<Mutex<FileTransfers>>,
+    pub shares: Arc<Mutex<Shares>>,
+}
+
+/// The other end of a connection.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    pub addr: SocketAddr,
+    /// The peer ID the remote proved during the handshake, see `network::secure`.
+    pub peer_id: String,
+}
+
+impl ConnectionContext {
+    /// Publish a `ChatEvent::Warning`.
+    pub fn warn(&self, text: String) {
+        let _ = self.events.send(ChatEvent::Warning(text));
+    }
+}
+
+/// Read and decrypt frames from either side of an established secure connection, and apply
+/// each decoded message until the stream closes.
+pub async fn handle_tcp_connection<R>(
+    mut stream: SecureReader<R>,
+    addr: SocketAddr,
+    ctx: ConnectionContext,
+) -> Result<(), ChatError>
+where
+    R: AsyncRead + Unpin,
+{
+    let remote = Remote {
+        addr,
+        peer_id: stream.peer_id().to_string(),
+    };
+    while let Some(frame) = stream.read_frame().await? {
+        let Ok(network_msg) = serde_json::from_slice::<NetworkMessage>(&frame) else {
+            continue;
+        };
+        handle_message(&ctx, &remote, network_msg).await;
+    }
+    Ok(())
+}
+
+/// Apply one decoded message received from `remote`.
+pub async fn handle_message(ctx: &ConnectionContext, remote: &Remote, network_msg: NetworkMessage) {
+    match network_msg {
+        NetworkMessage::Chat(message) => {
+            if !ctx.channels.lock().await.is_joined(&message.channel) {
+                // The sender hasn't seen us leave this channel yet
+                return;
+            }
+            let Some(trusted) = authenticate(ctx, &message).await else {
+                return;
+            };
+            if ctx.history.lock().await.contains(&message.id) {
+                // Already received, e.g. through history sync, or a retry whose ack got lost
+                delivery::send_ack(ctx, &message.from_id, &message.id, AckKind::Delivered).await;
+                return;
+            }
+            if ctx.ignored.lock().await.matches(&message) {
+                delivery::send_ack(ctx, &message.from_id, &message.id, AckKind::Delivered).await;
+                return;
+            }
+            let check =
+                ctx.sequences
+                    .lock()
+                    .await
+                    .observe(&message.from_id, &message.channel, message.seq);
+            if let SequenceCheck::Gap { missed } = check {
+                let _ = ctx.events.send(ChatEvent::MessagesMissed {
+                    from_name: message.from_name.clone(),
+                    channel: message.channel.clone(),
+                    missed,
+                });
+            }
+            let _ = ctx.events.send(ChatEvent::MessageReceived {
+                message: message.clone(),
+                direct: false,
+                trusted,
+            });
+            acknowledge(ctx, &message).await;
+            record_received(ctx, message, false).await;
+        }
+        NetworkMessage::Direct(message) => {
+            if message.to_id != ctx.peer_id {
+                // Somebody else's direct message, passed on to us
+                return;
+            }
+            let Some(trusted) = authenticate(ctx, &message).await else {
+                return;
+            };
+            if ctx.history.lock().await.contains(&message.id) {
+                delivery::send_ack(ctx, &message.from_id, &message.id, AckKind::Delivered).await;
+                return;
+            }
+            if ctx.ignored.lock().await.matches(&message) {
+                delivery::send_ack(ctx, &message.from_id, &message.id, AckKind::Delivered).await;
+                return;
+            }
+            let _ = ctx.events.send(ChatEvent::MessageReceived {
+                message: message.clone(),
+                direct: true,
+                trusted,
+            });
+            acknowledge(ctx, &message).await;
+            record_received(ctx, message, true).await;
+        }
+        NetworkMessage::Exit(peer_id) => {
+            if peer_id != remote.peer_id {
+                // Only a peer itself may say it is leaving
+                return;
+            }
+            let removed = ctx.peers.lock().await.remove(&peer_id);
+            if let Some(connections) = ctx.connections.upgrade() {
+                connections.disconnect(&peer_id).await;
+            }
+            if let Some(info) = removed {
+                let _ = ctx.events.send(ChatEvent::PeerLeft {
+                    peer: info,
+                    reason: LeaveReason::Exited,
+                });
+            }
+        }
+        NetworkMessage::Discovery(mut peer_info) => {
+            if peer_info.id == ctx.peer_id {
+                // Ignore our own Discovery messages
+                return;
+            }
+            // Only the peer itself can tell us where it is, so nobody can redirect its traffic
+            if peer_info.id != remote.peer_id {
+                ctx.warn(format!(
+                    "Ignoring peer info for {} sent by {} ({})",
+                    fingerprint(&peer_info.id),
+                    fingerprint(&remote.peer_id),
+                    remote.addr
+                ));
+                return;
+            }
+            // Validate discovered peer before adding
+            if !peer_info.is_valid() {
+                ctx.warn(format!(
+                    "Invalid peer info received via TCP: {:?}",
+                    peer_info
+                ));
+                return;
+            }
+            // Trust the address we are actually talking to over the one the peer reports
+            peer_info.ip = remote.addr.ip();
+            let mut peers = ctx.peers.lock().await;
+            if !peers.contains_key(&peer_info.id) {
+                let _ = ctx.events.send(ChatEvent::PeerJoined {
+                    peer: peer_info.clone(),
+                    via: DiscoveryMethod::Tcp,
+                });
+                // A peer we haven't seen yet may have history we are missing, and we may
+                // have messages queued for it from an earlier session
+                sync::request_sync(ctx, peer_info.clone());
+                if let Some(connections) = ctx.connections.upgrade() {
+                    connections.flush_outbox(peer_info.clone());
                 }
             }
-            Err(e) => return Err(ChatError::Network(e.to_string())),
+            peers.insert(peer_info.id.clone(), peer_info);
+        }
+        NetworkMessage::SyncRequest(request) => {
+            sync::answer_sync(ctx, remote, request).await;
+        }
+        NetworkMessage::SyncResponse(messages) => {
+            let added = sync::apply_sync(ctx, messages).await;
+            if added > 0 {
+                let _ = ctx.events.send(ChatEvent::HistorySynced { count: added });
+            }
         }
+        NetworkMessage::Ack(ack) => {
+            if ack.from_id != remote.peer_id {
+                // Acks come straight from the recipient, so anything else is forged
+                return;
+            }
+            let update =
+                ctx.receipts
+                    .lock()
+                    .await
+                    .acknowledge(&ack.message_id, &ack.from_id, ack.kind);
+            if let Some((peer_name, status)) = update {
+                let _ = ctx.events.send(ChatEvent::DeliveryStatus {
+                    message_id: ack.message_id,
+                    peer_id: ack.from_id,
+                    peer_name,
+                    status,
+                });
+            }
+        }
+        NetworkMessage::FileOffer(offer) => transfer::handle_offer(ctx, remote, offer).await,
+        NetworkMessage::FileDecline(offer_id) => {
+            transfer::handle_decline(ctx, remote, &offer_id).await
+        }
+        NetworkMessage::ShareAnnounce(manifest) => {
+            swarm::handle_announce(ctx, remote, manifest).await
+        }
+        NetworkMessage::ShareHave(have) => swarm::handle_have(ctx, remote, have).await,
+        NetworkMessage::FileRequest(_) | NetworkMessage::ChunkRequest(_) => {
+            // Only valid as the first message of a dedicated stream, see `listener`
+            ctx.warn(format!(
+                "Ignoring transfer request from {} on a chat stream",
+                remote.addr
+            ));
+        }
+        NetworkMessage::Heartbeat(_) => {}
+    }
+}
+
+/// Tell the author a message was displayed, and owe them a read receipt for it.
+async fn acknowledge(ctx: &ConnectionContext, message: &Message) {
+    delivery::send_ack(ctx, &message.from_id, &message.id, AckKind::Delivered).await;
+    ctx.receipts
+        .lock()
+        .await
+        .mark_unread(&message.from_id, &message.id);
+}
+
+async fn record_received(ctx: &ConnectionContext, message: Message, direct: bool) {
+    let entry = HistoryEntry {
+        message,
+        outgoing: false,
+        direct,
+    };
+    if let Err(e) = ctx.history.lock().await.append(entry) {
+        ctx.warn(format!("Failed to write message history: {}", e));
+    }
+}
+
+/// Verify a received message's signature and refresh its sender's liveness. Returns `None` if
+/// the message must be dropped, otherwise whether the sender is trusted, i.e. hasn't presented a
+/// mismatched key.
+async fn authenticate(ctx: &ConnectionContext, message: &Message) -> Option<bool> {
+    if !verify_message(message) {
+        let _ = ctx.events.send(ChatEvent::Error(format!(
+            "Dropping message with invalid signature claiming to be from {}",
+            message.from_name
+        )));
+        return None;
+    }
+    if let Some(info) = ctx.peers.lock().await.get_mut(&message.from_id) {
+        info.touch();
+    }
+    Some(ctx.known_peers.lock().await.is_trusted(&message.from_id))
+}
+
+/// Check the key a peer presented in its handshake against the trust-on-first-use store, warning
+/// loudly if its ID is on record with a different key. Run after every handshake.
+pub async fn check_trust(ctx: &ConnectionContext, session: &Session) {
+    let key = session.transport.get_remote_static().unwrap_or_default();
+    let status = ctx.known_peers.lock().await.check(&session.peer_id, key);
+    match status {
+        Ok(TrustStatus::New) => {
+            let _ = ctx.events.send(ChatEvent::PeerTrusted {
+                peer_id: session.peer_id.clone(),
+            });
+        }
+        Ok(TrustStatus::Known) => {}
+        Ok(TrustStatus::Mismatch { expected_key }) => {
+            let _ = ctx.events.send(ChatEvent::KeyMismatch {
+                peer_id: session.peer_id.clone(),
+                expected_key,
+                presented_key: hex::encode(key),
+            });
+        }
+        Err(e) => ctx.warn(format!("Failed to update known peers: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Peer;
+    use crate::identity::Identity;
+    use crate::peer::{Ack, FileOffer, ShareHave, ShareManifest};
+    use std::net::IpAddr;
+
+    #[tokio::test]
+    async fn test_peer_info_only_accepted_from_that_peer() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let bob_id = "b".repeat(64);
+        let bob = PeerInfo::new(
+            bob_id.clone(),
+            "Bob".to_string(),
+            IpAddr::from([192, 168, 1, 20]),
+            9001,
+        );
+        alice.peers.lock().await.insert(bob_id.clone(), bob);
+
+        // Mallory claims to be Bob to have his traffic sent to her
+        let mallory = Remote {
+            addr: "192.168.1.66:9002".parse().unwrap(),
+            peer_id: "c".repeat(64),
+        };
+        let forged = PeerInfo::new(bob_id.clone(), "Bob".to_string(), mallory.addr.ip(), 9002);
+        handle_message(&ctx, &mallory, NetworkMessage::Discovery(forged)).await;
+        handle_message(&ctx, &mallory, NetworkMessage::Exit(bob_id.clone())).await;
+        let peers = alice.peers.lock().await;
+        assert_eq!(peers[&bob_id].ip, IpAddr::from([192, 168, 1, 20]));
+        assert_eq!(peers.len(), 1);
+        drop(peers);
+
+        // Bob himself may move
+        let bob = Remote {
+            addr: "192.168.1.21:9001".parse().unwrap(),
+            peer_id: bob_id.clone(),
+        };
+        let moved = PeerInfo::new(bob_id.clone(), "Bob".to_string(), bob.addr.ip(), 9001);
+        handle_message(&ctx, &bob, NetworkMessage::Discovery(moved)).await;
+        assert_eq!(alice.peers.lock().await[&bob_id].ip, bob.addr.ip());
+    }
+
+    #[tokio::test]
+    async fn test_file_offers_only_accepted_from_the_sender() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let bob_id = "b".repeat(64);
+        let bob = PeerInfo::new(
+            bob_id.clone(),
+            "Bob".to_string(),
+            IpAddr::from([192, 168, 1, 20]),
+            9001,
+        );
+        alice.peers.lock().await.insert(bob_id.clone(), bob);
+        let offer = FileOffer {
+            id: "a".repeat(32),
+            from_id: bob_id.clone(),
+            from_name: "Bob".to_string(),
+            file_name: "notes.txt".to_string(),
+            size: 1,
+            sha256: "e".repeat(64),
+        };
+
+        // Mallory offers a file in Bob's name
+        let mallory = Remote {
+            addr: "192.168.1.66:9002".parse().unwrap(),
+            peer_id: "c".repeat(64),
+        };
+        handle_message(&ctx, &mallory, NetworkMessage::FileOffer(offer.clone())).await;
+        assert!(alice.transfers.lock().await.list().1.is_empty());
+
+        let bob = Remote {
+            addr: "192.168.1.20:9001".parse().unwrap(),
+            peer_id: bob_id.clone(),
+        };
+        handle_message(&ctx, &bob, NetworkMessage::FileOffer(offer.clone())).await;
+        assert_eq!(alice.transfers.lock().await.list().1.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shares_only_accepted_from_the_peer_named() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let bob_id = "b".repeat(64);
+        let bob = PeerInfo::new(
+            bob_id.clone(),
+            "Bob".to_string(),
+            IpAddr::from([192, 168, 1, 20]),
+            9001,
+        );
+        alice.peers.lock().await.insert(bob_id.clone(), bob);
+        let manifest = ShareManifest {
+            id: "e".repeat(64),
+            from_id: bob_id.clone(),
+            from_name: "Bob".to_string(),
+            file_name: "notes.txt".to_string(),
+            size: 1,
+            chunk_len: 1,
+            chunks: vec!["f".repeat(64)],
+        };
+
+        // Mallory announces a share in Bob's name
+        let mallory = Remote {
+            addr: "192.168.1.66:9002".parse().unwrap(),
+            peer_id: "c".repeat(64),
+        };
+        let forged = NetworkMessage::ShareAnnounce(manifest.clone());
+        handle_message(&ctx, &mallory, forged).await;
+        assert!(alice.shares.lock().await.get(&manifest.id).is_none());
+
+        let bob = Remote {
+            addr: "192.168.1.20:9001".parse().unwrap(),
+            peer_id: bob_id.clone(),
+        };
+        handle_message(&ctx, &bob, NetworkMessage::ShareAnnounce(manifest.clone())).await;
+        assert!(alice.shares.lock().await.get(&manifest.id).is_some());
+
+        // ... and claims that Dave holds it
+        let have = ShareHave {
+            share_id: manifest.id.clone(),
+            peer_id: "d".repeat(64),
+            chunks: vec![0],
+        };
+        handle_message(&ctx, &mallory, NetworkMessage::ShareHave(have)).await;
+        let shares = alice.shares.lock().await;
+        let sources = &shares.get(&manifest.id).unwrap().sources;
+        assert_eq!(sources.keys().collect::<Vec<_>>(), vec![&bob_id]);
+    }
+
+    #[tokio::test]
+    async fn test_acks_only_accepted_from_the_recipient() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let mut events = alice.events.subscribe();
+        let bob_id = "b".repeat(64);
+        let message = NetworkMessage::Heartbeat(alice.peer_id.clone());
+        alice.receipts.lock().await.track(
+            "m1",
+            "hello",
+            message,
+            [(bob_id.clone(), "Bob".to_string())],
+        );
+        let ack = Ack {
+            message_id: "m1".to_string(),
+            from_id: bob_id.clone(),
+            kind: AckKind::Read,
+        };
+
+        let mallory = Remote {
+            addr: "192.168.1.66:9002".parse().unwrap(),
+            peer_id: "c".repeat(64),
+        };
+        handle_message(&ctx, &mallory, NetworkMessage::Ack(ack.clone())).await;
+        assert!(events.try_recv().is_err());
+
+        let bob = Remote {
+            addr: "192.168.1.20:9001".parse().unwrap(),
+            peer_id: bob_id,
+        };
+        handle_message(&ctx, &bob, NetworkMessage::Ack(ack)).await;
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ChatEvent::DeliveryStatus { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages_only_accepted_by_the_recipient() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let bob = Identity::generate();
+        let mut message = Message {
+            id: Message::new_id(),
+            from_id: bob.peer_id(),
+            from_name: "Bob".to_string(),
+            content: "for Carol only".to_string(),
+            timestamp: 1234567890,
+            channel: String::new(),
+            seq: 0,
+            to_id: "c".repeat(64),
+            signature: String::new(),
+        };
+        bob.sign(&mut message);
+
+        // Carol passes Bob's direct message on to Alice
+        let mut events = alice.events.subscribe();
+        let carol = Remote {
+            addr: "192.168.1.30:9002".parse().unwrap(),
+            peer_id: "c".repeat(64),
+        };
+        handle_message(&ctx, &carol, NetworkMessage::Direct(message.clone())).await;
+        assert!(events.try_recv().is_err());
+
+        message.to_id = alice.peer_id.clone();
+        bob.sign(&mut message);
+        let remote = Remote {
+            addr: "192.168.1.20:9001".parse().unwrap(),
+            peer_id: bob.peer_id(),
+        };
+        handle_message(&ctx, &remote, NetworkMessage::Direct(message)).await;
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ChatEvent::MessageReceived { direct: true, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_is_authenticated_before_ack() {
+        let alice = Peer::new("Alice".to_string(), 9000);
+        let ctx = alice.connection_context();
+        let bob = Identity::generate();
+        let mut message = Message {
+            id: Message::new_id(),
+            from_id: bob.peer_id(),
+            from_name: "Bob".to_string(),
+            content: "hello".to_string(),
+            timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 1,
+            to_id: String::new(),
+            signature: String::new(),
+        };
+        bob.sign(&mut message);
+        record_received(&ctx, message.clone(), false).await;
+
+        // A tampered copy of a message we already have is dropped, not acknowledged
+        let mut events = alice.events.subscribe();
+        message.content = "tampered".to_string();
+        let remote = Remote {
+            addr: "192.168.1.20:9001".parse().unwrap(),
+            peer_id: bob.peer_id(),
+        };
+        handle_message(&ctx, &remote, NetworkMessage::Chat(message)).await;
+        assert!(matches!(events.try_recv(), Ok(ChatEvent::Error(_))));
     }
-    Ok(())
 }
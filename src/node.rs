@@ -0,0 +1,319 @@
+//! Node module: Embeddable entry point that builds a chat peer, runs its services and hands back a handle.
+//!
+//! `ChatNode::builder()` takes a `Config` and/or individual settings plus a storage location;
+//! `build` loads the stores, and `start` binds the listener and runs every network service in
+//! the background. The returned `ChatHandle` sends messages, lists peers, subscribes to
+//! `ChatEvent`s and shuts the node down. Nothing in here touches stdin or stdout, so the same
+//! node drives the terminal front-end in `main.rs`, bots and tests.
+//!
+//! Shutdown is driven by the `CancellationToken` in `Peer::shutdown`: cancelling it (from
+//! `ChatHandle::shutdown`, a signal handler or anything holding a clone) stops the services,
+//! after which the handle says goodbye to peers and flushes storage.
+
+use crate::chat::events::ChatEvent;
+use crate::chat::net::broadcast::{self, DeliveryResult};
+use crate::chat::net::discovery::Discovery;
+use crate::chat::{net, Peer};
+use crate::config::{Config, DiscoveryBackend};
+use crate::error::ChatError;
+use crate::history::HistoryStore;
+use crate::identity::Identity;
+use crate::known_peers::KnownPeers;
+use crate::outbox::Outbox;
+use crate::peer::{FileOffer, PeerInfo, ShareManifest};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Configuration for a `ChatNode`; see `ChatNode::builder`.
+#[derive(Default)]
+pub struct ChatNodeBuilder {
+    storage: Option<PathBuf>,
+    config: Config,
+}
+
+impl ChatNodeBuilder {
+    /// Display name announced to other peers.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    /// TCP port to listen on.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Whether to advertise and browse for peers over mDNS (on by default, next to beacons).
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        let methods = &mut self.config.discovery.methods;
+        methods.retain(|method| *method != DiscoveryBackend::Mdns);
+        if enabled {
+            methods.push(DiscoveryBackend::Mdns);
+        }
+        self
+    }
+
+    /// The discovery backends to run, replacing the default of mDNS and beacons. Their settings
+    /// (static peers, registry directory) come from the config.
+    pub fn discovery(mut self, methods: impl IntoIterator<Item = DiscoveryBackend>) -> Self {
+        self.config.discovery.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Keep the identity key at `identity_path`, with the known peers, history and outbox in
+    /// files next to it. Without this the node uses a throwaway identity and in-memory stores.
+    pub fn storage(mut self, identity_path: impl Into<PathBuf>) -> Self {
+        self.storage = Some(identity_path.into());
+        self
+    }
+
+    /// Replace all settings with `config`, e.g. one read from a config file. Setters called
+    /// before this are overwritten; later ones apply on top. The config's `identity` is only
+    /// used if `storage` is set as well.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Load (or create) the configured storage and build the node without starting it.
+    pub fn build(self) -> Result<ChatNode, ChatError> {
+        let config = self.config;
+        let liveness = config.liveness()?;
+        let name = config.name.clone();
+        let mut peer = match &self.storage {
+            Some(identity_path) => Peer::with_storage(
+                name,
+                config.port,
+                Identity::load_or_create(identity_path)?,
+                KnownPeers::load(identity_path.with_extension("known_peers"))?,
+                HistoryStore::open(identity_path.with_extension("history.jsonl"))?,
+                Outbox::load(identity_path.with_extension("outbox.json"))?,
+            ),
+            None => Peer::new(name, config.port),
+        };
+        peer.liveness = liveness;
+        Ok(ChatNode {
+            peer: Arc::new(peer),
+            config,
+        })
+    }
+
+    /// Build the node and start it; see `ChatNode::start`.
+    pub async fn start(self) -> Result<ChatHandle, ChatError> {
+        self.build()?.start().await
+    }
+}
+
+/// A configured chat peer that hasn't started its network services yet.
+pub struct ChatNode {
+    peer: Arc<Peer>,
+    config: Config,
+}
+
+impl ChatNode {
+    pub fn builder() -> ChatNodeBuilder {
+        ChatNodeBuilder::default()
+    }
+
+    /// The peer's state, e.g. to subscribe to events before anything can happen.
+    pub fn peer(&self) -> &Arc<Peer> {
+        &self.peer
+    }
+
+    /// Bind the TCP listener and run every network service in the background. Fails right away
+    /// if the port can't be bound.
+    pub async fn start(self) -> Result<ChatHandle, ChatError> {
+        self.peer.apply_config(&self.config).await?;
+        let listener = net::listener::bind_tcp_listener(self.peer.port).await?;
+        let discovery = &self.config.discovery;
+        let advertiser = if discovery.methods.contains(&DiscoveryBackend::Mdns) {
+            Some(net::mdns::start_advertising(&self.peer).await)
+        } else {
+            None
+        };
+        let backends = net::discovery::backends(discovery);
+        let services = tokio::spawn(run_services(self.peer.clone(), listener, backends));
+        Ok(ChatHandle {
+            peer: self.peer,
+            services: Some(services),
+            advertiser,
+        })
+    }
+}
+
+/// Run the network services until the shutdown token is cancelled or one of them fails. A
+/// failure cancels the token too, so nothing is left running on its own.
+async fn run_services(
+    peer: Arc<Peer>,
+    listener: TcpListener,
+    backends: Vec<Box<dyn Discovery>>,
+) -> Result<(), ChatError> {
+    let result = services(&peer, listener, backends).await;
+    peer.shutdown.cancel();
+    result
+}
+
+async fn services(
+    peer: &Arc<Peer>,
+    listener: TcpListener,
+    backends: Vec<Box<dyn Discovery>>,
+) -> Result<(), ChatError> {
+    let service_error =
+        |service: &str, e: ChatError| ChatError::Unknown(format!("{}: {}", service, e));
+    tokio::select! {
+        _ = peer.shutdown.cancelled() => Ok(()),
+        result = net::listener::start_tcp_listener(peer, listener) => {
+            result.map_err(|e| service_error("TCP listener", e))
+        }
+        result = net::discovery::start_discovery(peer.clone(), backends) => {
+            result.map_err(|e| service_error("Discovery", e))
+        }
+        result = net::heartbeat::start_heartbeat(peer) => {
+            result.map_err(|e| service_error("Heartbeat sender", e))
+        }
+        result = net::heartbeat::start_heartbeat_listener(peer) => {
+            result.map_err(|e| service_error("Heartbeat listener", e))
+        }
+        result = net::liveness::start_peer_reaper(peer) => {
+            result.map_err(|e| service_error("Peer reaper", e))
+        }
+        result = net::delivery::start_retry_loop(peer) => {
+            result.map_err(|e| service_error("Delivery retry", e))
+        }
+    }
+}
+
+/// A running chat node.
+pub struct ChatHandle {
+    peer: Arc<Peer>,
+    /// `None` once `closed` has collected the result.
+    services: Option<JoinHandle<Result<(), ChatError>>>,
+    advertiser: Option<JoinHandle<()>>,
+}
+
+impl ChatHandle {
+    /// The peer's state, for the finer-grained calls on `Peer`.
+    pub fn peer(&self) -> &Arc<Peer> {
+        &self.peer
+    }
+
+    /// Post `content` to the active channel.
+    pub async fn send(&self, content: &str) -> Result<Vec<DeliveryResult>, ChatError> {
+        self.peer.broadcast_message(content).await
+    }
+
+    /// Send a private message to the peer matching `to` (name, ID, or unique ID prefix).
+    pub async fn send_direct(&self, to: &str, content: &str) -> Result<DeliveryResult, ChatError> {
+        self.peer.send_direct(to, content).await
+    }
+
+    /// Offer a file to the peer matching `to`; see `Peer::send_file`.
+    pub async fn send_file(
+        &self,
+        to: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<FileOffer, ChatError> {
+        self.peer.send_file(to, path.as_ref()).await
+    }
+
+    /// Download a file offered to us; see `Peer::accept_file`.
+    pub async fn accept_file(&self, offer_id: &str) -> Result<FileOffer, ChatError> {
+        self.peer.accept_file(offer_id).await
+    }
+
+    /// Add the peer listening at `addr` (`host:port`); see `Peer::connect`.
+    pub async fn connect(&self, addr: &str) -> Result<PeerInfo, ChatError> {
+        self.peer.connect(addr).await
+    }
+
+    /// Share a file with every peer; see `Peer::share_file`.
+    pub async fn share_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(ShareManifest, Vec<DeliveryResult>), ChatError> {
+        self.peer.share_file(path.as_ref()).await
+    }
+
+    /// Fetch a file someone shared; see `Peer::fetch_share`.
+    pub async fn fetch_share(&self, share_id: &str) -> Result<ShareManifest, ChatError> {
+        self.peer.fetch_share(share_id).await
+    }
+
+    /// Receive every `ChatEvent` published from now on.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ChatEvent> {
+        self.peer.events.subscribe()
+    }
+
+    /// The peers currently known, sorted by name.
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.peer.peers.lock().await.values().cloned().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        peers
+    }
+
+    /// Wait until the services stop, because of a failure (returned here) or because shutdown
+    /// was requested.
+    pub async fn closed(&mut self) -> Result<(), ChatError> {
+        let Some(services) = self.services.as_mut() else {
+            return Ok(());
+        };
+        let result = services.await;
+        self.services = None;
+        result.map_err(|e| ChatError::Unknown(e.to_string()))?
+    }
+
+    /// Stop the listener and every other service, unregister from mDNS, tell every peer we are
+    /// leaving, close all connections and flush storage. Returns the result of the exit message
+    /// for each peer.
+    pub async fn shutdown(mut self) -> Result<Vec<DeliveryResult>, ChatError> {
+        self.peer.shutdown.cancel();
+        // A service failure has already been reported through `closed` or is moot now
+        let _ = self.closed().await;
+        if let Some(advertiser) = self.advertiser.take() {
+            let _ = advertiser.await;
+        }
+        let results = broadcast::broadcast_exit(&self.peer).await;
+        self.peer.sync_storage().await?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_start_send_and_shutdown() {
+        let node = ChatNode::builder()
+            .name("Embedded")
+            .port(free_port())
+            .discovery([])
+            .build()
+            .unwrap();
+        assert_eq!(node.peer().name(), "Embedded");
+        let handle = node.start().await.unwrap();
+        assert!(handle.peers().await.is_empty());
+        // Nobody to deliver to yet, but the message is recorded locally
+        assert!(handle.send("hello").await.unwrap().is_empty());
+        assert_eq!(handle.peer().history.lock().await.len(), 1);
+        let shutdown = handle.peer().shutdown.clone();
+        assert!(handle.shutdown().await.unwrap().is_empty());
+        assert!(shutdown.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_start_fails_on_busy_port() {
+        let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let result = ChatNode::builder().port(port).discovery([]).start().await;
+        assert!(matches!(result, Err(ChatError::Network(_))));
+    }
+}
@@ -0,0 +1,150 @@
+//! Outbox module: Per-peer queue of messages that could not be delivered, kept on disk until the peer is back.
+//!
+//! When a chat or direct message can't be written to a peer, it is queued here under the peer's ID
+//! instead of being lost. The queues are flushed in order once the peer is seen again (mDNS
+//! rediscovery, a fresh `Discovery` or a resumed heartbeat). The whole outbox is small, so it is
+//! rewritten as a single JSON file on every change.
+
+use crate::error::ChatError;
+use crate::peer::NetworkMessage;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+/// Most messages held for a single peer; the oldest are dropped beyond this.
+const MAX_QUEUED_PER_PEER: usize = 500;
+
+pub struct Outbox {
+    path: Option<PathBuf>,
+    queues: BTreeMap<String, VecDeque<NetworkMessage>>,
+}
+
+impl Outbox {
+    /// An outbox that lives only for this session.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            queues: BTreeMap::new(),
+        }
+    }
+
+    /// Load the outbox from `path`. A missing file is treated as empty and created on first write.
+    pub fn load(path: PathBuf) -> Result<Self, ChatError> {
+        let queues = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path: Some(path),
+            queues,
+        })
+    }
+
+    /// Queue `msg` for `peer_id`.
+    pub fn enqueue(&mut self, peer_id: &str, msg: NetworkMessage) -> Result<(), ChatError> {
+        let queue = self.queues.entry(peer_id.to_string()).or_default();
+        if queue.len() == MAX_QUEUED_PER_PEER {
+            queue.pop_front();
+        }
+        queue.push_back(msg);
+        self.save()
+    }
+
+    /// Remove and return everything queued for `peer_id`, oldest first.
+    pub fn take(&mut self, peer_id: &str) -> Result<Vec<NetworkMessage>, ChatError> {
+        let Some(queue) = self.queues.remove(peer_id) else {
+            return Ok(Vec::new());
+        };
+        self.save()?;
+        Ok(queue.into())
+    }
+
+    /// Put messages that still couldn't be sent back at the front of `peer_id`'s queue.
+    pub fn requeue(&mut self, peer_id: &str, msgs: Vec<NetworkMessage>) -> Result<(), ChatError> {
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        let queue = self.queues.entry(peer_id.to_string()).or_default();
+        for msg in msgs.into_iter().rev() {
+            queue.push_front(msg);
+        }
+        queue.truncate(MAX_QUEUED_PER_PEER);
+        self.save()
+    }
+
+    /// Number of messages waiting for `peer_id`.
+    pub fn queued(&self, peer_id: &str) -> usize {
+        self.queues.get(peer_id).map_or(0, VecDeque::len)
+    }
+
+    /// Flush the outbox file to disk, e.g. before exiting.
+    pub fn sync(&self) -> Result<(), ChatError> {
+        match &self.path {
+            Some(path) if path.exists() => Ok(fs::File::open(path)?.sync_all()?),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<(), ChatError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write then rename, so a crash mid-write can't leave a truncated outbox behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.queues)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exit(id: &str) -> NetworkMessage {
+        NetworkMessage::Exit(id.to_string())
+    }
+
+    fn ids(msgs: &[NetworkMessage]) -> Vec<&str> {
+        msgs.iter()
+            .map(|m| match m {
+                NetworkMessage::Exit(id) => id.as_str(),
+                _ => "",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_take_and_requeue_keep_order() {
+        let mut outbox = Outbox::in_memory();
+        outbox.enqueue("bob", exit("1")).unwrap();
+        outbox.enqueue("bob", exit("2")).unwrap();
+        outbox.enqueue("carol", exit("3")).unwrap();
+        assert_eq!(outbox.queued("bob"), 2);
+
+        let taken = outbox.take("bob").unwrap();
+        assert_eq!(ids(&taken), ["1", "2"]);
+        assert_eq!(outbox.queued("bob"), 0);
+
+        outbox.enqueue("bob", exit("4")).unwrap();
+        outbox.requeue("bob", taken).unwrap();
+        assert_eq!(ids(&outbox.take("bob").unwrap()), ["1", "2", "4"]);
+        assert_eq!(outbox.queued("carol"), 1);
+    }
+
+    #[test]
+    fn test_outbox_persists() {
+        let path = std::env::temp_dir().join(format!("p2p_chat_outbox_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut outbox = Outbox::load(path.clone()).unwrap();
+        outbox.enqueue("bob", exit("1")).unwrap();
+        let mut reloaded = Outbox::load(path.clone()).unwrap();
+        assert_eq!(ids(&reloaded.take("bob").unwrap()), ["1"]);
+        assert_eq!(Outbox::load(path.clone()).unwrap().queued("bob"), 0);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -5,8 +5,12 @@
 //! identifying peers in the network, the `Message` struct for chat messages, and the `NetworkMessage`
 //! enum for different types of network messages.
 
+use crate::chat::channels::{default_channel, default_channels};
+use crate::network::codec::MAX_FRAME_LEN;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::IpAddr;
+use std::time::Instant;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
@@ -14,9 +18,36 @@ pub struct PeerInfo {
     pub name: String,
     pub ip: IpAddr,
     pub port: u16,
+    /// Channels this peer is subscribed to.
+    #[serde(default = "default_channels")]
+    pub channels: BTreeSet<String>,
+    /// When we last heard from this peer (heartbeat, discovery or chat traffic). Local only.
+    #[serde(skip, default = "Instant::now")]
+    pub last_seen: Instant,
+    /// Set by the liveness reaper once the peer has been silent for too long. Local only.
+    #[serde(skip)]
+    pub stale: bool,
 }
 
 impl PeerInfo {
+    pub fn new(id: String, name: String, ip: IpAddr, port: u16) -> Self {
+        Self {
+            id,
+            name,
+            ip,
+            port,
+            channels: default_channels(),
+            last_seen: Instant::now(),
+            stale: false,
+        }
+    }
+
+    /// Record that the peer is alive. Returns `true` if it had been marked stale.
+    pub fn touch(&mut self) -> bool {
+        self.last_seen = Instant::now();
+        std::mem::replace(&mut self.stale, false)
+    }
+
     /// Validate the fields of a PeerInfo instance.
     pub fn is_valid(&self) -> bool {
         !self.id.trim().is_empty()
@@ -30,18 +61,194 @@ impl PeerInfo {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// Random 128-bit ID, hex-encoded, unique across all peers. Used to de-duplicate and to
+    /// refer to a specific message.
+    #[serde(default)]
+    pub id: String,
     pub from_id: String,
     pub from_name: String,
     pub content: String,
     pub timestamp: u64,
+    /// Channel the message was posted to; empty for direct messages.
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    /// Per-sender sequence number within `channel`, starting at 1, so receivers can spot
+    /// messages they missed. 0 for direct messages, which are not sequenced.
+    #[serde(default)]
+    pub seq: u64,
+    /// Peer ID of the recipient of a direct message; empty for channel messages. Signed, so a
+    /// direct message can't be passed on to anyone else as if it had been sent to them.
+    #[serde(default)]
+    pub to_id: String,
+    /// Hex-encoded Ed25519 signature by the key in `from_id`, see `identity::verify_message`.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Message {
+    /// A fresh random message ID.
+    pub fn new_id() -> String {
+        hex::encode(rand::random::<[u8; 16]>())
+    }
+
+    /// The bytes covered by the sender's signature: every field except the signature itself.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.id,
+            &self.from_id,
+            &self.from_name,
+            &self.content,
+            self.timestamp,
+            &self.channel,
+            self.seq,
+            &self.to_id,
+        ))
+        .expect("serialize message fields")
+    }
+}
+
+/// Summary of the history a peer already has, sent to pull what it is missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub from_id: String,
+    /// Channel -> sender ID -> the sequence numbers we have from that sender, as sorted
+    /// inclusive ranges. Every joined channel is listed, even with nothing in it.
+    pub held: BTreeMap<String, SeqRanges>,
+}
+
+/// Sender ID -> sequence numbers, as sorted inclusive ranges.
+pub type SeqRanges = BTreeMap<String, Vec<(u64, u64)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AckKind {
+    /// The message was decoded and displayed.
+    Delivered,
+    /// The recipient's user has seen it.
+    Read,
+}
+
+/// Acknowledgement of a `Chat` or `Direct` message, sent back to its author.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+    pub message_id: String,
+    pub from_id: String,
+    pub kind: AckKind,
+}
+
+/// A file a peer would like to send us; see `chat::net::transfer`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOffer {
+    /// Random 128-bit ID, hex-encoded. Only the recipient learns it, so it also authorizes the
+    /// download.
+    pub id: String,
+    pub from_id: String,
+    pub from_name: String,
+    /// Bare file name, without any directories.
+    pub file_name: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the whole file.
+    pub sha256: String,
+}
+
+/// Value of `Beacon::protocol`, telling our beacons apart from other traffic on the port.
+pub const BEACON_PROTOCOL: &str = "p2p_chat";
+/// Version of the beacon format and of the TCP protocol it points to. Beacons with another
+/// version are ignored.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// UDP datagram announcing a peer, see `chat::net::beacon`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Beacon {
+    pub protocol: String,
+    pub version: u32,
+    /// The sender; its `ip` is replaced by the address the datagram came from.
+    pub peer: PeerInfo,
+}
+
+/// The part of a `Beacon` every version agrees on, read before the rest.
+#[derive(Debug, Deserialize)]
+pub struct BeaconHeader {
+    pub protocol: String,
+    pub version: u32,
+}
+
+/// First message on a dedicated file transfer stream: send offer `id` from byte `offset` on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub id: String,
+    pub offset: u64,
+}
+
+/// Description of a file shared with everyone, see `chat::net::swarm`. Chunks are verified
+/// against `chunks` as they arrive, and the whole file against `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareManifest {
+    /// Hex-encoded SHA-256 of the whole file, which also identifies the share.
+    pub id: String,
+    pub from_id: String,
+    pub from_name: String,
+    pub file_name: String,
+    pub size: u64,
+    pub chunk_len: u32,
+    /// Hex-encoded SHA-256 of each chunk, in order.
+    pub chunks: Vec<String>,
+}
+
+impl ShareManifest {
+    /// Check that the chunk list fits the size, so chunk offsets can be trusted.
+    pub fn is_valid(&self) -> bool {
+        let is_hash = |h: &str| h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit());
+        self.size > 0
+            && self.chunk_len > 0
+            && self.chunk_len as usize <= MAX_FRAME_LEN
+            && self.size.div_ceil(self.chunk_len as u64) == self.chunks.len() as u64
+            && is_hash(&self.id)
+            && self.chunks.iter().all(|h| is_hash(h))
+    }
+
+    /// Byte offset and length of chunk `index`.
+    pub fn chunk_range(&self, index: u32) -> Option<(u64, usize)> {
+        if index as usize >= self.chunks.len() {
+            return None;
+        }
+        let offset = index as u64 * self.chunk_len as u64;
+        let len = (self.size - offset).min(self.chunk_len as u64);
+        Some((offset, len as usize))
+    }
+}
+
+/// The chunks of a share a peer can serve.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareHave {
+    pub share_id: String,
+    pub peer_id: String,
+    pub chunks: Vec<u32>,
+}
+
+/// Ask for one chunk of a share. The first one opens a dedicated stream, on which further
+/// requests may follow; each is answered with the chunk's bytes, or nothing if we lack it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub share_id: String,
+    pub index: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NetworkMessage {
     Discovery(PeerInfo),
     Chat(Message),
+    Direct(Message),   // private message, sent only to its recipient
     Heartbeat(String), // peer_id
     Exit(String),      // peer_id
+    SyncRequest(SyncRequest),
+    SyncResponse(Vec<Message>), // channel messages the requester may be missing
+    Ack(Ack),
+    FileOffer(FileOffer),
+    FileDecline(String), // offer ID
+    FileRequest(FileRequest),
+    ShareAnnounce(ShareManifest),
+    ShareHave(ShareHave),
+    ChunkRequest(ChunkRequest),
 }
 
 #[cfg(test)]
@@ -52,42 +259,47 @@ mod tests {
 
     #[test]
     fn test_peer_info_valid() {
-        let valid_peer = PeerInfo {
-            id: "abc123".to_string(),
-            name: "Alice".to_string(),
-            ip: IpAddr::from_str("192.168.1.2").unwrap(),
-            port: 9000,
-        };
+        let valid_peer = PeerInfo::new(
+            "abc123".to_string(),
+            "Alice".to_string(),
+            IpAddr::from_str("192.168.1.2").unwrap(),
+            9000,
+        );
         assert!(valid_peer.is_valid());
 
-        let invalid_peer = PeerInfo {
-            id: "".to_string(),
-            name: "".to_string(),
-            ip: IpAddr::from_str("127.0.0.1").unwrap(),
-            port: 0,
-        };
+        let invalid_peer = PeerInfo::new(
+            "".to_string(),
+            "".to_string(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            0,
+        );
         assert!(!invalid_peer.is_valid());
     }
 
     #[test]
     fn test_peer_name_length() {
         let long_name = "a".repeat(1000);
-        let p1 = PeerInfo {
-            id: "id".to_string(),
-            name: long_name,
-            ip: IpAddr::from_str("10.0.0.1").unwrap(),
-            port: 1234,
-        };
+        let p1 = PeerInfo::new(
+            "id".to_string(),
+            long_name,
+            IpAddr::from_str("10.0.0.1").unwrap(),
+            1234,
+        );
         assert!(!p1.is_valid());
     }
 
     #[test]
     fn test_message_content() {
         let msg = Message {
+            id: Message::new_id(),
             from_id: "id1".to_string(),
             from_name: "Alice".to_string(),
             content: "Hello, world!".to_string(),
             timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 0,
+            to_id: String::new(),
+            signature: String::new(),
         };
         assert_eq!(msg.content, "Hello, world!");
         assert!(!msg.content.is_empty());
@@ -96,11 +308,23 @@ mod tests {
     #[test]
     fn test_message_empty_content() {
         let msg = Message {
+            id: Message::new_id(),
             from_id: "id2".to_string(),
             from_name: "Bob".to_string(),
             content: "".to_string(),
             timestamp: 1234567890,
+            channel: "general".to_string(),
+            seq: 0,
+            to_id: String::new(),
+            signature: String::new(),
         };
         assert!(msg.content.is_empty());
     }
+
+    #[test]
+    fn test_message_ids_are_unique() {
+        let id = Message::new_id();
+        assert_eq!(id.len(), 32);
+        assert_ne!(id, Message::new_id());
+    }
 }
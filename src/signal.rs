@@ -1,14 +1,51 @@
-use crate::chat::{display::cli::broadcast_exit, Peer};
+//! Signal module: Turns termination signals into a graceful shutdown and SIGHUP into a config reload.
+
+use p2p_chat::chat::events::ChatEvent;
+use p2p_chat::chat::Peer;
+use p2p_chat::config::Config;
+use p2p_chat::error::ChatError;
 use std::sync::Arc;
 use tokio::signal;
 
-pub async fn handle_signals(wt: Arc<Peer>) {
-    // Wait for either SIGINT (Ctrl+C) or SIGTERM
-    let _ = signal::ctrl_c().await;
-    // Call the quit procedure (same as /quit)
-    if let Err(e) = broadcast_exit(&wt).await {
-        eprintln!("Error broadcasting exit: {}", e);
+/// Wait for Ctrl+C or SIGTERM, then cancel the peer's shutdown token so `main` says goodbye to
+/// peers exactly as for `/quit`. Each SIGHUP in the meantime re-applies the config from `reload`.
+pub async fn handle_signals(peer: Arc<Peer>, reload: impl Fn() -> Result<Config, ChatError>) {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+        match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::hangup()),
+        ) {
+            (Ok(mut terminate), Ok(mut hangup)) => loop {
+                tokio::select! {
+                    _ = signal::ctrl_c() => break,
+                    _ = terminate.recv() => break,
+                    _ = hangup.recv() => reload_config(&peer, &reload).await,
+                }
+            },
+            _ => {
+                let _ = signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = &reload;
+        let _ = signal::ctrl_c().await;
     }
-    println!("\u{1F44B} Now Goodbye!");
-    std::process::exit(0);
+    peer.shutdown.cancel();
+}
+
+#[cfg(unix)]
+async fn reload_config(peer: &Peer, reload: &impl Fn() -> Result<Config, ChatError>) {
+    let result = match reload() {
+        Ok(config) => peer.apply_config(&config).await,
+        Err(e) => Err(e),
+    };
+    let event = match result {
+        Ok(()) => ChatEvent::ConfigReloaded,
+        Err(e) => ChatEvent::Error(format!("Keeping the current configuration: {}", e)),
+    };
+    let _ = peer.events.send(event);
 }